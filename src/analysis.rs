@@ -0,0 +1,107 @@
+use crate::instruction::{Instruction, Opcode};
+
+/// A run of instructions that is only entered at its start and only left at its end
+#[derive(Debug, PartialEq)]
+pub struct BasicBlock {
+    /// Byte offset of the first instruction
+    pub start: usize,
+    /// Byte offset just past the last instruction
+    pub end: usize,
+    /// Starts of the blocks control can pass to, in ascending order. Jumps through registers
+    /// and RET have no successors here as their targets are only known at run time.
+    pub successors: Vec<usize>,
+}
+
+/// Splits a code section into basic blocks, in program order
+pub fn basic_blocks(program: &[u8]) -> Vec<BasicBlock> {
+    let instructions: Vec<Instruction> = program
+        .chunks_exact(4)
+        .map(|b| Instruction::from_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+    let len = instructions.len() * 4;
+    let in_program = |target: i64| target >= 0 && (target as usize) < len && target % 4 == 0;
+
+    // a block starts at the program start, at every jump target and after every jump or HLT
+    let mut leaders = vec![0];
+    for (index, instruction) in instructions.iter().enumerate() {
+        let offset = index * 4;
+        if let Some(target) = instruction.static_target(offset) {
+            if in_program(target) {
+                leaders.push(target as usize);
+            }
+        }
+        if instruction.opcode().is_jump() || instruction.opcode() == Opcode::HLT {
+            leaders.push(offset + 4);
+        }
+    }
+    leaders.retain(|&leader| leader < len);
+    leaders.sort_unstable();
+    leaders.dedup();
+
+    let mut blocks = vec![];
+    for (i, &start) in leaders.iter().enumerate() {
+        let end = leaders.get(i + 1).copied().unwrap_or(len);
+        let last_offset = end - 4;
+        let last = instructions[last_offset / 4];
+        let mut successors = vec![];
+        if let Some(target) = last.static_target(last_offset) {
+            if in_program(target) {
+                successors.push(target as usize);
+            }
+        }
+        // TRAP comes back after its handler, JEQ only jumps through a register
+        let falls_through = match last.opcode() {
+            Opcode::HLT | Opcode::RET | Opcode::JMPFI | Opcode::JMPBI => false,
+            opcode => !opcode.is_jump() || opcode.is_conditional_jump() || opcode == Opcode::TRAP,
+        };
+        if falls_through && end < len {
+            successors.push(end);
+        }
+        successors.sort_unstable();
+        successors.dedup();
+        blocks.push(BasicBlock {
+            start,
+            end,
+            successors,
+        });
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_blocks_with_loop() {
+        let program = vec![
+            Opcode::LOAD.into(), 0, 0, 3,
+            Opcode::DEC.into(), 0, 0, 0,
+            Opcode::EQ.into(), 0, 1, 0,
+            Opcode::JEQD.into(), 0, 20, 0,
+            Opcode::JMPBI.into(), 0, 16, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+        ];
+        let blocks = basic_blocks(&program);
+        assert_eq!(
+            blocks,
+            vec![
+                BasicBlock { start: 0, end: 4, successors: vec![4] },
+                BasicBlock { start: 4, end: 16, successors: vec![16, 20] },
+                // the back-edge to the top of the loop
+                BasicBlock { start: 16, end: 20, successors: vec![4] },
+                BasicBlock { start: 20, end: 24, successors: vec![] },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_basic_blocks_straight_line() {
+        let program = vec![Opcode::INC.into(), 0, 0, 0, Opcode::INC.into(), 1, 0, 0];
+        assert_eq!(
+            basic_blocks(&program),
+            vec![BasicBlock { start: 0, end: 8, successors: vec![] }]
+        );
+        assert!(basic_blocks(&[]).is_empty());
+    }
+}
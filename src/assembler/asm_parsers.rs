@@ -1,51 +1,277 @@
-use crate::assembler::{AssemblerInstruction, Program, Token};
+use crate::assembler::{AssemblerInstruction, Expr, Program, Token};
 use crate::instruction::Opcode;
 
 use nom::types::CompleteStr;
 use nom::*;
 
+// Operands are separated by spaces or tabs only, never by line endings, so an operand can not be
+// mistaken for the start of the next line. Each line parser then eats the line ending itself.
+
 named!(pub opcode_parser <CompleteStr, Token>,
     do_parse!(
-        opcode: alpha1 >>
+        opcode: map_res!(alpha1, |s: CompleteStr| s.0.parse::<Opcode>()) >>
         (
-            Token::Op{code: Opcode::from(opcode)}
+            Token::Op{code: opcode}
         )
     )
 );
 
-named!(register <CompleteStr, Token>,
+// parse the mnemonic of an instruction without checking it against the known opcodes,
+// used to name the offending token when `opcode_parser` rejects it
+named!(pub mnemonic <CompleteStr, CompleteStr>,
     ws!(
         do_parse!(
-            tag!("$") >>
-            reg_num: digit >>
+            opt!(label_declaration) >>
+            name: alpha1 >>
             (
-                Token::Register{
-                    reg_num: reg_num.parse::<u8>().unwrap()
-                }
+                name
             )
         )
     )
 );
 
+/// Checks the digits following a `$` fit the operand byte. Whether the VM has the register
+/// depends on how many it is made with, which the assembler checks once the line is parsed.
+pub fn register_number(digits: &str) -> Result<u8, String> {
+    digits.parse::<u8>().map_err(|_| {
+        format!("Register `${}` is out of range, register numbers are $0 to $255", digits)
+    })
+}
+
+named!(register <CompleteStr, Token>,
+    do_parse!(
+        tag!("$") >>
+        reg_num: map_res!(digit, |s: CompleteStr| register_number(s.0)) >>
+        (
+            Token::Register{
+                reg_num
+            }
+        )
+    )
+);
+
+// an integer with an optional minus sign, such as `-1`
+named!(signed_integer <CompleteStr, i32>,
+    map_res!(
+        recognize!(pair!(opt!(tag!("-")), digit)),
+        |s: CompleteStr| s.0.parse::<i32>()
+    )
+);
+
 named!(integer_operand <CompleteStr, Token>,
-    ws!(
+    do_parse!(
+        tag!("#") >>
+        value: signed_integer >>
+        (
+            Token::IntegerOperand{
+                value
+            }
+        )
+    )
+);
+
+/// The character an escape such as the `n` of `\n` stands for
+fn unescape(c: CompleteStr) -> Result<char, String> {
+    match c.0 {
+        "n" => Ok('\n'),
+        "t" => Ok('\t'),
+        "0" => Ok('\0'),
+        "\\" => Ok('\\'),
+        "'" => Ok('\''),
+        _ => Err(format!("Unknown escape: `\\{}`", c.0)),
+    }
+}
+
+// parse a single character in quotes, such as `'A'` or `'\n'`
+named!(char_literal <CompleteStr, char>,
+    delimited!(
+        tag!("'"),
+        alt!(
+            preceded!(tag!("\\"), map_res!(take!(1), unescape)) |
+            none_of!("'\\")
+        ),
+        tag!("'")
+    )
+);
+
+// parse a character immediate, such as `#'A'`, into its code
+named!(char_operand <CompleteStr, Token>,
+    do_parse!(
+        tag!("#") >>
+        c: char_literal >>
+        (
+            Token::IntegerOperand{
+                value: c as i32
+            }
+        )
+    )
+);
+
+named!(float_literal <CompleteStr, CompleteStr>,
+    recognize!(
         do_parse!(
-            tag!("#") >>
-            value: digit >>
-            (
-                Token::IntegerOperand{
-                    value: value.parse::<i32>().unwrap()
-                }
-            )
+            digit >>
+            tag!(".") >>
+            digit >>
+            ()
+        )
+    )
+);
+
+named!(float_operand <CompleteStr, Token>,
+    do_parse!(
+        tag!("#") >>
+        value: map_res!(float_literal, |s: CompleteStr| s.0.parse::<f64>()) >>
+        (
+            Token::FloatOperand{
+                value
+            }
+        )
+    )
+);
+
+named!(string_literal <CompleteStr, CompleteStr>,
+    delimited!(
+        tag!("'"),
+        take_until!("'"),
+        tag!("'")
+    )
+);
+
+named!(irstring <CompleteStr, Token>,
+    do_parse!(
+        content: string_literal >>
+        (
+            Token::IrString{
+                name: content.to_string()
+            }
+        )
+    )
+);
+
+// a name starting with a letter, such as the constant `MAX2`
+named!(identifier <CompleteStr, CompleteStr>,
+    recognize!(
+        pair!(
+            alpha1,
+            opt!(alphanumeric)
+        )
+    )
+);
+
+// parse a usage of a constant defined with `.equ`, such as `#MAX`
+named!(constant_operand <CompleteStr, Token>,
+    do_parse!(
+        tag!("#") >>
+        name: identifier >>
+        (
+            Token::Constant{
+                name: name.to_string()
+            }
+        )
+    )
+);
+
+// parse a usage of a register alias defined with `.alias`, such as `inc counter`
+named!(register_alias <CompleteStr, Token>,
+    do_parse!(
+        name: identifier >>
+        (
+            Token::RegisterAlias{
+                name: name.to_string()
+            }
+        )
+    )
+);
+
+// a factor of an expression, a number, a constant or label, `$` for the current address or an
+// expression in parentheses
+named!(expr_factor <CompleteStr, Expr>,
+    alt!(
+        map!(signed_integer, Expr::Integer) |
+        map!(identifier, |name: CompleteStr| Expr::Symbol(name.to_string())) |
+        value!(Expr::Here, terminated!(tag!("$"), not!(digit))) |
+        delimited!(tag!("("), expr, tag!(")"))
+    )
+);
+
+// an operator either has spaces on both sides or none, so `.word 5 -3` is still two words
+named!(mul_operator <CompleteStr, char>,
+    alt!(delimited!(space1, one_of!("*/"), space1) | one_of!("*/"))
+);
+
+named!(add_operator <CompleteStr, char>,
+    alt!(delimited!(space1, one_of!("+-"), space1) | one_of!("+-"))
+);
+
+named!(expr_term <CompleteStr, Expr>,
+    do_parse!(
+        first: expr_factor >>
+        rest: many0!(pair!(mul_operator, expr_factor)) >>
+        (
+            fold_expr(first, rest)
         )
     )
 );
 
+named!(expr <CompleteStr, Expr>,
+    do_parse!(
+        first: expr_term >>
+        rest: many0!(pair!(add_operator, expr_term)) >>
+        (
+            fold_expr(first, rest)
+        )
+    )
+);
+
+/// Chains operators of the same precedence left to right, so `8-2-1` is `(8-2)-1`
+fn fold_expr(first: Expr, rest: Vec<(char, Expr)>) -> Expr {
+    rest.into_iter().fold(first, |left, (op, right)| {
+        Expr::Binary(Box::new(left), op, Box::new(right))
+    })
+}
+
+/// An expression worth computing, plain numbers and symbols are left to the other operands
+fn computed(expr: Expr) -> Option<Token> {
+    match expr {
+        Expr::Integer(_) | Expr::Symbol(_) => None,
+        _ => Some(Token::Expression { expr }),
+    }
+}
+
+// parse an immediate the assembler computes, such as `#(4*8)` or `#MAX-1`
+named!(expression_operand <CompleteStr, Token>,
+    map_opt!(preceded!(tag!("#"), expr), computed)
+);
+
 named!(operand <CompleteStr, Token>,
     alt!(
-        integer_operand |
-        register        |
-        label_usage
+        float_operand      |
+        expression_operand |
+        integer_operand    |
+        char_operand       |
+        constant_operand   |
+        register           |
+        label_usage        |
+        irstring           |
+        register_alias
+    )
+);
+
+// an operand on the same line, separated from what precedes it by spaces or tabs
+named!(line_operand <CompleteStr, Token>,
+    preceded!(
+        space1,
+        operand
+    )
+);
+
+// a directive's operand may also be a bare integer, such as the 4 of `.align 4`
+named!(directive_operand <CompleteStr, Token>,
+    alt!(
+        map_opt!(expr, computed) |
+        operand |
+        map!(signed_integer, |value| Token::IntegerOperand{ value })
     )
 );
 
@@ -60,23 +286,47 @@ named!(directive_declaration<CompleteStr, Token>,
 );
 
 named!(directive_combined<CompleteStr, AssemblerInstruction>,
-    ws!(
-        do_parse!(
-            l: opt!(label_declaration) >>
-            name: directive_declaration >>
-            o1: opt!(operand) >>
-            o2: opt!(operand) >>
-            o3: opt!(operand) >>
-            (
-                AssemblerInstruction {
-                    opcode: None,
-                    directive: Some(name),
-                    label: l,
-                    operand1: o1,
-                    operand2: o2,
-                    operand3: o3,
-                }
-            )
+    do_parse!(
+        l: opt!(label_declaration) >>
+        name: directive_declaration >>
+        operands: many0!(preceded!(space1, directive_operand)) >>
+        multispace0 >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(name),
+                label: l,
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands,
+            }
+        )
+    )
+);
+
+// parse a constant definition, such as `.equ MAX 100` or `.equ NEG -1`
+named!(equ_directive<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        tag!(".equ") >>
+        space1 >>
+        name: identifier >>
+        space1 >>
+        value: signed_integer >>
+        multispace0 >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                directive: Some(Token::Directive{ name: "equ".to_string() }),
+                label: None,
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands: vec![
+                    Token::Constant{ name: name.to_string() },
+                    Token::IntegerOperand{ value },
+                ],
+            }
         )
     )
 );
@@ -84,6 +334,7 @@ named!(directive_combined<CompleteStr, AssemblerInstruction>,
 named!(pub directive<CompleteStr, AssemblerInstruction>,
     do_parse!(
         ins: alt!(
+            equ_directive |
             directive_combined
         ) >>
         (
@@ -94,28 +345,23 @@ named!(pub directive<CompleteStr, AssemblerInstruction>,
 
 // parse a declaration of user-defined label, such as `label1: LOAD $1 100`
 named!(pub label_declaration<CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            name: alphanumeric >>
-            tag!(":") >>
-            opt!(multispace) >>
-            (
-                Token::LabelDeclaration {name: name.to_string()}
-            )
+    do_parse!(
+        name: alphanumeric >>
+        tag!(":") >>
+        space0 >>
+        (
+            Token::LabelDeclaration {name: name.to_string()}
         )
     )
 );
 
 // parse a usage of user-defined label, such as `JMP @label1`
 named!(pub label_usage<CompleteStr, Token>,
-    ws!(
-        do_parse!(
-            tag!("@") >>
-            name: alphanumeric >>
-            opt!(multispace) >>
-            (
-                Token::LabelUsage {name: name.to_string()}
-            )
+    do_parse!(
+        tag!("@") >>
+        name: alphanumeric >>
+        (
+            Token::LabelUsage {name: name.to_string()}
         )
     )
 );
@@ -124,9 +370,10 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
     do_parse!(
         l: opt!(label_declaration) >>
         o: opcode_parser >>
-        o1: opt!(operand) >>
-        o2: opt!(operand) >>
-        o3: opt!(operand) >>
+        o1: opt!(line_operand) >>
+        o2: opt!(line_operand) >>
+        o3: opt!(line_operand) >>
+        multispace0 >>
         (
             AssemblerInstruction {
                 opcode: Some(o),
@@ -135,6 +382,26 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
                 operand1: o1,
                 operand2: o2,
                 operand3: o3,
+                operands: vec![],
+            }
+        )
+    )
+);
+
+// parse a line holding only a label declaration, such as `loop:`
+named!(label_only<CompleteStr, AssemblerInstruction>,
+    do_parse!(
+        l: label_declaration >>
+        multispace0 >>
+        (
+            AssemblerInstruction {
+                opcode: None,
+                label: Some(l),
+                directive: None,
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands: vec![],
             }
         )
     )
@@ -142,9 +409,11 @@ named!(instruction_combined<CompleteStr, AssemblerInstruction>,
 
 named!(pub instruction<CompleteStr, AssemblerInstruction>,
     do_parse!(
+        multispace0 >>
         ins: alt! (
             instruction_combined |
-            directive
+            directive |
+            label_only
         ) >>
         (
             ins
@@ -152,19 +421,23 @@ named!(pub instruction<CompleteStr, AssemblerInstruction>,
     )
 );
 
+// parse a whole program, blank lines anywhere and a missing final newline are fine but
+// anything left that is not an instruction is an error
 named!(pub program<CompleteStr, Program>,
     do_parse!(
-        instructions: many1!(instruction) >>
+        instructions: many0!(instruction) >>
+        multispace0 >>
+        eof!() >>
         (
             Program {
-                instructions: instructions
+                instructions
             }
         )
     )
 );
 
+#[cfg(test)]
 mod tests {
-    #[allow(unused_imports)]
     use super::*;
 
     #[test]
@@ -177,10 +450,15 @@ mod tests {
         assert_eq!(token, Token::Op { code: Opcode::LOAD });
         assert_eq!(rest, CompleteStr(""));
 
-        // Tests that an invalid opcode recognized as IGL
+        // Tests that an invalid opcode is rejected
         let result = opcode_parser(CompleteStr("xxxilg"));
-        let (_, token) = result.unwrap();
-        assert_eq!(token, Token::Op { code: Opcode::IGL });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_parse_mnemonic() {
+        let (_, name) = mnemonic(CompleteStr("test: frobnicate $0")).unwrap();
+        assert_eq!(name, CompleteStr("frobnicate"));
     }
 
     #[test]
@@ -196,6 +474,18 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn test_parse_register_range() {
+        let (_, token) = register(CompleteStr("$255")).unwrap();
+        assert_eq!(token, Token::Register { reg_num: 255 });
+        assert!(register(CompleteStr("$256")).is_err());
+        assert!(register(CompleteStr("$-1")).is_err());
+        assert_eq!(
+            register_number("300"),
+            Err("Register `$300` is out of range, register numbers are $0 to $255".to_string())
+        );
+    }
+
     #[test]
     fn test_parse_integer() {
         let result = integer_operand(CompleteStr("#566"));
@@ -205,6 +495,138 @@ mod tests {
 
         let result = integer_operand(CompleteStr("10"));
         assert!(result.is_err());
+
+        let result = integer_operand(CompleteStr("#-1"));
+        let (_, token) = result.unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: -1 });
+    }
+
+    #[test]
+    fn test_parse_malformed_operands() {
+        for input in ["$", "#", "$ ", "# ", "#-", "#99999999999"] {
+            assert!(operand(CompleteStr(input)).is_err(), "{:?}", input);
+        }
+        assert!(register(CompleteStr("$ 1")).is_err());
+
+        use crate::assembler::Assembler;
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".equ BIG 99999999999\nhlt").is_err());
+        let errors = asm.assemble("load $ #5\nload $0 #\nhlt").unwrap_err();
+        let lines: Vec<Option<usize>> = errors.iter().map(|e| e.line()).collect();
+        assert_eq!(lines, vec![Some(1), Some(2)]);
+    }
+
+    #[test]
+    fn test_parse_char_operand() {
+        let (_, token) = operand(CompleteStr("#'A'")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 65 });
+        let (_, token) = operand(CompleteStr("#'\\n'")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 10 });
+        let (_, token) = operand(CompleteStr("#'\\0'")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 0 });
+
+        assert!(char_operand(CompleteStr("#'AB'")).is_err());
+        assert!(char_operand(CompleteStr("#''")).is_err());
+        assert!(char_operand(CompleteStr("#'\\q'")).is_err());
+    }
+
+    #[test]
+    fn test_parse_float() {
+        let result = float_operand(CompleteStr("#2.5"));
+        let (_, token) = result.unwrap();
+        assert_eq!(token, Token::FloatOperand { value: 2.5 });
+
+        let result = float_operand(CompleteStr("#3"));
+        assert!(result.is_err());
+
+        let result = operand(CompleteStr("#3"));
+        let (_, token) = result.unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: 3 });
+    }
+
+    #[test]
+    fn test_assemble_float_immediate() {
+        use crate::assembler::{Assembler, PIE_HEADER_LENGTH};
+        let mut asm = Assembler::new();
+        let bytecode = asm.assemble("fload $1 #2.5\n").unwrap();
+        // 2.5 as a half-precision float is 0x4100
+        assert_eq!(
+            bytecode[PIE_HEADER_LENGTH..].to_vec(),
+            vec![Opcode::FLOAD.into(), 1, 0x41, 0x00]
+        );
+    }
+
+    #[test]
+    fn test_parse_expression() {
+        let (_, token) = operand(CompleteStr("#(4*8)")).unwrap();
+        assert_eq!(
+            token,
+            Token::Expression {
+                expr: Expr::Binary(Box::new(Expr::Integer(4)), '*', Box::new(Expr::Integer(8)))
+            }
+        );
+
+        let (_, token) = operand(CompleteStr("#MAX-1")).unwrap();
+        assert_eq!(
+            token,
+            Token::Expression {
+                expr: Expr::Binary(
+                    Box::new(Expr::Symbol("MAX".to_string())),
+                    '-',
+                    Box::new(Expr::Integer(1))
+                )
+            }
+        );
+
+        // a lone number or constant is not an expression
+        let (_, token) = operand(CompleteStr("#-5")).unwrap();
+        assert_eq!(token, Token::IntegerOperand { value: -5 });
+    }
+
+    #[test]
+    fn test_parse_constant() {
+        let result = operand(CompleteStr("#MAX2"));
+        let (_, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::Constant {
+                name: "MAX2".to_string()
+            }
+        );
+
+        let result = instruction(CompleteStr(".equ MAX 100\n"));
+        let (_, ins) = result.unwrap();
+        assert_eq!(
+            ins,
+            AssemblerInstruction {
+                opcode: None,
+                label: None,
+                directive: Some(Token::Directive {
+                    name: "equ".to_string()
+                }),
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands: vec![
+                    Token::Constant {
+                        name: "MAX".to_string()
+                    },
+                    Token::IntegerOperand { value: 100 },
+                ],
+            }
+        );
+
+        let result = instruction(CompleteStr(".equ NEG -1\n"));
+        let (_, ins) = result.unwrap();
+        assert_eq!(
+            ins.operands,
+            vec![
+                Token::Constant {
+                    name: "NEG".to_string()
+                },
+                Token::IntegerOperand { value: -1 },
+            ]
+        );
     }
 
     #[test]
@@ -220,7 +642,8 @@ mod tests {
                 directive: None,
                 operand1: None,
                 operand2: None,
-                operand3: None
+                operand3: None,
+                operands: vec![],
             }
         );
 
@@ -235,7 +658,8 @@ mod tests {
                 directive: None,
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::IntegerOperand { value: 12345 }),
-                operand3: None
+                operand3: None,
+                operands: vec![],
             }
         );
 
@@ -251,14 +675,67 @@ mod tests {
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::Register { reg_num: 1 }),
                 operand3: Some(Token::Register { reg_num: 2 }),
+                operands: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_addi_instruction() {
+        let result = instruction(CompleteStr("addi $0 $1 #5\n"));
+        let (_, ins) = result.unwrap();
+        assert_eq!(
+            ins,
+            AssemblerInstruction {
+                opcode: Some(Token::Op { code: Opcode::ADDI }),
+                label: None,
+                directive: None,
+                operand1: Some(Token::Register { reg_num: 0 }),
+                operand2: Some(Token::Register { reg_num: 1 }),
+                operand3: Some(Token::IntegerOperand { value: 5 }),
+                operands: vec![],
+            }
+        );
+
+        use crate::assembler::{Assembler, PIE_HEADER_LENGTH};
+        let mut asm = Assembler::new();
+        let bytecode = asm.assemble("addi $0 $1 #5\n").unwrap();
+        assert_eq!(
+            bytecode[PIE_HEADER_LENGTH..].to_vec(),
+            vec![Opcode::ADDI.into(), 0, 1, 5]
+        );
+    }
+
+    #[test]
+    fn test_parse_jmpfi_instruction() {
+        let result = instruction(CompleteStr("jmpfi #8\n"));
+        let (_, ins) = result.unwrap();
+        assert_eq!(
+            ins,
+            AssemblerInstruction {
+                opcode: Some(Token::Op { code: Opcode::JMPFI }),
+                label: None,
+                directive: None,
+                operand1: Some(Token::IntegerOperand { value: 8 }),
+                operand2: None,
+                operand3: None,
+                operands: vec![],
             }
         );
+
+        use crate::assembler::{Assembler, PIE_HEADER_LENGTH};
+        let mut asm = Assembler::new();
+        let bytecode = asm.assemble("jmpbi #260\n").unwrap();
+        assert_eq!(
+            bytecode[PIE_HEADER_LENGTH..].to_vec(),
+            vec![Opcode::JMPBI.into(), 1, 4, 0]
+        );
     }
 
     #[test]
     fn test_parse_program() {
         let result = program(CompleteStr("load $0 #100\n"));
-        assert_eq!(result.is_ok(), true);
+        assert!(result.is_ok());
         let (leftover, p) = result.unwrap();
         assert_eq!(leftover, CompleteStr(""));
         assert_eq!(1, p.instructions.len());
@@ -296,34 +773,99 @@ mod tests {
                 operand1: None,
                 operand2: None,
                 operand3: None,
+                operands: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_string() {
+        let result = irstring(CompleteStr("' Hello there'"));
+        let (_, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::IrString {
+                name: " Hello there".to_string()
+            }
+        );
+
+        let result = irstring(CompleteStr("'unterminated"));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_string_directive() {
+        let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
+        assert!(result.is_ok());
+        let (_, directive) = result.unwrap();
+
+        // Yes, this is the what the result should be
+        let correct_instruction = AssemblerInstruction {
+            opcode: None,
+            label: Some(Token::LabelDeclaration {
+                name: "test".to_string(),
+            }),
+            directive: Some(Token::Directive {
+                name: "asciiz".to_string(),
+            }),
+            operand1: None,
+            operand2: None,
+            operand3: None,
+            operands: vec![Token::IrString {
+                name: "Hello".to_string(),
+            }],
+        };
+
+        assert_eq!(directive, correct_instruction);
+    }
+
+    #[test]
+    fn test_byte_directive() {
+        let (rest, directive) = directive(CompleteStr("table: .byte 1 2 255\nhlt")).unwrap();
+        assert_eq!(rest, CompleteStr("hlt"));
+        assert_eq!(
+            directive,
+            AssemblerInstruction {
+                opcode: None,
+                label: Some(Token::LabelDeclaration {
+                    name: "table".to_string(),
+                }),
+                directive: Some(Token::Directive {
+                    name: "byte".to_string(),
+                }),
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands: vec![
+                    Token::IntegerOperand { value: 1 },
+                    Token::IntegerOperand { value: 2 },
+                    Token::IntegerOperand { value: 255 },
+                ],
             }
         );
+
+        // a byte is any directive operand, its range is checked when the data is laid out
+        let (_, ins) = super::directive(CompleteStr(".byte 2*3 MAX #'A'\n")).unwrap();
+        assert_eq!(ins.operands[1], Token::RegisterAlias { name: "MAX".to_string() });
+        assert_eq!(ins.operands[2], Token::IntegerOperand { value: 65 });
     }
 
-    // #[test]
-    // fn test_string_directive() {
-    //     let result = directive_combined(CompleteStr("test: .asciiz 'Hello'"));
-    //     assert_eq!(result.is_ok(), true);
-    //     let (_, directive) = result.unwrap();
-
-    //     // Yes, this is the what the result should be
-    //     let correct_instruction = AssemblerInstruction {
-    //         opcode: None,
-    //         label: Some(Token::LabelDeclaration {
-    //             name: "test".to_string(),
-    //         }),
-    //         directive: Some(Token::Directive {
-    //             name: "asciiz".to_string(),
-    //         }),
-    //         operand1: Some(Token::IrString {
-    //             name: "Hello".to_string(),
-    //         }),
-    //         operand2: None,
-    //         operand3: None,
-    //     };
-
-    //     assert_eq!(directive, correct_instruction);
-    // }
+    #[test]
+    fn test_directive_operand_list() {
+        let (_, ins) = directive(CompleteStr(".byte 1 2 3 4 5 6 7 8 9 10\n")).unwrap();
+        let symbols = crate::assembler::SymbolTable::new();
+        assert_eq!(ins.data_bytes(&symbols, 0), Ok(Some((1..=10).collect())));
+
+        let (_, ins) = directive(CompleteStr(".word #1 #2 #3 #4 #5\n")).unwrap();
+        assert_eq!(ins.operands.len(), 5);
+        assert_eq!(ins.operands[4], Token::IntegerOperand { value: 5 });
+        assert_eq!(ins.operand1, None);
+
+        // instructions still stop at three operands
+        let (rest, ins) = instruction(CompleteStr("add $0 $1 $2 $3\n")).unwrap();
+        assert_eq!(ins.operands, vec![]);
+        assert_eq!(rest, CompleteStr("$3\n"));
+    }
 
     #[test]
     fn test_parse_label_declaration_instruction() {
@@ -341,8 +883,90 @@ mod tests {
                 operand1: Some(Token::Register { reg_num: 1 }),
                 operand2: Some(Token::IntegerOperand { value: 12345 }),
                 operand3: None,
+                operands: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_label_only_instruction() {
+        let result = instruction(CompleteStr("loop:\ninc $0\n"));
+        let (rest, ins) = result.unwrap();
+        assert_eq!(rest, CompleteStr("inc $0\n"));
+        assert_eq!(
+            ins,
+            AssemblerInstruction {
+                opcode: None,
+                label: Some(Token::LabelDeclaration {
+                    name: "loop".to_string()
+                }),
+                directive: None,
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands: vec![],
             }
         );
+
+        let result = program(CompleteStr("first: second: inc $0\n"));
+        let (_, p) = result.unwrap();
+        assert_eq!(p.instructions.len(), 2);
+    }
+
+    #[test]
+    fn test_parse_register_alias() {
+        let result = instruction(CompleteStr(".alias counter $5\ninc counter\n"));
+        let (rest, ins) = result.unwrap();
+        assert_eq!(rest, CompleteStr("inc counter\n"));
+        assert_eq!(
+            ins,
+            AssemblerInstruction {
+                opcode: None,
+                label: None,
+                directive: Some(Token::Directive {
+                    name: "alias".to_string()
+                }),
+                operand1: None,
+                operand2: None,
+                operand3: None,
+                operands: vec![
+                    Token::RegisterAlias {
+                        name: "counter".to_string()
+                    },
+                    Token::Register { reg_num: 5 },
+                ],
+            }
+        );
+
+        let (_, ins) = instruction(rest).unwrap();
+        assert_eq!(
+            ins.operand1,
+            Some(Token::RegisterAlias {
+                name: "counter".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_operands_stay_on_their_line() {
+        let (_, p) = program(CompleteStr("hlt\ninc $0\nload $1   \n  hlt\n")).unwrap();
+        assert_eq!(p.instructions.len(), 4);
+        assert_eq!(p.instructions[0].operand1, None);
+        assert_eq!(p.instructions[2].operand2, None);
+    }
+
+    #[test]
+    fn test_parse_program_line_breaks() {
+        let count = |source: &str| {
+            let parsed = program(CompleteStr(source)).ok();
+            parsed.map(|(_, p)| p.instructions.len())
+        };
+        assert_eq!(count("load $0 #100\nhlt"), Some(2));
+        assert_eq!(count("load $0 #100\n\n\n  \n\thlt\n\n"), Some(2));
+        assert_eq!(count("\n\n   \nload $0 #100\nhlt"), Some(2));
+        assert_eq!(count("\r\nhlt\r\n"), Some(1));
+        assert_eq!(count(""), Some(0));
+        assert!(count("hlt\n%%%\n").is_none());
     }
 
     #[test]
@@ -375,6 +999,7 @@ mod tests {
                 }),
                 operand2: None,
                 operand3: None,
+                operands: vec![],
             }
         );
     }
@@ -1,22 +1,207 @@
 pub mod asm_parsers;
+pub mod preprocessor;
 
 use nom::types::CompleteStr;
+use std::collections::HashSet;
+use std::path::Path;
 
-use crate::assembler::asm_parsers::program;
-use crate::instruction::Opcode;
-
-// PIE Magic numbers
-pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
-pub const PIE_HEADER_LENGTH: usize = 64;
+use crate::assembler::asm_parsers::{instruction, mnemonic};
+use crate::assembler::preprocessor::{expand_includes, expand_macros, expand_pseudo_instructions};
+use crate::instruction::{f64_to_f16_bits, Opcode, OperandKind};
+use crate::linker::{AssembledObject, ObjectLabel, Section};
+use crate::vm::{DEFAULT_REGISTERS, FLOAT_REGISTERS};
+pub use crate::vm::{pie_header, PIE_HEADER_LENGTH, PIE_HEADER_PREFIX};
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
     Op { code: Opcode },
     Register { reg_num: u8 },
     IntegerOperand { value: i32 },
+    FloatOperand { value: f64 },
     LabelDeclaration { name: String },
     LabelUsage { name: String },
     Directive { name: String },
+    IrString { name: String },
+    Constant { name: String },
+    RegisterAlias { name: String },
+    Expression { expr: Expr },
+}
+
+/// A value the assembler computes, such as `#(4*8)`, `#MAX-1` or `.word $ - msg`
+#[derive(Debug, PartialEq, Clone)]
+pub enum Expr {
+    Integer(i32),
+    /// A `.equ` constant or a label
+    Symbol(String),
+    /// `$`, the address the expression is assembled at
+    Here,
+    /// The left operand, one of `+ - * /` and the right operand
+    Binary(Box<Expr>, char, Box<Expr>),
+}
+
+impl Expr {
+    /// The value with the constants and labels of `symbols` filled in and `$` as `here`.
+    /// Division truncates toward zero, dividing by zero and overflowing an i32 are errors.
+    pub fn evaluate(&self, symbols: &SymbolTable, here: u32) -> Result<i32, AssemblerError> {
+        match self {
+            Expr::Integer(value) => Ok(*value),
+            Expr::Symbol(name) => symbols
+                .constant_value(name)
+                .or_else(|| symbols.label_value(name))
+                .ok_or_else(|| format!("Undefined constant or label: `{}`", name).into()),
+            Expr::Here => Ok(here as i32),
+            Expr::Binary(left, op, right) => {
+                let (a, b) = (left.evaluate(symbols, here)?, right.evaluate(symbols, here)?);
+                let value = match op {
+                    '+' => a.checked_add(b),
+                    '-' => a.checked_sub(b),
+                    '*' => a.checked_mul(b),
+                    _ if b == 0 => return Err(format!("Division by zero in `{} / 0`", a).into()),
+                    _ => a.checked_div(b),
+                };
+                value.ok_or_else(|| format!("Overflow in `{} {} {}`", a, op, b).into())
+            }
+        }
+    }
+
+    /// The constants and labels the expression refers to
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Symbol(name) => vec![name.as_str()],
+            Expr::Binary(left, _, right) => {
+                let mut names = left.symbols();
+                names.extend(right.symbols());
+                names
+            }
+            Expr::Integer(_) | Expr::Here => vec![],
+        }
+    }
+}
+
+/// What went wrong while assembling and where. Lines are 1-based lines of the source as written,
+/// before macros and pseudo-instructions are expanded, columns are 1-based characters into the
+/// expanded line.
+#[derive(Debug, PartialEq, Clone)]
+pub enum AssemblerError {
+    /// A line that is no instruction, directive or label, `msg` holding its text
+    Parse { line: usize, col: usize, msg: String },
+    UnknownOpcode { line: usize, col: usize, name: String },
+    /// A register operand that names no register, such as the 300 of `$300`, `count` being how
+    /// many registers of its kind there are
+    BadRegister { line: usize, col: usize, number: u32, count: usize },
+    UndefinedLabel { line: Option<usize>, name: String },
+    DuplicateLabel { line: usize, name: String },
+    /// A label nothing refers to, only ever a warning
+    UnusedLabel { line: usize, name: String },
+    /// Any other problem, described in full, on `line` when it belongs to one
+    Other { line: Option<usize>, msg: String },
+}
+
+impl AssemblerError {
+    /// Whether the problem still lets the program assemble
+    pub fn is_warning(&self) -> bool {
+        matches!(self, AssemblerError::UnusedLabel { .. })
+    }
+
+    /// The line the problem is on, if it belongs to one
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            AssemblerError::Parse { line, .. }
+            | AssemblerError::UnknownOpcode { line, .. }
+            | AssemblerError::BadRegister { line, .. }
+            | AssemblerError::DuplicateLabel { line, .. }
+            | AssemblerError::UnusedLabel { line, .. } => Some(*line),
+            AssemblerError::UndefinedLabel { line, .. } | AssemblerError::Other { line, .. } => {
+                *line
+            }
+        }
+    }
+
+    /// The column the problem starts at, known for the problems found while parsing
+    pub fn col(&self) -> Option<usize> {
+        match self {
+            AssemblerError::Parse { col, .. }
+            | AssemblerError::UnknownOpcode { col, .. }
+            | AssemblerError::BadRegister { col, .. } => Some(*col),
+            _ => None,
+        }
+    }
+
+    /// Places a problem found in an instruction on the line the instruction came from
+    fn on_line(self, at: usize) -> AssemblerError {
+        match self {
+            AssemblerError::UndefinedLabel { line: None, name } => {
+                AssemblerError::UndefinedLabel { line: Some(at), name }
+            }
+            AssemblerError::Other { line: None, msg } => {
+                AssemblerError::Other { line: Some(at), msg }
+            }
+            other => other,
+        }
+    }
+
+    /// Moves a problem from a line of the preprocessed source to the source line it came from
+    fn on_source_line(mut self, source_lines: &[usize]) -> AssemblerError {
+        let line = match &mut self {
+            AssemblerError::Parse { line, .. }
+            | AssemblerError::UnknownOpcode { line, .. }
+            | AssemblerError::BadRegister { line, .. }
+            | AssemblerError::DuplicateLabel { line, .. }
+            | AssemblerError::UnusedLabel { line, .. } => Some(line),
+            AssemblerError::UndefinedLabel { line, .. } | AssemblerError::Other { line, .. } => {
+                line.as_mut()
+            }
+        };
+        if let Some(line) = line {
+            if let Some(source_line) = source_lines.get(*line - 1) {
+                *line = *source_line;
+            }
+        }
+        self
+    }
+}
+
+impl std::fmt::Display for AssemblerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = if self.is_warning() { "Warning" } else { "Error" };
+        match (self.line(), self.col()) {
+            (Some(line), Some(col)) => write!(f, "{} at line {}, column {}: ", kind, line, col)?,
+            (Some(line), None) => write!(f, "{} at line {}: ", kind, line)?,
+            _ => {}
+        }
+        match self {
+            AssemblerError::Parse { msg, .. } => write!(f, "Unable to parse `{}`", msg),
+            AssemblerError::UnknownOpcode { name, .. } => write!(f, "Unknown opcode: `{}`", name),
+            AssemblerError::BadRegister { number, count, .. } => write!(
+                f,
+                "Register `${}` is out of range, registers are $0 to ${}",
+                number,
+                count - 1
+            ),
+            AssemblerError::UndefinedLabel { name, .. } => write!(f, "Undefined label: `{}`", name),
+            AssemblerError::DuplicateLabel { name, .. } => {
+                write!(f, "Label `{}` is declared more than once", name)
+            }
+            AssemblerError::UnusedLabel { name, .. } => write!(f, "Label `{}` is never used", name),
+            AssemblerError::Other { msg, .. } => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for AssemblerError {}
+
+impl From<String> for AssemblerError {
+    fn from(msg: String) -> AssemblerError {
+        AssemblerError::Other { line: None, msg }
+    }
+}
+
+/// A label used by the instruction at `offset` in the code section, whose encoded address has to
+/// be fixed up if the code is moved, such as when concatenated after other code
+#[derive(Debug, PartialEq, Clone)]
+pub struct Relocation {
+    pub offset: u32,
+    pub label: String,
 }
 
 #[derive(Debug, PartialEq)]
@@ -27,31 +212,220 @@ pub struct AssemblerInstruction {
     operand1: Option<Token>,
     operand2: Option<Token>,
     operand3: Option<Token>,
+    /// A directive's operands, which unlike an instruction's can be any number of them
+    operands: Vec<Token>,
 }
 
 impl std::fmt::Display for AssemblerInstruction {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(
             f,
-            "(Label: {:?} Opcode: {:?} Directive: {:?} Operand #1: {:?} Operand #2: {:?} Operand #3: {:?})",
-            self.label, self.opcode, self.directive, self.operand1, self.operand2, self.operand3
+            concat!(
+                "(Label: {:?} Opcode: {:?} Directive: {:?} ",
+                "Operand #1: {:?} Operand #2: {:?} Operand #3: {:?} Operands: {:?})"
+            ),
+            self.label,
+            self.opcode,
+            self.directive,
+            self.operand1,
+            self.operand2,
+            self.operand3,
+            self.operands
         )
     }
 }
 
 impl AssemblerInstruction {
-    pub fn to_bytes(&self, symbol_tbl: &SymbolTable) -> Vec<u8> {
+    /// Only instructions with an opcode take space in the code section, label-only lines label
+    /// the following instruction and directives go to the read-only data section, if anywhere
+    pub fn is_opcode(&self) -> bool {
+        self.opcode.is_some()
+    }
+
+    /// The bytes a directive places in the read-only data section, such as `.asciiz 'Hello'`,
+    /// `.byte 1 2 3` or `.word 70000`, whose words are little-endian like LW and SW expect.
+    /// `here` is the data offset the directive is placed at, for a `$` in `.word`.
+    pub fn data_bytes(
+        &self,
+        symbols: &SymbolTable,
+        here: u32,
+    ) -> Result<Option<Vec<u8>>, String> {
+        match (&self.directive, self.operands.as_slice()) {
+            (Some(Token::Directive { name }), [Token::IrString { name: s }])
+                if name == "asciiz" =>
+            {
+                let mut bytes = s.as_bytes().to_vec();
+                bytes.push(0);
+                Ok(Some(bytes))
+            }
+            (Some(Token::Directive { name }), values) if name == "byte" => {
+                let mut bytes = vec![];
+                for value in values {
+                    let value = match data_expr(value) {
+                        Some(expr) => expr.evaluate(symbols, here).map_err(|e| e.to_string())?,
+                        None => return Err(format!("Expected a byte value, found `{:?}`", value)),
+                    };
+                    match u8::try_from(value) {
+                        Ok(byte) => bytes.push(byte),
+                        Err(_) => {
+                            return Err(format!(
+                                "Byte value `{}` is out of range, bytes are 0 to 255",
+                                value
+                            ))
+                        }
+                    }
+                }
+                Ok(Some(bytes))
+            }
+            (Some(Token::Directive { name }), values) if name == "word" => {
+                let mut bytes = vec![];
+                for value in values {
+                    let value = match data_expr(value) {
+                        Some(expr) => expr.evaluate(symbols, here).map_err(|e| e.to_string())?,
+                        None => return Err(format!("Expected a word value, found `{:?}`", value)),
+                    };
+                    bytes.extend(value.to_le_bytes())
+                }
+                Ok(Some(bytes))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The boundary an `.align 4` directive pads the read-only data section to
+    pub fn alignment(&self) -> Result<Option<usize>, String> {
+        match (&self.directive, self.operands.as_slice()) {
+            (Some(Token::Directive { name }), [Token::IntegerOperand { value }])
+                if name == "align" =>
+            {
+                match usize::try_from(*value) {
+                    Ok(n) if n.is_power_of_two() => Ok(Some(n)),
+                    _ => Err(format!("Alignment `{}` is not a power of two", value)),
+                }
+            }
+            (Some(Token::Directive { name }), _) if name == "align" => {
+                Err("Malformed align, expected `.align n`".to_string())
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The register operands, each with how many registers of its kind the VM has when it is
+    /// made with `register_count` integer registers
+    fn register_operands(&self, register_count: usize) -> Vec<(u8, usize)> {
+        let kinds = match &self.opcode {
+            Some(Token::Op { code }) => code.operand_kinds(),
+            _ => &[],
+        };
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        let operands = operands.into_iter().enumerate().filter_map(|(i, operand)| {
+            match (operand, kinds.get(i)) {
+                (Some(Token::Register { reg_num }), Some(OperandKind::FloatRegister)) => {
+                    Some((*reg_num, FLOAT_REGISTERS))
+                }
+                (Some(Token::Register { reg_num }), _) => Some((*reg_num, register_count)),
+                _ => None,
+            }
+        });
+        // only `.alias` takes a register among the directives, an integer one
+        let directive_operands = self.operands.iter().filter_map(|operand| match operand {
+            Token::Register { reg_num } => Some((*reg_num, register_count)),
+            _ => None,
+        });
+        operands.chain(directive_operands).collect()
+    }
+
+    /// The labels the operands refer to, such as `loop` in `jeqd @loop`
+    pub fn label_usages(&self) -> impl Iterator<Item = &str> {
+        [&self.operand1, &self.operand2, &self.operand3]
+            .into_iter()
+            .flatten()
+            .chain(&self.operands)
+            .filter_map(|operand| match operand {
+                Token::LabelUsage { name } => Some(name.as_str()),
+                _ => None,
+            })
+    }
+
+    /// The labels and constants the instruction refers to, in its operands or expressions
+    pub fn symbol_references(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = self.label_usages().chain(self.entry_label()).collect();
+        let operands = [&self.operand1, &self.operand2, &self.operand3];
+        for operand in operands.into_iter().flatten().chain(&self.operands) {
+            if let Token::Expression { expr } = operand {
+                names.extend(expr.symbols());
+            }
+        }
+        // the values of `.byte` and `.word` may also name a constant or label on their own
+        let data = matches!(
+            &self.directive,
+            Some(Token::Directive { name }) if name == "byte" || name == "word"
+        );
+        if data {
+            names.extend(self.operands.iter().filter_map(|operand| match operand {
+                Token::Constant { name } | Token::RegisterAlias { name } => Some(name.as_str()),
+                _ => None,
+            }));
+        }
+        names
+    }
+
+    /// The name and value of a constant definition, such as `.equ MAX 100`
+    pub fn constant_definition(&self) -> Option<(&str, i32)> {
+        match (&self.directive, self.operands.as_slice()) {
+            (
+                Some(Token::Directive { name }),
+                [Token::Constant { name: constant }, Token::IntegerOperand { value }],
+            ) if name == "equ" => Some((constant.as_str(), *value)),
+            _ => None,
+        }
+    }
+
+    /// The label named by an entry-point directive, such as `.entry main` or `.entry @main`
+    pub fn entry_label(&self) -> Option<&str> {
+        match (&self.directive, self.operands.as_slice()) {
+            (Some(Token::Directive { name }), [Token::RegisterAlias { name: label }])
+            | (Some(Token::Directive { name }), [Token::LabelUsage { name: label }])
+                if name == "entry" =>
+            {
+                Some(label.as_str())
+            }
+            _ => None,
+        }
+    }
+
+    /// The name and register of a register alias, such as `.alias counter $5`
+    pub fn alias_definition(&self) -> Option<(&str, u8)> {
+        match (&self.directive, self.operands.as_slice()) {
+            (
+                Some(Token::Directive { name }),
+                [Token::RegisterAlias { name: alias }, Token::Register { reg_num }],
+            ) if name == "alias" => Some((alias.as_str(), *reg_num)),
+            _ => None,
+        }
+    }
+
+    /// Encodes the instruction at the code offset `here`, data directives encode to nothing
+    pub fn to_bytes(&self, symbol_tbl: &SymbolTable, here: u32) -> Result<Vec<u8>, AssemblerError> {
         let mut result = vec![];
+        if !self.is_opcode() {
+            return Ok(result);
+        }
+        let signed =
+            matches!(&self.opcode, Some(Token::Op { code }) if code.has_signed_immediate());
         if let Some(token) = &self.opcode {
             match token {
                 Token::Op { code } => {
+                    // `prints $reg` takes the address from a register instead of an immediate
+                    let code = match (code, &self.operand1) {
+                        (Opcode::PRINTS, Some(Token::Register { .. })) => &Opcode::PRINTSR,
+                        _ => code,
+                    };
                     result.push(code.into());
                 }
                 _ => {
-                    println!(
-                        "Non-opcode found in opcode field, AssemblerInstruction: `{:?}`",
-                        self
-                    );
+                    let message = format!("Non-opcode found in opcode field: `{:?}`", token);
+                    return Err(message.into());
                 }
             }
         }
@@ -59,49 +433,109 @@ impl AssemblerInstruction {
         for operand in [&self.operand1, &self.operand2, &self.operand3] {
             match operand {
                 Some(Token::Register { reg_num }) => {
-                    result.push(*reg_num as u8);
+                    result.push(*reg_num);
                 }
                 Some(Token::IntegerOperand { value }) => {
-                    let upper = ((0xFF00 & *value) >> 8) as u8;
-                    let lower = (0xFF & *value) as u8;
-                    result.push(upper);
-                    result.push(lower);
+                    push_integer(&mut result, *value, signed)?;
+                }
+                Some(Token::Expression { expr }) => {
+                    push_integer(&mut result, expr.evaluate(symbol_tbl, here)?, signed)?;
+                }
+                Some(Token::Constant { name }) => match symbol_tbl.constant_value(name) {
+                    Some(value) => push_integer(&mut result, value, signed)?,
+                    None => return Err(format!("Undefined constant: `{}`", name).into()),
+                },
+                Some(Token::RegisterAlias { name }) => match symbol_tbl.alias_register(name) {
+                    Some(reg_num) => result.push(reg_num),
+                    None => {
+                        return Err(format!("Undefined register alias: `{}`", name).into())
+                    }
+                },
+                Some(Token::FloatOperand { value }) => {
+                    let bits = f64_to_f16_bits(*value);
+                    result.push((bits >> 8) as u8);
+                    result.push((bits & 0xFF) as u8);
                 }
                 Some(Token::LabelUsage { name }) => {
-                    let offset = symbol_tbl.symbol_value(name);
-                    let offset = offset
-                        .unwrap_or_else(|| panic!("LabelUsage token has no offset: `{:?}`", self));
+                    let offset = match symbol_tbl.symbol_value(name) {
+                        Some(offset) => offset,
+                        None => {
+                            let name = name.clone();
+                            return Err(AssemblerError::UndefinedLabel { line: None, name });
+                        }
+                    };
                     let upper = ((0xFF00 & offset) >> 8) as u8;
                     let lower = (0xFF & offset) as u8;
                     result.push(upper);
                     result.push(lower);
                 }
-                Some(Token::Op { code: _ }) => {
-                    panic!(
-                        "operand should not contain opcode, AssemblerInstruction: `{:?}`",
-                        self
-                    );
+                Some(Token::Op { code }) => {
+                    return Err(format!("Opcode `{:?}` can not be an operand", code).into());
                 }
-                Some(Token::Directive { name: _ }) => {
-                    panic!(
-                        "operand should not contain directive, AssemblerInstruction: `{:?}`",
-                        self
-                    );
+                Some(Token::Directive { name }) => {
+                    return Err(format!("Directive `.{}` can not be an operand", name).into());
                 }
-                Some(Token::LabelDeclaration { name: _ }) => {
-                    panic!("operand should not contain label declaration, AssemblerInstruction: `{:?}`", self);
+                Some(Token::LabelDeclaration { name }) => {
+                    let message = format!("Label declaration `{}:` can not be an operand", name);
+                    return Err(message.into());
+                }
+                Some(Token::IrString { name }) => {
+                    let message = format!("String `'{}'` can only be the operand of .asciiz", name);
+                    return Err(message.into());
                 }
-
                 None => {}
             };
         }
 
-        assert!(result.len() <= 4);
+        if result.len() > 4 {
+            let message = format!(
+                "Operands take {} bytes, an instruction only has room for 3",
+                result.len() - 1
+            );
+            return Err(message.into());
+        }
 
         while result.len() < 4 {
             result.push(0);
         }
-        result
+        Ok(result)
+    }
+}
+
+/// Encodes an immediate into the operand bytes, it takes two bytes unless it is in the last slot.
+/// The value must fit the slot as the VM reads it back, sign-extended or zero-extended.
+fn push_integer(result: &mut Vec<u8>, value: i32, signed: bool) -> Result<(), AssemblerError> {
+    // only one byte is left for an immediate in the last operand slot
+    let bits = if result.len() == 3 { 8 } else { 16 };
+    let range = if signed {
+        -(1 << (bits - 1))..1 << (bits - 1)
+    } else {
+        0..1 << bits
+    };
+    if !range.contains(&value) {
+        let kind = if signed { " signed" } else { "" };
+        return Err(format!("Immediate `{}` does not fit in {}{} bits", value, bits, kind).into());
+    }
+    if bits == 8 {
+        result.push((0xFF & value) as u8);
+    } else {
+        let upper = ((0xFF00 & value) >> 8) as u8;
+        let lower = (0xFF & value) as u8;
+        result.push(upper);
+        result.push(lower);
+    }
+    Ok(())
+}
+
+/// The expression a value of `.byte` or `.word` stands for, such as `5`, `#'A'`, `MAX` or `2*3`
+fn data_expr(operand: &Token) -> Option<Expr> {
+    match operand {
+        Token::IntegerOperand { value } => Some(Expr::Integer(*value)),
+        Token::Expression { expr } => Some(expr.clone()),
+        Token::Constant { name } | Token::RegisterAlias { name } => {
+            Some(Expr::Symbol(name.clone()))
+        }
+        _ => None,
     }
 }
 
@@ -110,6 +544,22 @@ pub struct Program {
     instructions: Vec<AssemblerInstruction>,
 }
 
+impl Program {
+    /// The parsed instructions, one for each non-blank source line
+    pub fn instructions(&self) -> &[AssemblerInstruction] {
+        &self.instructions
+    }
+}
+
+impl std::fmt::Display for Program {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for instruction in &self.instructions {
+            writeln!(f, "{}", instruction)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 pub enum AssemblerPhase {
     First,
@@ -121,6 +571,28 @@ pub struct Assembler {
     pub phase: AssemblerPhase,
     pub symbols: SymbolTable,
     pub program: Option<Program>,
+    /// Read-only data section, addressed by the labels of its directives
+    pub ro: Vec<u8>,
+    // the macro-expanded source of the last successful `assemble`, for the listing
+    source: String,
+    // the line of `source` each instruction of `program` was parsed from
+    lines: Vec<usize>,
+    // code offset execution starts at, set by `.entry`
+    entry: u32,
+    // the label usages of the last assembled code, in code order
+    relocations: Vec<Relocation>,
+    // whether code that can run off its end is an error, see `set_require_hlt`
+    require_hlt: bool,
+    // how many integer registers the VM running the code has, see `set_register_count`
+    register_count: usize,
+    // what the last `assemble` found suspicious but assembled anyway
+    warnings: Vec<AssemblerError>,
+}
+
+impl Default for Assembler {
+    fn default() -> Assembler {
+        Assembler::new()
+    }
 }
 
 impl Assembler {
@@ -129,84 +601,510 @@ impl Assembler {
             phase: AssemblerPhase::First,
             symbols: SymbolTable::new(),
             program: None,
+            ro: vec![],
+            source: String::new(),
+            lines: vec![],
+            entry: 0,
+            relocations: vec![],
+            require_hlt: false,
+            register_count: DEFAULT_REGISTERS,
+            warnings: vec![],
         }
     }
 
-    pub fn assemble(&mut self, raw: &str) -> Option<Vec<u8>> {
-        match program(CompleteStr(raw)) {
-            Ok((_rem, program)) => {
-                let mut assembled_program = self.write_pie_header();
-                self.process_first_phase(&program);
-                let mut body = self.process_second_phase(&program);
+    /// Sets how many integer registers the VM running the code has, as for `VM::with_registers`,
+    /// so register operands are checked against it. `DEFAULT_REGISTERS` by default.
+    pub fn set_register_count(&mut self, count: usize) {
+        self.register_count = count;
+    }
+
+    /// Makes `assemble` reject code whose last instruction is neither HLT nor an unconditional
+    /// jump, which the VM would otherwise silently run off the end of. Off by default.
+    pub fn set_require_hlt(&mut self, on: bool) {
+        self.require_hlt = on;
+    }
 
-                self.program = Some(program);
-                assembled_program.append(&mut body);
-                Some(assembled_program)
+    /// What the last `assemble` found suspicious but assembled anyway, such as unused labels
+    pub fn warnings(&self) -> &[AssemblerError] {
+        &self.warnings
+    }
+
+    /// Assembles `raw`, carrying on past recoverable problems so all of them are reported
+    pub fn assemble(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let mut body = self.assemble_raw(raw)?;
+        let mut assembled_program = self.write_pie_header();
+        assembled_program.extend_from_slice(&self.ro);
+        assembled_program.append(&mut body);
+        Ok(assembled_program)
+    }
+
+    /// Assembles `raw` into the bare code section, without the PIE header or the read-only
+    /// data, which is left in `ro`. Labels are offsets into the code section either way.
+    pub fn assemble_raw(&mut self, raw: &str) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let (expanded, source_lines) =
+            preprocess(raw).map_err(|e| vec![AssemblerError::from(e)])?;
+        let raw = expanded.as_str();
+        let (program, lines, mut errors) = self.parse(raw);
+        self.process_first_phase(&program, &lines, &mut errors);
+        let body = self.process_second_phase(&program, &lines, &mut errors);
+        self.warnings = on_source_lines(unused_labels(&program, &lines), &source_lines);
+        if self.require_hlt {
+            errors.extend(check_terminator(&program, &lines));
+        }
+        if !errors.is_empty() {
+            return Err(on_source_lines(errors, &source_lines));
+        }
+        self.program = Some(program);
+        self.source = raw.to_string();
+        self.lines = lines;
+        Ok(body)
+    }
+
+    /// Assembles `raw` into an object for `link`. Unlike `assemble_raw`, labels it uses without
+    /// defining them are left for the linker to resolve instead of being errors.
+    pub fn assemble_object(&mut self, raw: &str) -> Result<AssembledObject, Vec<AssemblerError>> {
+        let (expanded, source_lines) =
+            preprocess(raw).map_err(|e| vec![AssemblerError::from(e)])?;
+        let (program, lines, mut errors) = self.parse(&expanded);
+        self.symbols = SymbolTable::new();
+        self.process_first_phase(&program, &lines, &mut errors);
+        // encoded as 0 until the linker fixes them up
+        for ins in &program.instructions {
+            for label in ins.label_usages() {
+                if self.symbols.symbol_value(label).is_none() {
+                    let symbol = Symbol::new(label.to_string(), 0, SymbolType::External);
+                    self.symbols.add_symbol(symbol);
+                }
             }
-            Err(e) => {
-                println!("There was an error assembling the code: {:?}", e);
-                None
+        }
+        let code = self.process_second_phase(&program, &lines, &mut errors);
+        // another object may use any of the labels, so none are reported as unused
+        self.warnings.clear();
+        if !errors.is_empty() {
+            return Err(on_source_lines(errors, &source_lines));
+        }
+        let labels = self.labels_of(&program);
+        let object = AssembledObject {
+            code,
+            ro: self.ro.clone(),
+            labels,
+            relocations: self.relocations.clone(),
+        };
+        self.program = Some(program);
+        self.source = expanded;
+        self.lines = lines;
+        Ok(object)
+    }
+
+    /// The labels of the last assembled program that point into the code, with their offsets
+    pub fn code_labels(&self) -> Vec<(String, usize)> {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return vec![],
+        };
+        self.labels_of(program)
+            .into_iter()
+            .filter(|label| label.section == Section::Code)
+            .map(|label| (label.name, label.offset as usize))
+            .collect()
+    }
+
+    /// The labels `p` declares, with the section each points into
+    fn labels_of(&self, p: &Program) -> Vec<ObjectLabel> {
+        p.instructions
+            .iter()
+            .filter_map(|ins| match &ins.label {
+                Some(Token::LabelDeclaration { name }) => {
+                    let data = ins.data_bytes(&self.symbols, 0);
+                    let section = match (data, ins.alignment()) {
+                        (Ok(Some(_)), _) | (_, Ok(Some(_))) => Section::Data,
+                        _ => Section::Code,
+                    };
+                    let offset = self.symbols.typed_symbol(name, SymbolType::Label)?.value;
+                    Some(ObjectLabel { name: name.clone(), section, offset })
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Runs only the first phase over `raw`, collecting its labels, constants and aliases
+    /// without encoding any instructions, such as for an editor looking up definitions
+    pub fn collect_symbols(&mut self, raw: &str) -> Result<&SymbolTable, Vec<AssemblerError>> {
+        let (expanded, source_lines) =
+            preprocess(raw).map_err(|e| vec![AssemblerError::from(e)])?;
+        let (program, lines, mut errors) = self.parse(&expanded);
+        self.symbols = SymbolTable::new();
+        self.process_first_phase(&program, &lines, &mut errors);
+        if !errors.is_empty() {
+            return Err(on_source_lines(errors, &source_lines));
+        }
+        Ok(&self.symbols)
+    }
+
+    /// Parses `raw` one line at a time, skipping lines that do not parse after noting why.
+    /// Also returns the source line of each parsed instruction. Blank input is an empty program.
+    fn parse(&self, raw: &str) -> (Program, Vec<usize>, Vec<AssemblerError>) {
+        let mut instructions = vec![];
+        let mut lines = vec![];
+        let mut errors = vec![];
+        let mut rest = raw;
+        while !rest.trim().is_empty() {
+            let start = raw.len() - rest.trim_start().len();
+            match instruction(CompleteStr(rest)) {
+                Ok((rem, ins)) if rem.0.len() < rest.len() => {
+                    let parsed = &raw[start..raw.len() - rem.0.len()];
+                    errors.extend(self.bad_register(&ins, raw, start, parsed));
+                    lines.push(line_col(raw, start).0);
+                    instructions.push(ins);
+                    rest = rem.0;
+                }
+                _ => {
+                    errors.push(self.parse_error(raw, rest));
+                    rest = match raw[start..].find('\n') {
+                        Some(end) => &raw[start + end + 1..],
+                        None => "",
+                    };
+                }
             }
         }
+        let mut program = Program { instructions };
+        resolve_local_labels(&mut program);
+        (program, lines, errors)
+    }
+
+    /// Lists each instruction of the last assembled program as `ADDR: BYTES    SOURCE`, SOURCE
+    /// being the line it was parsed from. Code addresses are offsets into the code section, data
+    /// addresses are prefixed with `ro` and are offsets into the read-only section. Instructions
+    /// that emit nothing, such as `.equ`, have no address.
+    pub fn listing(&self) -> String {
+        let program = match &self.program {
+            Some(program) => program,
+            None => return String::new(),
+        };
+        let source: Vec<&str> = self.source.lines().map(str::trim).collect();
+        let mut listing = String::new();
+        let mut pos = 0;
+        let mut ro_pos = 0;
+        for (ins, line) in program.instructions.iter().zip(&self.lines) {
+            let line = source.get(line - 1).copied().unwrap_or_default();
+            let (address, bytes) = if ins.is_opcode() {
+                let bytes = ins.to_bytes(&self.symbols, pos as u32).unwrap_or_default();
+                let address = format!("{:04X}:", pos);
+                pos += bytes.len();
+                (address, bytes)
+            } else if let Ok(Some(bytes)) = ins.data_bytes(&self.symbols, ro_pos as u32) {
+                let address = format!("ro {:04X}:", ro_pos);
+                ro_pos += bytes.len();
+                (address, bytes)
+            } else if let Ok(Some(boundary)) = ins.alignment() {
+                let address = format!("ro {:04X}:", ro_pos);
+                let padding = vec![0; padding(ro_pos, boundary)];
+                ro_pos += padding.len();
+                (address, padding)
+            } else if ins.label.is_some() {
+                (format!("{:04X}:", pos), vec![])
+            } else {
+                (String::new(), vec![])
+            };
+            let bytes: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let row = format!("{:<9}{:<15} {}", address, bytes.join(" "), line);
+            listing.push_str(row.trim_end());
+            listing.push('\n');
+        }
+        listing
+    }
+
+    /// Assembles the file at `path` along with the files it `.include`s
+    pub fn assemble_file(&mut self, path: &Path) -> Result<Vec<u8>, Vec<AssemblerError>> {
+        let source = expand_includes(path).map_err(|e| vec![AssemblerError::from(e)])?;
+        self.assemble(&source)
+    }
+
+    /// Describes why parsing stopped at `rem`, the part of `raw` the parser could not consume
+    fn parse_error(&self, raw: &str, rem: &str) -> AssemblerError {
+        let rem = rem.trim_start();
+        let (line, col) = line_col(raw, raw.len() - rem.len());
+        let error = match mnemonic(CompleteStr(rem)) {
+            Ok((_, name)) if name.0.parse::<Opcode>().is_err() => {
+                Some(AssemblerError::UnknownOpcode { line, col, name: name.0.to_string() })
+            }
+            _ => None,
+        };
+        let near = rem.lines().next().unwrap_or("");
+        let count = self.register_count;
+        let error = error.or_else(|| {
+            near.split_whitespace()
+                .filter_map(|word| word.strip_prefix('$')?.parse::<u32>().ok())
+                .find(|&number| number as usize >= count)
+                .map(|number| AssemblerError::BadRegister { line, col, number, count })
+        });
+        error.unwrap_or_else(|| AssemblerError::Parse { line, col, msg: near.to_string() })
+    }
+
+    /// Describes the first register operand of `ins` the VM does not have, `parsed` being the
+    /// source it was parsed from, starting at `start` into `raw`
+    fn bad_register(
+        &self,
+        ins: &AssemblerInstruction,
+        raw: &str,
+        start: usize,
+        parsed: &str,
+    ) -> Option<AssemblerError> {
+        let (reg_num, count) = ins
+            .register_operands(self.register_count)
+            .into_iter()
+            .find(|&(reg_num, count)| reg_num as usize >= count)?;
+        // points at the operand, `$4` not matching the start of `$40`
+        let operand = format!("${}", reg_num);
+        let at = parsed.match_indices(&operand).map(|(i, _)| i).find(|&i| {
+            !parsed[i + operand.len()..].starts_with(|c: char| c.is_ascii_digit())
+        });
+        let (line, col) = line_col(raw, start + at.unwrap_or(0));
+        Some(AssemblerError::BadRegister { line, col, number: reg_num as u32, count })
     }
 
     pub fn get_assembled_program(&self) -> Option<&Program> {
         self.program.as_ref()
     }
 
-    fn extract_labels(&mut self, p: &Program) {
+    fn extract_labels(&mut self, p: &Program, lines: &[usize], errors: &mut Vec<AssemblerError>) {
         let mut pos = 0;
-        for ins in p.instructions.iter() {
+        let mut declared = HashSet::new();
+        for (ins, &line) in p.instructions.iter().zip(lines) {
+            let alignment = match ins.alignment() {
+                Ok(alignment) => alignment,
+                Err(e) => {
+                    errors.push(AssemblerError::from(e).on_line(line));
+                    None
+                }
+            };
+            if let Some(boundary) = alignment {
+                let len = self.ro.len() + padding(self.ro.len(), boundary);
+                self.ro.resize(len, 0);
+            }
+            // data is laid out here, so its expressions can only use the labels above it
+            let data = match ins.data_bytes(&self.symbols, self.ro.len() as u32) {
+                Ok(data) => data,
+                Err(e) => {
+                    errors.push(AssemblerError::from(e).on_line(line));
+                    None
+                }
+            };
             match &ins.label {
+                Some(Token::LabelDeclaration { name }) if !declared.insert(name) => {
+                    errors.push(AssemblerError::DuplicateLabel { line, name: name.clone() });
+                }
                 Some(Token::LabelDeclaration { name }) => {
-                    let symbel = Symbol::new(name.clone(), pos, SymbolType::Label);
+                    // labels on data directives address the read-only section instead of the code
+                    let offset = match (&data, alignment) {
+                        (None, None) => pos,
+                        _ => self.ro.len() as u32,
+                    };
+                    let symbel = Symbol::new(name.clone(), offset, SymbolType::Label);
                     self.symbols.add_symbol(symbel);
                 }
                 _ => {}
             }
-            pos += 4;
+            if let Some((name, value)) = ins.constant_definition() {
+                let symbol = Symbol::new(name.to_string(), value as u32, SymbolType::Constant);
+                self.symbols.add_symbol(symbol);
+            }
+            if let Some((name, reg_num)) = ins.alias_definition() {
+                if name.parse::<Opcode>().is_ok() {
+                    let message = format!("Register alias `{}` conflicts with an opcode", name);
+                    errors.push(AssemblerError::from(message).on_line(line));
+                    continue;
+                }
+                let symbol = Symbol::new(name.to_string(), reg_num as u32, SymbolType::RegisterAlias);
+                self.symbols.add_symbol(symbol);
+            }
+            if let Some(mut bytes) = data {
+                self.ro.append(&mut bytes);
+            }
+            if ins.is_opcode() {
+                pos += 4;
+            }
         }
     }
 
-    fn process_first_phase(&mut self, p: &Program) {
-        self.extract_labels(p);
+    fn process_first_phase(
+        &mut self,
+        p: &Program,
+        lines: &[usize],
+        errors: &mut Vec<AssemblerError>,
+    ) {
+        self.ro.clear();
+        self.extract_labels(p, lines, errors);
+        self.entry = 0;
+        for (ins, &line) in p.instructions.iter().zip(lines) {
+            if let Some(label) = ins.entry_label() {
+                match self.symbols.typed_symbol(label, SymbolType::Label) {
+                    Some(symbol) => self.entry = symbol.value,
+                    None => {
+                        let message = format!("Undefined entry label: `{}`", label);
+                        errors.push(AssemblerError::from(message).on_line(line));
+                    }
+                }
+            }
+        }
         self.phase = AssemblerPhase::Second;
     }
 
-    fn process_second_phase(&mut self, p: &Program) -> Vec<u8> {
+    fn process_second_phase(
+        &mut self,
+        p: &Program,
+        lines: &[usize],
+        errors: &mut Vec<AssemblerError>,
+    ) -> Vec<u8> {
         let mut program = vec![];
-        for i in &p.instructions {
-            let mut bytes = i.to_bytes(&self.symbols);
-            program.append(&mut bytes);
+        self.relocations.clear();
+        for (i, &line) in p.instructions.iter().zip(lines) {
+            if i.is_opcode() {
+                for label in i.label_usages() {
+                    self.relocations.push(Relocation {
+                        offset: program.len() as u32,
+                        label: label.to_string(),
+                    });
+                }
+            }
+            match i.to_bytes(&self.symbols, program.len() as u32) {
+                Ok(mut bytes) => program.append(&mut bytes),
+                Err(e) => errors.push(e.on_line(line)),
+            }
         }
         program
     }
 
+    /// Where the last assembled code uses labels, in code order
+    pub fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
     fn write_pie_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in PIE_HEADER_PREFIX {
-            header.push(byte);
+        pie_header(self.ro.len() as u32, self.entry)
+    }
+}
+
+/// Gives every numeric local label, such as `1:`, a name of its own, then points each `@1f` at
+/// the next such label after its line and each `@1b` at the latest one up to and including it.
+/// References with no such label are left as they are, to be reported as undefined.
+fn resolve_local_labels(p: &mut Program) {
+    // the number, instruction index and unique name of every local label in order
+    let mut locals: Vec<(String, usize, String)> = vec![];
+    for (index, ins) in p.instructions.iter_mut().enumerate() {
+        if let Some(Token::LabelDeclaration { name }) = &mut ins.label {
+            if is_local_label(name) {
+                let count = locals.iter().filter(|(number, _, _)| number == name).count();
+                let unique = format!("{}~{}", name, count);
+                locals.push((name.clone(), index, unique.clone()));
+                *name = unique;
+            }
         }
+    }
+    for (index, ins) in p.instructions.iter_mut().enumerate() {
+        for operand in [&mut ins.operand1, &mut ins.operand2, &mut ins.operand3] {
+            let name = match operand {
+                Some(Token::LabelUsage { name }) => name,
+                _ => continue,
+            };
+            let target = if let Some(number) = name.strip_suffix('f') {
+                locals
+                    .iter()
+                    .find(|(n, at, _)| is_local_label(number) && n == number && *at > index)
+            } else if let Some(number) = name.strip_suffix('b') {
+                locals
+                    .iter()
+                    .rev()
+                    .find(|(n, at, _)| is_local_label(number) && n == number && *at <= index)
+            } else {
+                None
+            };
+            if let Some((_, _, unique)) = target {
+                *name = unique.clone();
+            }
+        }
+    }
+}
+
+/// How many zero bytes take `len` bytes up to a multiple of `boundary`
+fn padding(len: usize, boundary: usize) -> usize {
+    (boundary - len % boundary) % boundary
+}
+
+/// Whether a label is a numeric local label, which may be declared more than once
+fn is_local_label(name: &str) -> bool {
+    !name.is_empty() && name.bytes().all(|b| b.is_ascii_digit())
+}
 
-        while header.len() < PIE_HEADER_LENGTH {
-            header.push(0);
+/// An error on the last instruction of `p` if execution can fall off its end from there
+fn check_terminator(p: &Program, lines: &[usize]) -> Option<AssemblerError> {
+    let (ins, &line) = p.instructions.iter().zip(lines).rev().find(|(i, _)| i.is_opcode())?;
+    match &ins.opcode {
+        Some(Token::Op {
+            code: Opcode::HLT | Opcode::JMP | Opcode::JMPF | Opcode::JMPB | Opcode::JMPFI
+            | Opcode::JMPBI | Opcode::RET,
+        }) => None,
+        Some(Token::Op { code }) => {
+            let name = format!("{:?}", code).to_lowercase();
+            let message = format!("Program ends with `{}`, not HLT or an unconditional jump", name);
+            Some(AssemblerError::from(message).on_line(line))
         }
-        header
+        _ => None,
     }
 }
 
+/// Warns of each label declared in `p` that nothing refers to
+fn unused_labels(p: &Program, lines: &[usize]) -> Vec<AssemblerError> {
+    let used: HashSet<&str> = p.instructions.iter().flat_map(|i| i.symbol_references()).collect();
+    p.instructions
+        .iter()
+        .zip(lines)
+        .filter_map(|(ins, &line)| match &ins.label {
+            Some(Token::LabelDeclaration { name }) if !used.contains(name.as_str()) => {
+                Some(AssemblerError::UnusedLabel { line, name: name.clone() })
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Expands macros, then the pseudo-instructions they and the source may use. Also returns the
+/// source line each line of the expanded source came from.
+fn preprocess(raw: &str) -> Result<(String, Vec<usize>), String> {
+    let (expanded, macro_lines) = expand_macros(raw)?;
+    let (expanded, pseudo_lines) = expand_pseudo_instructions(&expanded)?;
+    let source_lines = pseudo_lines.iter().map(|line| macro_lines[line - 1]).collect();
+    Ok((expanded, source_lines))
+}
+
+/// Moves each problem onto the source line its preprocessed line came from
+fn on_source_lines(errors: Vec<AssemblerError>, source_lines: &[usize]) -> Vec<AssemblerError> {
+    errors.into_iter().map(|e| e.on_source_line(source_lines)).collect()
+}
+
+/// Maps a byte offset into `source` to a 1-based (line, column) pair
+pub fn line_col(source: &str, offset: usize) -> (usize, usize) {
+    let consumed = &source[..offset];
+    let line = consumed.matches('\n').count() + 1;
+    let line_start = consumed.rfind('\n').map_or(0, |i| i + 1);
+    let column = consumed[line_start..].chars().count() + 1;
+    (line, column)
+}
+
 #[derive(Debug)]
 pub struct Symbol {
     name: String,
-    offset: u32,
+    /// The offset a label points to, or the value of a constant
+    value: u32,
     symbol_type: SymbolType,
 }
 
 impl Symbol {
-    pub fn new(name: String, offset: u32, symbol_type: SymbolType) -> Self {
+    pub fn new(name: String, value: u32, symbol_type: SymbolType) -> Self {
         Symbol {
             name,
-            offset,
+            value,
             symbol_type,
         }
     }
@@ -219,6 +1117,10 @@ impl Symbol {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SymbolType {
     Label,
+    Constant,
+    RegisterAlias,
+    /// A label used but defined in another object, see `assemble_object`
+    External,
 }
 
 #[derive(Debug)]
@@ -226,6 +1128,12 @@ pub struct SymbolTable {
     symbols: Vec<Symbol>,
 }
 
+impl Default for SymbolTable {
+    fn default() -> SymbolTable {
+        SymbolTable::new()
+    }
+}
+
 impl SymbolTable {
     pub fn new() -> SymbolTable {
         SymbolTable { symbols: vec![] }
@@ -238,14 +1146,35 @@ impl SymbolTable {
     pub fn symbol_value(&self, s: &str) -> Option<u32> {
         for symbol in &self.symbols {
             if symbol.name == s {
-                return Some(symbol.offset);
+                return Some(symbol.value);
             }
         }
         None
     }
+
+    fn typed_symbol(&self, s: &str, symbol_type: SymbolType) -> Option<&Symbol> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == s && symbol.symbol_type == symbol_type)
+    }
+
+    pub fn constant_value(&self, s: &str) -> Option<i32> {
+        self.typed_symbol(s, SymbolType::Constant)
+            .map(|symbol| symbol.value as i32)
+    }
+
+    pub fn label_value(&self, s: &str) -> Option<i32> {
+        self.typed_symbol(s, SymbolType::Label)
+            .map(|symbol| symbol.value as i32)
+    }
+
+    pub fn alias_register(&self, s: &str) -> Option<u8> {
+        self.typed_symbol(s, SymbolType::RegisterAlias)
+            .map(|symbol| symbol.value as u8)
+    }
 }
 
-#[allow(unused_imports)]
+#[cfg(test)]
 mod tests {
     use super::*;
     use crate::vm::VM;
@@ -265,9 +1194,8 @@ mod tests {
     #[test]
     fn test_assemble_program() {
         let mut asm = Assembler::new();
-        let test_string = "load $0 #100\ntest: inc $2\nneq $0 $2\njeqd @test\nhlt";
-        let program = asm.assemble(test_string).unwrap();
-        let program = program[64..].to_vec();   // trim PIE header
+        let test_string = "load $0 #100\ntest: inc $2\nneq $0 $2\njneqd @test\nhlt";
+        let program = asm.assemble_raw(test_string).unwrap();
         assert_eq!(program.len(), 20);
 
         let prog = asm.get_assembled_program().unwrap();
@@ -281,7 +1209,8 @@ mod tests {
                 directive: None,
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::IntegerOperand { value: 100 }),
-                operand3: None
+                operand3: None,
+                operands: vec![]
             }
         );
 
@@ -295,7 +1224,8 @@ mod tests {
                 directive: None,
                 operand1: Some(Token::Register { reg_num: 2 }),
                 operand2: None,
-                operand3: None
+                operand3: None,
+                operands: vec![]
             }
         );
 
@@ -307,21 +1237,23 @@ mod tests {
                 directive: None,
                 operand1: Some(Token::Register { reg_num: 0 }),
                 operand2: Some(Token::Register { reg_num: 2 }),
-                operand3: None
+                operand3: None,
+                operands: vec![]
             }
         );
 
         assert_eq!(
             instructions[3],
             AssemblerInstruction {
-                opcode: Some(Token::Op { code: Opcode::JEQD }),
+                opcode: Some(Token::Op { code: Opcode::JNEQD }),
                 label: None,
                 directive: None,
                 operand1: Some(Token::LabelUsage {
                     name: "test".to_string()
                 }),
                 operand2: None,
-                operand3: None
+                operand3: None,
+                operands: vec![]
             }
         );
 
@@ -333,7 +1265,8 @@ mod tests {
                 directive: None,
                 operand1: None,
                 operand2: None,
-                operand3: None
+                operand3: None,
+                operands: vec![]
             }
         );
 
@@ -341,7 +1274,687 @@ mod tests {
         let mut vm = VM::new();
         vm.add_bytes(program);
         vm.run();
-        assert_eq!(vm.pc, 17);
-        assert_eq!(vm.registers[0], vm.registers[2]);
+        assert_eq!(vm.pc(), 17);
+        assert_eq!(vm.registers()[0], vm.registers()[2]);
+    }
+
+    #[test]
+    fn test_assemble_empty() {
+        for source in ["", "   \n"] {
+            let mut asm = Assembler::new();
+            assert_eq!(asm.assemble(source), Ok(pie_header(0, 0)));
+            assert!(asm.get_assembled_program().unwrap().instructions().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_assemble_raw() {
+        let mut asm = Assembler::new();
+        let source = "msg: .asciiz 'Hi'\nloop: inc $0\njeqd @loop\nhlt";
+        let raw = asm.assemble_raw(source).unwrap();
+        assert_eq!(raw.len(), 12);
+        assert_eq!(raw[0..4].to_vec(), vec![Opcode::INC.into(), 0, 0, 0]);
+        assert_eq!(raw[4..8].to_vec(), vec![Opcode::JEQD.into(), 0, 0, 0]);
+        assert_eq!(asm.ro, b"Hi\0".to_vec());
+
+        let full = asm.assemble(source).unwrap();
+        assert_eq!(full[PIE_HEADER_LENGTH + 3..].to_vec(), raw);
+    }
+
+    #[test]
+    fn test_assemble_li() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble_raw("li $0 #-2\nhlt").unwrap();
+        assert_eq!(program[0..4].to_vec(), vec![Opcode::LOADS.into(), 0, 0xFF, 0xFE]);
+        assert_eq!(program.len(), 8);
+
+        let mut asm = Assembler::new();
+        let source = "li $0 #305419896\nafter: hlt\njeqd @after";
+        let program = asm.assemble_raw(source).unwrap();
+        assert_eq!(program[0..4].to_vec(), vec![Opcode::MOVLO.into(), 0, 0x56, 0x78]);
+        assert_eq!(program[4..8].to_vec(), vec![Opcode::MOVHI.into(), 0, 0x12, 0x34]);
+        // the label after the two-instruction expansion
+        assert_eq!(asm.symbols.symbol_value("after"), Some(8));
+        assert_eq!(program[12..16].to_vec(), vec![Opcode::JEQD.into(), 0, 8, 0]);
+
+        let mut test_vm = VM::new();
+        test_vm.program = program;
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 0x1234_5678);
+    }
+
+    #[test]
+    fn test_relocations() {
+        let mut asm = Assembler::new();
+        assert!(asm.relocations().is_empty());
+        let source = "msg: .asciiz 'Hi'\nload $0 #1\nloop: prints @msg\ninc $0\njeqd @loop\nhlt";
+        asm.assemble(source).unwrap();
+        assert_eq!(
+            asm.relocations(),
+            &[
+                Relocation { offset: 4, label: "msg".to_string() },
+                Relocation { offset: 12, label: "loop".to_string() },
+            ]
+        );
+
+        asm.assemble("hlt").unwrap();
+        assert!(asm.relocations().is_empty());
+    }
+
+    #[test]
+    fn test_require_hlt() {
+        let mut asm = Assembler::new();
+        assert!(asm.assemble("load $0 #1\ninc $0").is_ok());
+
+        asm.set_require_hlt(true);
+        assert_eq!(
+            messages(asm.assemble("load $0 #1\ninc $0\nmsg: .asciiz 'Hi'")),
+            vec!["Error at line 2: Program ends with `inc`, not HLT or an unconditional jump"]
+        );
+        assert!(asm.assemble("load $0 #1\nhlt\nmsg: .asciiz 'Hi'").is_ok());
+        assert!(asm.assemble("loop: inc $0\njmpbi #8").is_ok());
+        assert!(asm.assemble("jeqd @end\nend: jneqd @end").is_err());
+    }
+
+    #[test]
+    fn test_local_labels() {
+        let mut asm = Assembler::new();
+        let source = "1: inc $0\neq $0 $1\njneqd @1b\njeqd @1f\nnop\n\
+                      1: dec $0\neq $0 $2\njneqd @1b\nhlt";
+        let program = asm.assemble_raw(source).unwrap();
+        assert_eq!(program[8..12].to_vec(), vec![Opcode::JNEQD.into(), 0, 0, 0]);
+        assert_eq!(program[12..16].to_vec(), vec![Opcode::JEQD.into(), 0, 20, 0]);
+        assert_eq!(program[28..32].to_vec(), vec![Opcode::JNEQD.into(), 0, 20, 0]);
+
+        // a backward reference may name the label on its own line
+        let mut asm = Assembler::new();
+        let program = asm.assemble_raw("nop\n1: jeqd @1b\nhlt").unwrap();
+        assert_eq!(program[4..8].to_vec(), vec![Opcode::JEQD.into(), 0, 4, 0]);
+
+        let result = Assembler::new().assemble("1: nop\njeqd @1f\njeqd @2b\nhlt");
+        assert_eq!(
+            messages(result),
+            vec![
+                "Error at line 2: Undefined label: `1f`",
+                "Error at line 3: Undefined label: `2b`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_collect_symbols() {
+        let mut asm = Assembler::new();
+        let source = "msg: .asciiz 'Hi'\n.equ MAX 10\nload $0 #MAX\nloop: inc $0\njmpbi #8\nhlt";
+        let symbols = asm.collect_symbols(source).unwrap();
+        assert_eq!(symbols.symbol_value("loop"), Some(4));
+        assert_eq!(symbols.symbol_value("msg"), Some(0));
+        assert_eq!(symbols.constant_value("MAX"), Some(10));
+        assert_eq!(symbols.symbol_value("missing"), None);
+        assert!(asm.get_assembled_program().is_none());
+
+        // a use of an undefined label is only caught when encoding
+        assert!(asm.collect_symbols("jeqd @nowhere\nhlt").is_ok());
+        let result = asm.collect_symbols("loop: inc $0\nfrobnicate $0");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 2, column 1: Unknown opcode: `frobnicate`"]
+        );
+    }
+
+    #[test]
+    fn test_assemble_unknown_opcode() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble("frobnicate $0");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1, column 1: Unknown opcode: `frobnicate`"]
+        );
+
+        let result = asm.assemble("load $0 #100\nfrobnicate $0\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 2, column 1: Unknown opcode: `frobnicate`"]
+        );
+    }
+
+    #[test]
+    fn test_assemble_label_only_line() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $0 #5\nloop:\ninc $1\nhlt").unwrap();
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 12);
+        assert_eq!(asm.symbols.symbol_value("loop"), Some(4));
+    }
+
+    #[test]
+    fn test_assemble_two_labels() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #5\nfirst:\nsecond: inc $1\nhlt")
+            .unwrap();
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 12);
+        assert_eq!(asm.symbols.symbol_value("first"), Some(4));
+        assert_eq!(asm.symbols.symbol_value("second"), Some(4));
+
+        let mut asm = Assembler::new();
+        asm.assemble("load $0 #5\nfirst: second: inc $1\nhlt").unwrap();
+        assert_eq!(asm.symbols.symbol_value("first"), Some(4));
+        assert_eq!(asm.symbols.symbol_value("second"), Some(4));
+    }
+
+    #[test]
+    fn test_assemble_string_data() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #1\nfirst: .asciiz 'Hi'\nsecond: .asciiz 'there'\nprints @second\nhlt")
+            .unwrap();
+        assert_eq!(asm.ro, b"Hi\0there\0".to_vec());
+        assert_eq!(asm.symbols.symbol_value("first"), Some(0));
+        assert_eq!(asm.symbols.symbol_value("second"), Some(3));
+        assert_eq!(program[4..8].to_vec(), vec![0, 0, 0, 9]);
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 9 + 12);
+        assert_eq!(
+            program[PIE_HEADER_LENGTH + 9 + 4..PIE_HEADER_LENGTH + 9 + 8].to_vec(),
+            vec![Opcode::PRINTS.into(), 0, 3, 0]
+        );
+    }
+
+    #[test]
+    fn test_assemble_byte_data() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("msg: .asciiz 'Hi'\ntable: .byte 1 2 3 255\nload $0 @table\nhlt")
+            .unwrap();
+        assert_eq!(asm.ro, vec![b'H', b'i', 0, 1, 2, 3, 255]);
+        assert_eq!(asm.symbols.symbol_value("table"), Some(3));
+        assert_eq!(
+            program[PIE_HEADER_LENGTH + 7..PIE_HEADER_LENGTH + 11].to_vec(),
+            vec![Opcode::LOAD.into(), 0, 0, 3]
+        );
+
+        let result = asm.assemble("table: .byte 1 256\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: Byte value `256` is out of range, bytes are 0 to 255"]
+        );
+
+        // bytes take the same values as words, checked against the byte range once computed
+        asm.assemble(".equ MAX 200\ntable: .byte 2*3 MAX #'A' #MAX\nload $0 @table\nhlt").unwrap();
+        assert_eq!(asm.ro, vec![6, 200, b'A', 200]);
+        let result = asm.assemble(".equ MAX 200\n.byte MAX+56 -1\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 2: Byte value `256` is out of range, bytes are 0 to 255"]
+        );
+    }
+
+    #[test]
+    fn test_program_instructions() {
+        let mut asm = Assembler::new();
+        asm.assemble("load $0 #1\nloop: inc $0\nhlt").unwrap();
+        let program = asm.get_assembled_program().unwrap();
+        assert_eq!(program.instructions().iter().count(), 3);
+        assert_eq!(program.instructions()[0].opcode, Some(Token::Op { code: Opcode::LOAD }));
+
+        let text = program.to_string();
+        let rows: Vec<String> = program.instructions().iter().map(|i| i.to_string()).collect();
+        assert_eq!(text, rows.join("\n") + "\n");
+        assert!(text.lines().nth(1).unwrap().contains("LabelDeclaration { name: \"loop\" }"));
+    }
+
+    #[test]
+    fn test_constant_expressions() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble_raw(".equ MAX 100\nload $0 #(4*8)\nload $1 #MAX-1\nload $2 #2+3*(MAX-98)/2")
+            .unwrap();
+        assert_eq!(
+            program,
+            vec![
+                Opcode::LOAD.into(), 0, 0, 32,
+                Opcode::LOAD.into(), 1, 0, 99,
+                Opcode::LOAD.into(), 2, 0, 5,
+            ]
+        );
+
+        let result = Assembler::new().assemble("load $0 #(2147483647+1)\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: Overflow in `2147483647 + 1`"]
+        );
+        let result = Assembler::new().assemble(".equ ZERO 0\nload $0 #8/ZERO\nhlt");
+        assert_eq!(messages(result), vec!["Error at line 2: Division by zero in `8 / 0`"]);
+    }
+
+    #[test]
+    fn test_current_address() {
+        let mut asm = Assembler::new();
+        let source = "msg: .asciiz 'Hello'\nlen: .word $ - msg\nload $0 @len\nload $1 #$+8\nhlt";
+        let code = asm.assemble_raw(source).unwrap();
+        assert_eq!(asm.ro, b"Hello\0\x06\0\0\0".to_vec());
+        assert_eq!(code[4..8], [Opcode::LOAD.into(), 1, 0, 12]);
+
+        // data is laid out in order, so it cannot measure up to a label below it
+        let result = Assembler::new().assemble(".word end - $\nend: .byte 0\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: Undefined constant or label: `end`"]
+        );
+    }
+
+    #[test]
+    fn test_unused_label_warning() {
+        let mut asm = Assembler::new();
+        let source = "load $0 #3\nloop: dec $0\nspare: eq $0 $1\njneqd @loop\nmsg: .asciiz 'Hi'\n";
+        asm.assemble(&(source.to_string() + "hlt")).unwrap();
+        let warnings: Vec<String> = asm.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(
+            warnings,
+            vec![
+                "Warning at line 3: Label `spare` is never used",
+                "Warning at line 5: Label `msg` is never used",
+            ]
+        );
+
+        // labels in expressions and `.entry` count as used
+        let source = ".entry start\nmsg: .asciiz 'Hi'\nlen: .word $ - msg\nstart: load $0 @len\n";
+        asm.assemble(&(source.to_string() + "hlt")).unwrap();
+        assert!(asm.warnings().is_empty());
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let error = |source: &str| match Assembler::new().assemble(source) {
+            Err(errors) => errors[0].clone(),
+            Ok(_) => panic!("`{}` assembled", source),
+        };
+        assert_eq!(
+            error("inc $0\n%%%\nhlt"),
+            AssemblerError::Parse { line: 2, col: 1, msg: "%%%".to_string() }
+        );
+        assert_eq!(
+            error("frobnicate $0\nhlt"),
+            AssemblerError::UnknownOpcode { line: 1, col: 1, name: "frobnicate".to_string() }
+        );
+        assert_eq!(
+            error("hlt\ninc $300\nhlt"),
+            AssemblerError::BadRegister { line: 2, col: 5, number: 300, count: 32 }
+        );
+        assert_eq!(
+            error("jeqd @nowhere\nhlt"),
+            AssemblerError::UndefinedLabel { line: Some(1), name: "nowhere".to_string() }
+        );
+        assert_eq!(
+            error("top: inc $0\ntop: hlt"),
+            AssemblerError::DuplicateLabel { line: 2, name: "top".to_string() }
+        );
+        let msg = "Byte value `256` is out of range, bytes are 0 to 255".to_string();
+        assert_eq!(
+            error("table: .byte 256\nhlt"),
+            AssemblerError::Other { line: Some(1), msg }
+        );
+        assert_eq!(error("inc $0\n%%%\nhlt").line(), Some(2));
+        assert_eq!(error("inc $0\n%%%\nhlt").col(), Some(1));
+
+        // the same source assembled twice is no duplicate
+        let mut asm = Assembler::new();
+        asm.assemble("top: inc $0\nhlt").unwrap();
+        asm.assemble("top: inc $0\nhlt").unwrap();
+    }
+
+    #[test]
+    fn test_align_directive() {
+        let mut asm = Assembler::new();
+        let source = "flag: .byte 1\n.align 4\ncount: .word 70000\nload $0 @count\nhlt";
+        asm.assemble(source).unwrap();
+        assert_eq!(asm.symbols.symbol_value("count"), Some(4));
+        assert_eq!(asm.ro, vec![1, 0, 0, 0, 0x70, 0x11, 1, 0]);
+
+        // already on the boundary, no padding
+        let mut asm = Assembler::new();
+        asm.assemble(".word 1\n.align 4\nend: .byte 2\nhlt").unwrap();
+        assert_eq!(asm.symbols.symbol_value("end"), Some(4));
+
+        let result = Assembler::new().assemble(".byte 1\n.align 6\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 2: Alignment `6` is not a power of two"]
+        );
+    }
+
+    #[test]
+    fn test_listing() {
+        let mut asm = Assembler::new();
+        assert_eq!(asm.listing(), "");
+        asm.assemble(".equ TOP 3\nmsg: .asciiz 'Hi'\nload $0 #TOP\nloop:\ndec $0\nhlt")
+            .unwrap();
+        assert_eq!(
+            asm.listing(),
+            concat!(
+                "                         .equ TOP 3\n",
+                "ro 0000: 48 69 00        msg: .asciiz 'Hi'\n",
+                "0000:    01 00 00 03     load $0 #TOP\n",
+                "0004:                    loop:\n",
+                "0004:    14 00 00 00     dec $0\n",
+                "0008:    00 00 00 00     hlt\n",
+            )
+        );
+
+        // several instructions can come from one line, each is listed next to it
+        asm.assemble("a: b: inc $0\ninc $1\nhlt").unwrap();
+        assert_eq!(
+            asm.listing(),
+            concat!(
+                "0000:                    a: b: inc $0\n",
+                "0000:    13 00 00 00     a: b: inc $0\n",
+                "0004:    13 01 00 00     inc $1\n",
+                "0008:    00 00 00 00     hlt\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_assemble_prints_register() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("prints $1\nprints @msg\nmsg: .asciiz 'Hi'").unwrap();
+        let code = program[PIE_HEADER_LENGTH + 3..].to_vec();
+        assert_eq!(
+            code,
+            vec![Opcode::PRINTSR.into(), 1, 0, 0, Opcode::PRINTS.into(), 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_assemble_constant() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(".equ MAX 300\nload $0 #MAX\nhlt").unwrap();
+        assert_eq!(asm.symbols.constant_value("MAX"), Some(300));
+        assert_eq!(program.len(), PIE_HEADER_LENGTH + 8);
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 4].to_vec(),
+            vec![Opcode::LOAD.into(), 0, 1, 44]
+        );
+        let result = asm.assemble(".equ MAX 300\naddi $1 $0 #MAX\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 2: Immediate `300` does not fit in 8 bits"]
+        );
+
+        let program = asm.assemble(".equ NEG -1\nloads $0 #NEG\nhlt").unwrap();
+        assert_eq!(asm.symbols.constant_value("NEG"), Some(-1));
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 4].to_vec(),
+            vec![Opcode::LOADS.into(), 0, 255, 255]
+        );
+    }
+
+    #[test]
+    fn test_assemble_immediate_out_of_range() {
+        let mut asm = Assembler::new();
+        for (source, message) in [
+            ("addi $1 $0 #-1", "Immediate `-1` does not fit in 8 bits"),
+            ("addi $1 $0 #256", "Immediate `256` does not fit in 8 bits"),
+            ("load $0 #70000", "Immediate `70000` does not fit in 16 bits"),
+            ("eqi $0 #40000", "Immediate `40000` does not fit in 16 signed bits"),
+        ] {
+            let expected = format!("Error at line 1: {}", message);
+            assert_eq!(messages(asm.assemble(source)), vec![expected]);
+        }
+        assert!(asm.assemble("addi $1 $0 #255\nload $0 #65535\neqi $0 #-32768").is_ok());
+    }
+
+    #[test]
+    fn test_assemble_negative_immediate() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("loads $0 #-1\nloads $1 #-500").unwrap();
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..].to_vec(),
+            vec![Opcode::LOADS.into(), 0, 0xFF, 0xFF, Opcode::LOADS.into(), 1, 0xFE, 0x0C]
+        );
+    }
+
+    #[test]
+    fn test_assemble_movhi_movlo() {
+        let mut asm = Assembler::new();
+        let program = asm.assemble("movlo $3 #22136\nmovhi $3 #4660").unwrap();
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..].to_vec(),
+            vec![Opcode::MOVLO.into(), 3, 0x56, 0x78, Opcode::MOVHI.into(), 3, 0x12, 0x34]
+        );
+    }
+
+    #[test]
+    fn test_assemble_lea() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("lea $1 @target\njmp $1\ninc $0\ntarget: inc $2\nhlt")
+            .unwrap();
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 4].to_vec(),
+            vec![Opcode::LEA.into(), 1, 0, 12]
+        );
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.run();
+        assert_eq!(vm.registers()[1], 12);
+        assert_eq!(vm.registers()[0], 0);
+        assert_eq!(vm.registers()[2], 1);
+    }
+
+    #[test]
+    fn test_assemble_entry() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".entry main\nmsg: .asciiz 'Hi'\nload $0 #1\nmain: load $1 #2\nhlt")
+            .unwrap();
+        assert_eq!(program[8..12].to_vec(), vec![0, 0, 0, 4]);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        assert_eq!(vm.pc(), 4);
+        vm.run();
+        assert_eq!(vm.registers()[0], 0);
+        assert_eq!(vm.registers()[1], 2);
+
+        // without `.entry` execution starts at the first instruction
+        let program = asm.assemble("load $0 #1\nhlt").unwrap();
+        assert_eq!(program[8..12].to_vec(), vec![0, 0, 0, 0]);
+
+        let result = asm.assemble(".entry nowhere\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: Undefined entry label: `nowhere`"]
+        );
+    }
+
+    #[test]
+    fn test_assemble_bad_operands() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble("load $0 'hello'\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: String `'hello'` can only be the operand of .asciiz"]
+        );
+
+        let result = asm.assemble("load #1 #2 #3\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: Operands take 5 bytes, an instruction only has room for 3"]
+        );
+
+        // the parser never puts anything but an opcode there, but to_bytes does not rely on it
+        let ins = AssemblerInstruction {
+            opcode: Some(Token::Directive { name: "word".to_string() }),
+            label: None,
+            directive: None,
+            operand1: None,
+            operand2: None,
+            operand3: None,
+            operands: vec![],
+        };
+        assert_eq!(
+            ins.to_bytes(&SymbolTable::new(), 0).unwrap_err().to_string(),
+            "Non-opcode found in opcode field: `Directive { name: \"word\" }`"
+        );
+    }
+
+    #[test]
+    fn test_assemble_undefined_constant() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble("load $0 #MAX\nhlt");
+        assert_eq!(messages(result), vec!["Error at line 1: Undefined constant: `MAX`"]);
+    }
+
+    #[test]
+    fn test_assemble_register_alias() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".alias counter $5\n.alias total $6\ninc counter\nadd total counter $1\nhlt")
+            .unwrap();
+        assert_eq!(asm.symbols.alias_register("counter"), Some(5));
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..PIE_HEADER_LENGTH + 8].to_vec(),
+            vec![Opcode::INC.into(), 5, 0, 0, Opcode::ADD.into(), 6, 5, 1]
+        );
+    }
+
+    #[test]
+    fn test_assemble_register_alias_errors() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble(".alias inc $5\nhlt");
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 1: Register alias `inc` conflicts with an opcode"]
+        );
+
+        let mut asm = Assembler::new();
+        let result = asm.assemble("inc counter\nhlt");
+        assert_eq!(messages(result), vec!["Error at line 1: Undefined register alias: `counter`"]);
+    }
+
+    #[test]
+    fn test_assemble_macro() {
+        let mut asm = Assembler::new();
+        let source = ".macro addtwo\ninc %1\ninc %1\n.endmacro\nload $0 #1\naddtwo $0\naddtwo $2\nhlt";
+        let program = asm.assemble(source).unwrap();
+        assert_eq!(asm.get_assembled_program().unwrap().instructions.len(), 6);
+        assert_eq!(
+            program[PIE_HEADER_LENGTH..].to_vec(),
+            vec![
+                Opcode::LOAD.into(), 0, 0, 1,
+                Opcode::INC.into(), 0, 0, 0,
+                Opcode::INC.into(), 0, 0, 0,
+                Opcode::INC.into(), 2, 0, 0,
+                Opcode::INC.into(), 2, 0, 0,
+                Opcode::HLT.into(), 0, 0, 0,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_macro_error_lines() {
+        let mut asm = Assembler::new();
+        let source = ".macro addtwo\ninc %1\ninc %1\n.endmacro\naddtwo $0\nfrob $0\nhlt";
+        assert_eq!(
+            messages(asm.assemble(source)),
+            vec!["Error at line 6, column 1: Unknown opcode: `frob`"]
+        );
+        let source = ".macro addtwo\ninc %1\ninc %1\n.endmacro\naddtwo $0\nhlt\nunused: hlt";
+        asm.assemble(source).unwrap();
+        let warnings: Vec<String> = asm.warnings().iter().map(|w| w.to_string()).collect();
+        assert_eq!(warnings, vec!["Warning at line 7: Label `unused` is never used"]);
+    }
+
+    #[test]
+    fn test_assemble_li_error_lines() {
+        let mut asm = Assembler::new();
+        assert_eq!(
+            messages(asm.assemble("li $0 #100000\nhlt\nfrob $0")),
+            vec!["Error at line 3, column 1: Unknown opcode: `frob`"]
+        );
+        let source = ".macro big\nli %1 #100000\nli %1 #-100000\n.endmacro\nbig $0\nfrob $0";
+        assert_eq!(
+            messages(asm.assemble(source)),
+            vec!["Error at line 6, column 1: Unknown opcode: `frob`"]
+        );
+    }
+
+    #[test]
+    fn test_line_col() {
+        let source = "load $0 #100\nhlt\n  nop";
+        assert_eq!(line_col(source, 0), (1, 1));
+        assert_eq!(line_col(source, 5), (1, 6));
+        assert_eq!(line_col(source, 13), (2, 1));
+        assert_eq!(line_col(source, 19), (3, 3));
+    }
+
+    fn messages<T: std::fmt::Debug>(result: Result<T, Vec<AssemblerError>>) -> Vec<String> {
+        result.unwrap_err().iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn test_assemble_collects_all_errors() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble("load $0 #1\nfrobnicate $0\njeqd @nowhere\nadd $0 $1 $40\nhlt");
+        assert_eq!(
+            messages(result),
+            vec![
+                "Error at line 2, column 1: Unknown opcode: `frobnicate`",
+                "Error at line 4, column 11: Register `$40` is out of range, registers are $0 to $31",
+                "Error at line 3: Undefined label: `nowhere`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_assemble_error_position() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble("load $0 #100\nload $1 #200\nload $2 100\nhlt");
+        assert_eq!(messages(result), vec!["Error at line 3, column 9: Unable to parse `100`"]);
+    }
+
+    #[test]
+    fn test_assemble_file_errors() {
+        let path = std::env::temp_dir().join("register_vm_test_assemble_file.iasm");
+        std::fs::write(&path, "load $0 #1\nfrobnicate $0\nhlt\n").unwrap();
+        let mut asm = Assembler::new();
+        let result = asm.assemble_file(&path);
+        assert_eq!(
+            messages(result),
+            vec!["Error at line 2, column 1: Unknown opcode: `frobnicate`"]
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(asm.assemble_file(&path).is_err());
+    }
+
+    #[test]
+    fn test_assemble_register_out_of_range() {
+        let mut asm = Assembler::new();
+        let result = asm.assemble("load $0 #100\nadd $0 $1 $32\nhlt");
+        assert_eq!(
+            messages(result),
+            vec![
+                "Error at line 2, column 11: Register `$32` is out of range, registers are $0 to $31"
+            ]
+        );
+        assert!(asm.assemble("load $300 #1").is_err());
+
+        // the check follows the registers the VM is made with, float registers staying at 32
+        asm.set_register_count(64);
+        assert!(asm.assemble("add $0 $40 $63\nhlt").is_ok());
+        assert_eq!(
+            messages(asm.assemble("add $0 $40 $64\nfadd $40 $1 $2\nhlt")),
+            vec![
+                concat!(
+                    "Error at line 1, column 12: ",
+                    "Register `$64` is out of range, registers are $0 to $63"
+                ),
+                concat!(
+                    "Error at line 2, column 6: ",
+                    "Register `$40` is out of range, registers are $0 to $31"
+                ),
+            ]
+        );
     }
 }
@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::instruction::Opcode;
+
+/// Reads the source at `path`, replacing each `.include "file"` line with the source of that file.
+/// Included paths are resolved relative to the directory of the file including them.
+pub fn expand_includes(path: &Path) -> Result<String, String> {
+    let mut including = HashSet::new();
+    expand_file(path, &mut including)
+}
+
+// `including` holds the files on the current include chain, meeting one of them again is a cycle
+fn expand_file(path: &Path, including: &mut HashSet<PathBuf>) -> Result<String, String> {
+    let canonical = fs::canonicalize(path)
+        .map_err(|e| format!("Unable to read `{}`: {}", path.display(), e))?;
+    if !including.insert(canonical.clone()) {
+        return Err(format!("Include cycle: `{}` includes itself", path.display()));
+    }
+    let source = fs::read_to_string(&canonical)
+        .map_err(|e| format!("Unable to read `{}`: {}", path.display(), e))?;
+    let dir = canonical.parent().unwrap_or_else(|| Path::new(""));
+
+    let mut expanded = String::new();
+    for line in source.lines() {
+        match include_target(line)? {
+            Some(target) => expanded.push_str(&expand_file(&dir.join(target), including)?),
+            None => expanded.push_str(line),
+        }
+        expanded.push('\n');
+    }
+    including.remove(&canonical);
+    Ok(expanded)
+}
+
+/// The quoted path of an `.include "file"` line, `None` for any other line
+fn include_target(line: &str) -> Result<Option<&str>, String> {
+    let rest = match line.trim().strip_prefix(".include") {
+        Some(rest) => rest.trim(),
+        None => return Ok(None),
+    };
+    if rest.len() >= 2 && rest.starts_with('"') && rest.ends_with('"') {
+        Ok(Some(&rest[1..rest.len() - 1]))
+    } else {
+        Err(format!("Malformed include: `{}`", line.trim()))
+    }
+}
+
+/// Expands invocations of macros defined between `.macro name` and `.endmacro`. A line such as
+/// `name $0 #5` is replaced by the body of `name` with `%1`, `%2`... standing for its arguments,
+/// `$1` already being a register. Bodies are not expanded again, so macros can not invoke macros.
+/// Also returns the source line each expanded line came from, definitions become blank lines.
+pub fn expand_macros(source: &str) -> Result<(String, Vec<usize>), String> {
+    let mut macros: HashMap<String, Vec<String>> = HashMap::new();
+    let mut defining: Option<(String, Vec<String>)> = None;
+    let mut expanded = String::new();
+    let mut source_lines = vec![];
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let trimmed = line.trim();
+        if defining.is_some() {
+            if trimmed == ".endmacro" {
+                let (name, body) = defining.take().unwrap();
+                macros.insert(name, body);
+            } else if trimmed.starts_with(".macro") {
+                return Err("Macros can not be defined inside a macro".to_string());
+            } else if let Some((_, body)) = defining.as_mut() {
+                body.push(line.to_string());
+            }
+            expanded.push('\n');
+            source_lines.push(number);
+            continue;
+        }
+
+        if let Some(rest) = trimmed.strip_prefix(".macro") {
+            let name = rest.trim();
+            if name.is_empty() || name.contains(char::is_whitespace) {
+                return Err(format!("Malformed macro definition: `{}`", trimmed));
+            }
+            if name.parse::<Opcode>().is_ok() {
+                return Err(format!("Macro `{}` conflicts with an opcode", name));
+            }
+            defining = Some((name.to_string(), vec![]));
+            expanded.push('\n');
+            source_lines.push(number);
+            continue;
+        }
+        if trimmed == ".endmacro" {
+            return Err("`.endmacro` without a `.macro`".to_string());
+        }
+
+        let (label, rest) = split_label(trimmed);
+        let mut words = rest.split_whitespace();
+        let name = words.next().unwrap_or("");
+        match macros.get(name) {
+            Some(body) => {
+                let args: Vec<&str> = words.collect();
+                if let Some(label) = label {
+                    expanded.push_str(label);
+                    expanded.push('\n');
+                    source_lines.push(number);
+                }
+                for body_line in body {
+                    expanded.push_str(&substitute(body_line, name, &args)?);
+                    expanded.push('\n');
+                    source_lines.push(number);
+                }
+            }
+            None => {
+                expanded.push_str(line);
+                expanded.push('\n');
+                source_lines.push(number);
+            }
+        }
+    }
+    if let Some((name, _)) = defining {
+        return Err(format!("Macro `{}` is missing its `.endmacro`", name));
+    }
+    Ok((expanded, source_lines))
+}
+
+/// Expands the `li $r #value` pseudo-instruction, which loads any i32. Values that fit in 16 bits
+/// become one `loads`, others a `movlo` of the low half followed by a `movhi` of the high half.
+/// An operand that is not a literal, such as a constant, is left to `loads`. Also returns the
+/// source line each expanded line came from.
+pub fn expand_pseudo_instructions(source: &str) -> Result<(String, Vec<usize>), String> {
+    let mut expanded = String::new();
+    let mut source_lines = vec![];
+    for (number, line) in source.lines().enumerate() {
+        let number = number + 1;
+        let (label, rest) = split_label(line.trim());
+        let mut words = rest.split_whitespace();
+        if words.next() != Some("li") {
+            expanded.push_str(line);
+            expanded.push('\n');
+            source_lines.push(number);
+            continue;
+        }
+        let args: Vec<&str> = words.collect();
+        let (register, value) = match args.as_slice() {
+            [register, value] if value.starts_with('#') => (*register, *value),
+            _ => return Err(format!("Malformed li, expected `li $r #value`: `{}`", line.trim())),
+        };
+        if let Some(label) = label {
+            expanded.push_str(label);
+            expanded.push(' ');
+        }
+        match value[1..].parse::<i32>() {
+            Ok(v) if v < i16::MIN as i32 || v > i16::MAX as i32 => {
+                let bits = v as u32;
+                expanded.push_str(&format!("movlo {} #{}\n", register, bits & 0xFFFF));
+                expanded.push_str(&format!("movhi {} #{}\n", register, bits >> 16));
+                source_lines.extend([number, number]);
+            }
+            _ => {
+                expanded.push_str(&format!("loads {} {}\n", register, value));
+                source_lines.push(number);
+            }
+        }
+    }
+    Ok((expanded, source_lines))
+}
+
+/// Splits a leading `label:` off a line
+fn split_label(line: &str) -> (Option<&str>, &str) {
+    match line.split_whitespace().next() {
+        Some(first) if first.ends_with(':') => (Some(first), line[first.len()..].trim_start()),
+        _ => (None, line),
+    }
+}
+
+/// Replaces `%1`, `%2`... in a line of the body of macro `name` with its arguments
+fn substitute(line: &str, name: &str, args: &[&str]) -> Result<String, String> {
+    let mut result = String::new();
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            result.push(c);
+            continue;
+        }
+        let mut digits = String::new();
+        while let Some(d) = chars.next_if(|d| d.is_ascii_digit()) {
+            digits.push(d);
+        }
+        if digits.is_empty() {
+            result.push(c);
+            continue;
+        }
+        let index = digits.parse::<usize>().unwrap_or(0);
+        match args.get(index.wrapping_sub(1)) {
+            Some(arg) => result.push_str(arg),
+            None => return Err(format!("Macro `{}` is missing argument %{}", name, digits)),
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `files` into a fresh directory under the system temp dir
+    fn write_files(dir_name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(dir_name);
+        let _ = fs::remove_dir_all(&dir);
+        for (name, source) in files {
+            let path = dir.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, source).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn test_include_target() {
+        assert_eq!(include_target("load $0 #1"), Ok(None));
+        assert_eq!(include_target("  .include \"lib.iasm\""), Ok(Some("lib.iasm")));
+        assert!(include_target(".include lib.iasm").is_err());
+    }
+
+    #[test]
+    fn test_expand_includes() {
+        let dir = write_files(
+            "register_vm_test_expand_includes",
+            &[
+                ("main.iasm", "load $0 #1\n.include \"lib/inc.iasm\"\nhlt"),
+                ("lib/inc.iasm", "inc $0\n.include \"dec.iasm\""),
+                ("lib/dec.iasm", "dec $1"),
+            ],
+        );
+        let source = expand_includes(&dir.join("main.iasm")).unwrap();
+        assert_eq!(source, "load $0 #1\ninc $0\ndec $1\n\n\nhlt\n");
+    }
+
+    #[test]
+    fn test_include_cycle() {
+        let dir = write_files(
+            "register_vm_test_include_cycle",
+            &[
+                ("a.iasm", "inc $0\n.include \"b.iasm\""),
+                ("b.iasm", ".include \"a.iasm\"\nhlt"),
+            ],
+        );
+        let result = expand_includes(&dir.join("a.iasm"));
+        let error = result.unwrap_err();
+        assert!(error.starts_with("Include cycle"), "{}", error);
+    }
+
+    #[test]
+    fn test_expand_macros() {
+        let source = ".macro twice\n%2 %1\n%2 %1\n.endmacro\nload $0 #1\nstart: twice $0 inc\nhlt";
+        let (expanded, source_lines) = expand_macros(source).unwrap();
+        assert_eq!(expanded, "\n\n\n\nload $0 #1\nstart:\ninc $0\ninc $0\nhlt\n");
+        assert_eq!(source_lines, vec![1, 2, 3, 4, 5, 6, 6, 6, 7]);
+    }
+
+    #[test]
+    fn test_expand_pseudo_instructions() {
+        let source = "li $0 #-5\nstart: li $1 #100000\nli $2 #-100000\nli $3 #MAX\nhlt";
+        let (expanded, source_lines) = expand_pseudo_instructions(source).unwrap();
+        assert_eq!(
+            expanded,
+            "loads $0 #-5\nstart: movlo $1 #34464\nmovhi $1 #1\nmovlo $2 #31072\n\
+             movhi $2 #65534\nloads $3 #MAX\nhlt\n"
+        );
+        assert_eq!(source_lines, vec![1, 2, 2, 3, 3, 4, 5]);
+        assert!(expand_pseudo_instructions("li $0").is_err());
+        assert!(expand_pseudo_instructions("li $0 $1").is_err());
+    }
+
+    #[test]
+    fn test_expand_macros_errors() {
+        assert_eq!(
+            expand_macros(".macro inc\nhlt\n.endmacro"),
+            Err("Macro `inc` conflicts with an opcode".to_string())
+        );
+        assert_eq!(
+            expand_macros(".macro twice\ninc %1\n"),
+            Err("Macro `twice` is missing its `.endmacro`".to_string())
+        );
+        assert_eq!(
+            expand_macros(".macro twice\ninc %2\n.endmacro\ntwice $0"),
+            Err("Macro `twice` is missing argument %2".to_string())
+        );
+        assert_eq!(
+            expand_macros("hlt\n.endmacro"),
+            Err("`.endmacro` without a `.macro`".to_string())
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::instruction::{decode_program, Instruction, Opcode, OperandKind};
+
+/// The assembly text of one instruction, such as `load $0 #100`. Addresses are shown by the name
+/// of the label in `labels` at that address, if there is one.
+pub fn instruction_text(instruction: &Instruction, labels: &[(String, usize)]) -> String {
+    let opcode = instruction.opcode();
+    let operands = instruction.operands();
+    let mut text = format!("{:?}", opcode).to_lowercase();
+    let mut slot = 0;
+    for kind in opcode.operand_kinds() {
+        let value = match kind.width() {
+            1 => operands[slot] as usize,
+            _ => ((operands[slot] as usize) << 8) | operands[slot + 1] as usize,
+        };
+        let operand = match kind {
+            OperandKind::Register | OperandKind::FloatRegister => format!("${}", value),
+            OperandKind::Imm8 | OperandKind::Imm16 | OperandKind::Offset => format!("#{}", value),
+            OperandKind::Address => match label_at(labels, value) {
+                Some(name) => format!("@{}", name),
+                None => format!("{:#06X}", value),
+            },
+            OperandKind::DataAddress => format!("{:#06X}", value),
+        };
+        text.push(' ');
+        text.push_str(&operand);
+        slot += kind.width();
+    }
+    text
+}
+
+/// Lists the instructions of `program` between the byte offsets `start` and `end` as
+/// `ADDR: TEXT`, each preceded by the labels in `labels` pointing at it
+pub fn disassemble(
+    program: &[u8],
+    start: usize,
+    end: usize,
+    labels: &[(String, usize)],
+) -> Result<Vec<String>, String> {
+    if !start.is_multiple_of(4) || !end.is_multiple_of(4) {
+        return Err(format!("Range {} to {} does not fall on instructions", start, end));
+    }
+    let end = end.min(program.len() / 4 * 4);
+    let mut lines = vec![];
+    for (offset, instruction) in decode_program(program.get(start..end).unwrap_or(&[])) {
+        let address = start + offset;
+        for (name, _) in labels.iter().filter(|(_, at)| *at == address) {
+            lines.push(format!("{}:", name));
+        }
+        let text = match instruction.opcode() {
+            Opcode::IGL => format!("igl ; byte {}", program[address]),
+            _ => instruction_text(&instruction, labels),
+        };
+        lines.push(format!("{:04X}: {}", address, text));
+    }
+    Ok(lines)
+}
+
+fn label_at(labels: &[(String, usize)], address: usize) -> Option<&str> {
+    labels
+        .iter()
+        .find(|(_, at)| *at == address)
+        .map(|(name, _)| name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+
+    #[test]
+    fn test_instruction_text() {
+        let load = Instruction::with_operands(Opcode::LOAD, [2, 0x01, 0xF4]);
+        assert_eq!(instruction_text(&load, &[]), "load $2 #500");
+        let add = Instruction::with_operands(Opcode::ADD, [0, 1, 2]);
+        assert_eq!(instruction_text(&add, &[]), "add $0 $1 $2");
+        let jump = Instruction::with_operands(Opcode::JEQD, [0, 8, 0]);
+        assert_eq!(instruction_text(&jump, &[]), "jeqd 0x0008");
+        let labels = vec![("loop".to_string(), 8)];
+        assert_eq!(instruction_text(&jump, &labels), "jeqd @loop");
+        let hlt = Instruction::with_operands(Opcode::HLT, [0, 0, 0]);
+        assert_eq!(instruction_text(&hlt, &[]), "hlt");
+    }
+
+    #[test]
+    fn test_disassemble_range() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble_raw("load $0 #3\nloop: dec $0\neq $0 $1\njneqd @loop\nhlt")
+            .unwrap();
+        let labels = asm.code_labels();
+        assert_eq!(
+            disassemble(&program, 4, 16, &labels).unwrap(),
+            vec!["loop:", "0004: dec $0", "0008: eq $0 $1", "000C: jneqd @loop"]
+        );
+        // the end is clamped to the program
+        assert_eq!(disassemble(&program, 16, 64, &labels).unwrap(), vec!["0010: hlt"]);
+        assert!(disassemble(&program, 2, 8, &labels).is_err());
+    }
+}
@@ -1,13 +1,17 @@
+#[cfg(not(feature = "std"))]
+use alloc::{format, string::String, vec::Vec};
+use core::str::FromStr;
+#[cfg(feature = "std")]
 use nom::types::CompleteStr;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
 pub enum Opcode {
     HLT,
-    LOAD,
+    LOAD, // $dst #imm, the 16-bit immediate is zero-extended, so #65535 loads 65535
     ADD,
     SUB,
     MUL,
-    DIV,
+    DIV, // $dst $a $b, signed, rounds toward zero and the remainder takes the sign of a
     JMP,
     JMPF,
     JMPB,
@@ -24,6 +28,217 @@ pub enum Opcode {
     ALOC,
     INC,
     DEC,
+    ADDI, // $dst $src #imm, the immediate is an unsigned byte in the last operand slot
+    NEG,
+    CMOVE,  // $dst $src, moves only when equal_flag is set
+    CMOVNE, // $dst $src, moves only when equal_flag is clear
+    JMPFI,  // #offset, relative to the start of the next instruction
+    JMPBI,  // #offset, relative to the start of the next instruction
+    FLOAD,  // $fdst #imm, the immediate is a 16-bit half-precision float
+    FADD,
+    FSUB,
+    FMUL,
+    FDIV,
+    ITOF, // $fdst $isrc, the first operand indexes the float registers
+    FTOI, // $idst $fsrc, the second operand indexes the float registers
+    PRINTS, // @label, prints the null-terminated string at that read-only data address
+    PRINTSR, // $addr, assembled from `prints $addr`, the address may point past the data into the heap
+    DUMP,
+    RAND,
+    RANDR, // $dst $lo $hi, a number in [registers[lo], registers[hi])
+    LOADS, // $dst #imm, like LOAD but the 16-bit immediate is sign-extended, so #-1 loads -1
+    TRAP,  // #n, calls the handler installed in trap vector n
+    RET,   // returns to the address pushed by the last TRAP
+    GETC,  // $dst, reads one byte from the input, -1 at end of input
+    PUTC,  // $src, writes the low byte of the register to the output
+    SHR,   // $dst $src $amount, arithmetic, the sign bit fills in from the left
+    USHR,  // $dst $src $amount, logical, zeros fill in from the left
+    MIN,
+    MAX,
+    ABS,   // $dst $src, sets the overflow flag for the most negative value
+    SB,    // $val $addr, stores the low byte of val at addr in the heap
+    LB,    // $dst $addr, loads the byte at addr, zero-extended
+    ADDS,
+    SUBS,
+    MULS,
+    MOVLO, // $dst #imm, sets the low 16 bits and clears the high ones
+    MOVHI, // $dst #imm, sets the high 16 bits and keeps the low ones
+    MFREM, // $dst, copies the remainder of the last DIV or DIVU into the register
+    LEA,   // $dst @label, loads the address of the label
+    JNEQD, // @label, jumps when equal_flag is clear
+    ROL,   // $dst $src $amount, the amount is taken mod the register width
+    ROR,   // $dst $src $amount, the amount is taken mod the register width
+    POPCNT, // $dst $src, the number of set bits
+    CLZ,   // $dst $src, the number of leading zero bits, the register width for zero
+    CTZ,   // $dst $src, the number of trailing zero bits, the register width for zero
+    EQI,   // $src #imm, the 16-bit immediate is sign-extended like LOADS
+    NEQI,
+    GTI,
+    LTI,
+    TIME,  // $dst, the tick count of the clock, the cycle count unless `set_clock` was called
+    JGTD,  // @label, jumps when the last comparison found left > right
+    JLTD,  // @label, jumps when the last comparison found left < right
+    JGED,  // @label, jumps when the last comparison found left >= right
+    JLED,  // @label, jumps when the last comparison found left <= right
+    MEMCPY, // $dst $src $len, copies len heap bytes, the ranges may overlap
+    MEMSET, // $addr $val $len, fills len heap bytes with the low byte of val
+    DIVU,  // $dst $a $b, like DIV with both read as unsigned, so -1 is 4294967295
+    SH,    // $val $addr, stores the low 2 bytes of val from addr on, little-endian
+    SW,    // $val $addr, stores all 4 bytes of val from addr on, little-endian
+    LH,    // $dst $addr, loads 2 bytes little-endian, zero-extended
+    LW,    // $dst $addr, loads 4 bytes little-endian
+    LBS,   // $dst $addr, like LB but sign-extended, so the byte 0xFF loads -1
+    LHS,   // $dst $addr, like LH but sign-extended
+    ENTER, // #n, saves registers 0 to n-1 for the next LEAVE, so a TRAP handler can use them
+    LEAVE, // #n, restores the registers the matching ENTER #n saved
+}
+
+/// What an operand of an instruction holds, the 16-bit kinds take two operand bytes
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OperandKind {
+    Register,
+    FloatRegister,
+    Imm8,
+    Imm16,
+    /// An absolute code address, as in `JEQD @label`
+    Address,
+    /// An address into the read-only data, as in `PRINTS @label`
+    DataAddress,
+    /// A jump distance relative to the start of the next instruction
+    Offset,
+}
+
+impl OperandKind {
+    /// Number of operand bytes the operand takes
+    pub fn width(&self) -> usize {
+        match self {
+            OperandKind::Register | OperandKind::FloatRegister | OperandKind::Imm8 => 1,
+            _ => 2,
+        }
+    }
+}
+
+impl Opcode {
+    /// The operands the VM reads for this opcode, in order, operand bytes past them are unused
+    pub fn operand_kinds(&self) -> &'static [OperandKind] {
+        use OperandKind::*;
+        match self {
+            Opcode::HLT | Opcode::IGL | Opcode::NOP | Opcode::DUMP | Opcode::RET => &[],
+            Opcode::LOAD
+            | Opcode::LOADS
+            | Opcode::MOVLO
+            | Opcode::MOVHI
+            | Opcode::LEA
+            | Opcode::EQI
+            | Opcode::NEQI
+            | Opcode::GTI
+            | Opcode::LTI => &[Register, Imm16],
+            Opcode::ADD
+            | Opcode::SUB
+            | Opcode::MUL
+            | Opcode::DIV
+            | Opcode::DIVU
+            | Opcode::RANDR
+            | Opcode::SHR
+            | Opcode::USHR
+            | Opcode::ROL
+            | Opcode::ROR
+            | Opcode::MIN
+            | Opcode::MAX
+            | Opcode::ADDS
+            | Opcode::SUBS
+            | Opcode::MULS
+            | Opcode::MEMCPY
+            | Opcode::MEMSET => &[Register, Register, Register],
+            Opcode::JMP
+            | Opcode::JMPF
+            | Opcode::JMPB
+            | Opcode::JEQ
+            | Opcode::ALOC
+            | Opcode::INC
+            | Opcode::DEC
+            | Opcode::PRINTSR
+            | Opcode::RAND
+            | Opcode::GETC
+            | Opcode::PUTC
+            | Opcode::MFREM
+            | Opcode::TIME => &[Register],
+            Opcode::EQ
+            | Opcode::NEQ
+            | Opcode::GT
+            | Opcode::LT
+            | Opcode::GTE
+            | Opcode::LTE
+            | Opcode::NEG
+            | Opcode::CMOVE
+            | Opcode::CMOVNE
+            | Opcode::ABS
+            | Opcode::POPCNT
+            | Opcode::CLZ
+            | Opcode::CTZ
+            | Opcode::SB
+            | Opcode::SH
+            | Opcode::SW
+            | Opcode::LB
+            | Opcode::LBS
+            | Opcode::LH
+            | Opcode::LHS
+            | Opcode::LW => &[Register, Register],
+            Opcode::ADDI => &[Register, Register, Imm8],
+            Opcode::JEQD
+            | Opcode::JNEQD
+            | Opcode::JGTD
+            | Opcode::JLTD
+            | Opcode::JGED
+            | Opcode::JLED => &[Address],
+            Opcode::JMPFI | Opcode::JMPBI => &[Offset],
+            Opcode::FLOAD => &[FloatRegister, Imm16],
+            Opcode::FADD | Opcode::FSUB | Opcode::FMUL | Opcode::FDIV => {
+                &[FloatRegister, FloatRegister, FloatRegister]
+            }
+            Opcode::ITOF => &[FloatRegister, Register],
+            Opcode::FTOI => &[Register, FloatRegister],
+            Opcode::PRINTS => &[DataAddress],
+            Opcode::TRAP | Opcode::ENTER | Opcode::LEAVE => &[Imm16],
+        }
+    }
+
+    /// Whether the opcode can move the pc somewhere other than the next instruction
+    pub fn is_jump(&self) -> bool {
+        self.is_conditional_jump()
+            || matches!(
+                self,
+                Opcode::JMP
+                    | Opcode::JMPF
+                    | Opcode::JMPB
+                    | Opcode::JMPFI
+                    | Opcode::JMPBI
+                    | Opcode::TRAP
+                    | Opcode::RET
+            )
+    }
+
+    /// Whether the opcode jumps only depending on the last comparison
+    pub fn is_conditional_jump(&self) -> bool {
+        matches!(
+            self,
+            Opcode::JEQ
+                | Opcode::JEQD
+                | Opcode::JNEQD
+                | Opcode::JGTD
+                | Opcode::JLTD
+                | Opcode::JGED
+                | Opcode::JLED
+        )
+    }
+
+    /// Whether the VM sign-extends the opcode's immediate, the others are zero-extended
+    pub fn has_signed_immediate(&self) -> bool {
+        matches!(
+            self,
+            Opcode::LOADS | Opcode::EQI | Opcode::NEQI | Opcode::GTI | Opcode::LTI
+        )
+    }
 }
 
 impl From<u8> for Opcode {
@@ -50,14 +265,77 @@ impl From<u8> for Opcode {
             18 => Opcode::ALOC,
             19 => Opcode::INC,
             20 => Opcode::DEC,
+            21 => Opcode::ADDI,
+            22 => Opcode::NEG,
+            23 => Opcode::CMOVE,
+            24 => Opcode::CMOVNE,
+            25 => Opcode::JMPFI,
+            26 => Opcode::JMPBI,
+            27 => Opcode::FLOAD,
+            28 => Opcode::FADD,
+            29 => Opcode::FSUB,
+            30 => Opcode::FMUL,
+            31 => Opcode::FDIV,
+            32 => Opcode::ITOF,
+            33 => Opcode::FTOI,
+            34 => Opcode::PRINTS,
+            35 => Opcode::PRINTSR,
+            36 => Opcode::DUMP,
+            37 => Opcode::RAND,
+            38 => Opcode::RANDR,
+            39 => Opcode::LOADS,
+            40 => Opcode::TRAP,
+            41 => Opcode::RET,
+            42 => Opcode::GETC,
+            43 => Opcode::PUTC,
+            44 => Opcode::SHR,
+            45 => Opcode::USHR,
+            46 => Opcode::MIN,
+            47 => Opcode::MAX,
+            48 => Opcode::ABS,
+            49 => Opcode::SB,
+            50 => Opcode::LB,
+            51 => Opcode::ADDS,
+            52 => Opcode::SUBS,
+            53 => Opcode::MULS,
+            54 => Opcode::MOVLO,
+            55 => Opcode::MOVHI,
+            56 => Opcode::MFREM,
+            57 => Opcode::LEA,
+            58 => Opcode::JNEQD,
+            59 => Opcode::ROL,
+            60 => Opcode::ROR,
+            61 => Opcode::POPCNT,
+            62 => Opcode::CLZ,
+            63 => Opcode::CTZ,
+            64 => Opcode::EQI,
+            65 => Opcode::NEQI,
+            66 => Opcode::GTI,
+            67 => Opcode::LTI,
+            68 => Opcode::TIME,
+            69 => Opcode::JGTD,
+            70 => Opcode::JLTD,
+            71 => Opcode::JGED,
+            72 => Opcode::JLED,
+            73 => Opcode::MEMCPY,
+            74 => Opcode::MEMSET,
+            75 => Opcode::DIVU,
+            76 => Opcode::SH,
+            77 => Opcode::SW,
+            78 => Opcode::LH,
+            79 => Opcode::LW,
+            80 => Opcode::LBS,
+            81 => Opcode::LHS,
+            82 => Opcode::ENTER,
+            83 => Opcode::LEAVE,
             _ => Opcode::IGL,
         }
     }
 }
 
-impl Into<u8> for &Opcode {
-    fn into(self) -> u8 {
-        match self {
+impl From<&Opcode> for u8 {
+    fn from(opcode: &Opcode) -> u8 {
+        match opcode {
             Opcode::HLT => 0,
             Opcode::LOAD => 1,
             Opcode::ADD => 2,
@@ -79,62 +357,326 @@ impl Into<u8> for &Opcode {
             Opcode::ALOC => 18,
             Opcode::INC => 19,
             Opcode::DEC => 20,
+            Opcode::ADDI => 21,
+            Opcode::NEG => 22,
+            Opcode::CMOVE => 23,
+            Opcode::CMOVNE => 24,
+            Opcode::JMPFI => 25,
+            Opcode::JMPBI => 26,
+            Opcode::FLOAD => 27,
+            Opcode::FADD => 28,
+            Opcode::FSUB => 29,
+            Opcode::FMUL => 30,
+            Opcode::FDIV => 31,
+            Opcode::ITOF => 32,
+            Opcode::FTOI => 33,
+            Opcode::PRINTS => 34,
+            Opcode::PRINTSR => 35,
+            Opcode::DUMP => 36,
+            Opcode::RAND => 37,
+            Opcode::RANDR => 38,
+            Opcode::LOADS => 39,
+            Opcode::TRAP => 40,
+            Opcode::RET => 41,
+            Opcode::GETC => 42,
+            Opcode::PUTC => 43,
+            Opcode::SHR => 44,
+            Opcode::USHR => 45,
+            Opcode::MIN => 46,
+            Opcode::MAX => 47,
+            Opcode::ABS => 48,
+            Opcode::SB => 49,
+            Opcode::LB => 50,
+            Opcode::ADDS => 51,
+            Opcode::SUBS => 52,
+            Opcode::MULS => 53,
+            Opcode::MOVLO => 54,
+            Opcode::MOVHI => 55,
+            Opcode::MFREM => 56,
+            Opcode::LEA => 57,
+            Opcode::JNEQD => 58,
+            Opcode::ROL => 59,
+            Opcode::ROR => 60,
+            Opcode::POPCNT => 61,
+            Opcode::CLZ => 62,
+            Opcode::CTZ => 63,
+            Opcode::EQI => 64,
+            Opcode::NEQI => 65,
+            Opcode::GTI => 66,
+            Opcode::LTI => 67,
+            Opcode::TIME => 68,
+            Opcode::JGTD => 69,
+            Opcode::JLTD => 70,
+            Opcode::JGED => 71,
+            Opcode::JLED => 72,
+            Opcode::MEMCPY => 73,
+            Opcode::MEMSET => 74,
+            Opcode::DIVU => 75,
+            Opcode::SH => 76,
+            Opcode::SW => 77,
+            Opcode::LH => 78,
+            Opcode::LW => 79,
+            Opcode::LBS => 80,
+            Opcode::LHS => 81,
+            Opcode::ENTER => 82,
+            Opcode::LEAVE => 83,
             _ => 255,
         }
     }
 }
 
-impl Into<u8> for Opcode {
-    fn into(self) -> u8 {
-        (&self).into()
+impl From<Opcode> for u8 {
+    fn from(opcode: Opcode) -> u8 {
+        u8::from(&opcode)
+    }
+}
+
+impl FromStr for Opcode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eq" | "EQ" => Ok(Opcode::EQ),
+            "gt" | "GT" => Ok(Opcode::GT),
+            "lt" | "LT" => Ok(Opcode::LT),
+            "add" | "ADD" => Ok(Opcode::ADD),
+            "sub" | "SUB" => Ok(Opcode::SUB),
+            "mul" | "MUL" => Ok(Opcode::MUL),
+            "div" | "DIV" => Ok(Opcode::DIV),
+            "hlt" | "HLT" => Ok(Opcode::HLT),
+            "jmp" | "JMP" => Ok(Opcode::JMP),
+            "neq" | "NEQ" => Ok(Opcode::NEQ),
+            "gte" | "GTE" => Ok(Opcode::GTE),
+            "lte" | "LTE" => Ok(Opcode::LTE),
+            "jeq" | "JEQ" => Ok(Opcode::JEQ),
+            "nop" | "NOP" => Ok(Opcode::NOP),
+            "inc" | "INC" => Ok(Opcode::INC),
+            "dec" | "DEC" => Ok(Opcode::DEC),
+            "load" | "LOAD" => Ok(Opcode::LOAD),
+            "aloc" | "ALOC" => Ok(Opcode::ALOC),
+            "jmpf" | "JMPF" => Ok(Opcode::JMPF),
+            "jmpb" | "JMPB" => Ok(Opcode::JMPB),
+            "jeqd" | "JEQD" => Ok(Opcode::JEQD),
+            "addi" | "ADDI" => Ok(Opcode::ADDI),
+            "neg" | "NEG" => Ok(Opcode::NEG),
+            "cmove" | "CMOVE" => Ok(Opcode::CMOVE),
+            "cmovne" | "CMOVNE" => Ok(Opcode::CMOVNE),
+            "jmpfi" | "JMPFI" => Ok(Opcode::JMPFI),
+            "jmpbi" | "JMPBI" => Ok(Opcode::JMPBI),
+            "fload" | "FLOAD" => Ok(Opcode::FLOAD),
+            "fadd" | "FADD" => Ok(Opcode::FADD),
+            "fsub" | "FSUB" => Ok(Opcode::FSUB),
+            "fmul" | "FMUL" => Ok(Opcode::FMUL),
+            "fdiv" | "FDIV" => Ok(Opcode::FDIV),
+            "itof" | "ITOF" => Ok(Opcode::ITOF),
+            "ftoi" | "FTOI" => Ok(Opcode::FTOI),
+            "prints" | "PRINTS" => Ok(Opcode::PRINTS),
+            "printsr" | "PRINTSR" => Ok(Opcode::PRINTSR),
+            "dump" | "DUMP" => Ok(Opcode::DUMP),
+            "rand" | "RAND" => Ok(Opcode::RAND),
+            "randr" | "RANDR" => Ok(Opcode::RANDR),
+            "loads" | "LOADS" => Ok(Opcode::LOADS),
+            "trap" | "TRAP" => Ok(Opcode::TRAP),
+            "ret" | "RET" => Ok(Opcode::RET),
+            "getc" | "GETC" => Ok(Opcode::GETC),
+            "putc" | "PUTC" => Ok(Opcode::PUTC),
+            "shr" | "SHR" => Ok(Opcode::SHR),
+            "ushr" | "USHR" => Ok(Opcode::USHR),
+            "min" | "MIN" => Ok(Opcode::MIN),
+            "max" | "MAX" => Ok(Opcode::MAX),
+            "abs" | "ABS" => Ok(Opcode::ABS),
+            "sb" | "SB" => Ok(Opcode::SB),
+            "lb" | "LB" => Ok(Opcode::LB),
+            "adds" | "ADDS" => Ok(Opcode::ADDS),
+            "subs" | "SUBS" => Ok(Opcode::SUBS),
+            "muls" | "MULS" => Ok(Opcode::MULS),
+            "movlo" | "MOVLO" => Ok(Opcode::MOVLO),
+            "movhi" | "MOVHI" => Ok(Opcode::MOVHI),
+            "mfrem" | "MFREM" => Ok(Opcode::MFREM),
+            "lea" | "LEA" => Ok(Opcode::LEA),
+            "jneqd" | "JNEQD" => Ok(Opcode::JNEQD),
+            "rol" | "ROL" => Ok(Opcode::ROL),
+            "ror" | "ROR" => Ok(Opcode::ROR),
+            "popcnt" | "POPCNT" => Ok(Opcode::POPCNT),
+            "clz" | "CLZ" => Ok(Opcode::CLZ),
+            "ctz" | "CTZ" => Ok(Opcode::CTZ),
+            "eqi" | "EQI" => Ok(Opcode::EQI),
+            "neqi" | "NEQI" => Ok(Opcode::NEQI),
+            "gti" | "GTI" => Ok(Opcode::GTI),
+            "lti" | "LTI" => Ok(Opcode::LTI),
+            "time" | "TIME" => Ok(Opcode::TIME),
+            // the ordered jumps only come in the direct form, so they also go by the short name
+            "jgtd" | "JGTD" | "jgt" | "JGT" => Ok(Opcode::JGTD),
+            "jltd" | "JLTD" | "jlt" | "JLT" => Ok(Opcode::JLTD),
+            "jged" | "JGED" | "jge" | "JGE" => Ok(Opcode::JGED),
+            "jled" | "JLED" | "jle" | "JLE" => Ok(Opcode::JLED),
+            "memcpy" | "MEMCPY" => Ok(Opcode::MEMCPY),
+            "memset" | "MEMSET" => Ok(Opcode::MEMSET),
+            "divu" | "DIVU" => Ok(Opcode::DIVU),
+            "sh" | "SH" => Ok(Opcode::SH),
+            "sw" | "SW" => Ok(Opcode::SW),
+            "lh" | "LH" => Ok(Opcode::LH),
+            "lw" | "LW" => Ok(Opcode::LW),
+            "lbs" | "LBS" => Ok(Opcode::LBS),
+            "lhs" | "LHS" => Ok(Opcode::LHS),
+            "enter" | "ENTER" => Ok(Opcode::ENTER),
+            "leave" | "LEAVE" => Ok(Opcode::LEAVE),
+            _ => Err(format!("Unknown opcode: `{}`", s)),
+        }
     }
 }
 
+#[cfg(feature = "std")]
 impl<'a> From<CompleteStr<'a>> for Opcode {
     fn from(v: CompleteStr<'a>) -> Self {
-        match v {
-            CompleteStr("eq") | CompleteStr("EQ") => Opcode::EQ,
-            CompleteStr("gt") | CompleteStr("GT") => Opcode::GT,
-            CompleteStr("lt") | CompleteStr("LT") => Opcode::LT,
-            CompleteStr("add") | CompleteStr("ADD") => Opcode::ADD,
-            CompleteStr("sub") | CompleteStr("SUB") => Opcode::SUB,
-            CompleteStr("mul") | CompleteStr("MUL") => Opcode::MUL,
-            CompleteStr("div") | CompleteStr("DIV") => Opcode::DIV,
-            CompleteStr("hlt") | CompleteStr("HLT") => Opcode::HLT,
-            CompleteStr("jmp") | CompleteStr("JMP") => Opcode::JMP,
-            CompleteStr("neq") | CompleteStr("NEQ") => Opcode::NEQ,
-            CompleteStr("gte") | CompleteStr("GTE") => Opcode::GTE,
-            CompleteStr("lte") | CompleteStr("LTE") => Opcode::LTE,
-            CompleteStr("jeq") | CompleteStr("JEQ") => Opcode::JEQ,
-            CompleteStr("nop") | CompleteStr("NOP") => Opcode::NOP,
-            CompleteStr("inc") | CompleteStr("INC") => Opcode::INC,
-            CompleteStr("dec") | CompleteStr("DEC") => Opcode::DEC,
-            CompleteStr("load") | CompleteStr("LOAD") => Opcode::LOAD,
-            CompleteStr("aloc") | CompleteStr("ALOC") => Opcode::ALOC,
-            CompleteStr("jmpf") | CompleteStr("JMPF") => Opcode::JMPF,
-            CompleteStr("jmpb") | CompleteStr("JMPB") => Opcode::JMPB,
-            CompleteStr("jeqd") | CompleteStr("JEQD") => Opcode::JEQD,
-            _ => Opcode::IGL,
-        }
+        v.0.parse().unwrap_or(Opcode::IGL)
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// A decoded instruction, the opcode followed by the three operand bytes of its 4-byte form
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub struct Instruction {
     opcode: Opcode,
+    operands: [u8; 3],
 }
 
 impl Instruction {
     pub fn new(opcode: Opcode) -> Instruction {
-        Instruction { opcode: opcode }
+        Instruction {
+            opcode,
+            operands: [0; 3],
+        }
+    }
+
+    pub fn with_operands(opcode: Opcode, operands: [u8; 3]) -> Instruction {
+        Instruction { opcode, operands }
+    }
+
+    pub fn from_bytes(bytes: [u8; 4]) -> Instruction {
+        Instruction {
+            opcode: Opcode::from(bytes[0]),
+            operands: [bytes[1], bytes[2], bytes[3]],
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        [
+            self.opcode.into(),
+            self.operands[0],
+            self.operands[1],
+            self.operands[2],
+        ]
+    }
+
+    pub fn opcode(&self) -> Opcode {
+        self.opcode
+    }
+
+    pub fn operands(&self) -> [u8; 3] {
+        self.operands
+    }
+
+    /// The second and third operand bytes read as one 16-bit immediate, as in `LOAD $0 #500`
+    pub fn imm16(&self) -> u16 {
+        ((self.operands[1] as u16) << 8) | self.operands[2] as u16
+    }
+
+    /// The first and second operand bytes read as one 16-bit address, as in `JEQD @label`
+    pub fn addr16(&self) -> u16 {
+        ((self.operands[0] as u16) << 8) | self.operands[1] as u16
+    }
+
+    /// Where this instruction, placed at `offset`, jumps to when the target is fixed in the
+    /// bytecode rather than held in a register. The target may lie outside the program.
+    pub fn static_target(&self, offset: usize) -> Option<i64> {
+        let next = offset as i64 + 4;
+        match self.opcode {
+            Opcode::JEQD
+            | Opcode::JNEQD
+            | Opcode::JGTD
+            | Opcode::JLTD
+            | Opcode::JGED
+            | Opcode::JLED => Some(self.addr16() as i64),
+            Opcode::JMPFI => Some(next + self.addr16() as i64),
+            Opcode::JMPBI => Some(next - self.addr16() as i64),
+            _ => None,
+        }
+    }
+}
+
+/// Packs an opcode and its three operand bytes into the 4-byte big-endian form the VM executes
+pub fn create_instruction(op: Opcode, r0: u8, r1: u8, r2: u8) -> u32 {
+    u32::from_be_bytes(Instruction::with_operands(op, [r0, r1, r2]).to_bytes())
+}
+
+/// Decodes each instruction of `bytes` paired with its byte offset. A tail too short to be an
+/// instruction is left out.
+pub fn decode_program(bytes: &[u8]) -> Vec<(usize, Instruction)> {
+    bytes
+        .chunks_exact(4)
+        .enumerate()
+        .map(|(i, b)| (i * 4, Instruction::from_bytes([b[0], b[1], b[2], b[3]])))
+        .collect()
+}
+
+/// Encodes a float as IEEE 754 half precision, rounding to the nearest representable value
+pub fn f64_to_f16_bits(value: f64) -> u16 {
+    let sign = if value.is_sign_negative() { 0x8000 } else { 0 };
+    let abs = value.abs();
+    if abs.is_nan() {
+        return 0x7E00;
+    }
+    if abs >= 65520.0 {
+        // rounds up past the largest finite half (65504)
+        return sign | 0x7C00;
+    }
+    if abs < pow2(-14) {
+        // subnormal, rounding up into the smallest normal still yields the right bits
+        return sign | round(abs / pow2(-24));
+    }
+    // abs is a normal f64 here, so its biased exponent is floor(log2(abs))
+    let mut exp = (abs.to_bits() >> 52) as i32 - 1023;
+    let mut frac = round((abs / pow2(exp) - 1.0) * 1024.0);
+    if frac == 1024 {
+        frac = 0;
+        exp += 1;
+    }
+    sign | (((exp + 15) as u16) << 10) | frac
+}
+
+/// Decodes an IEEE 754 half precision float
+pub fn f16_bits_to_f64(bits: u16) -> f64 {
+    let sign = if bits & 0x8000 != 0 { -1.0 } else { 1.0 };
+    let exp = ((bits >> 10) & 0x1F) as i32;
+    let frac = (bits & 0x3FF) as f64;
+    match exp {
+        0 => sign * frac * pow2(-24),
+        0x1F if frac == 0.0 => sign * f64::INFINITY,
+        0x1F => f64::NAN,
+        _ => sign * (1.0 + frac / 1024.0) * pow2(exp - 15),
     }
 }
 
-// pub create_instruction(op: Opcode, r0, r1, r2) -> u32 {}
+/// 2 to the power of `exp`, built from the bits since `powi` needs std
+fn pow2(exp: i32) -> f64 {
+    f64::from_bits(((exp + 1023) as u64) << 52)
+}
+
+/// Rounds a non-negative value below 65536 half away from zero, like `f64::round`
+fn round(value: f64) -> u16 {
+    let whole = value as u16;
+    if value - whole as f64 >= 0.5 {
+        whole + 1
+    } else {
+        whole
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    #[cfg(not(feature = "std"))]
+    use alloc::{string::ToString, vec};
 
     #[test]
     fn test_create_hlt() {
@@ -146,13 +688,114 @@ mod tests {
     fn test_create_instruction() {
         let instruction = Instruction::new(Opcode::HLT);
         assert_eq!(instruction.opcode, Opcode::HLT);
+
+        let packed = create_instruction(Opcode::ADD, 1, 2, 3);
+        assert_eq!(packed, 0x02010203);
+    }
+
+    #[test]
+    fn test_instruction_round_trip() {
+        let instruction = Instruction::with_operands(Opcode::LOAD, [4, 1, 244]);
+        let bytes = instruction.to_bytes();
+        assert_eq!(bytes, [1, 4, 1, 244]);
+        assert_eq!(Instruction::from_bytes(bytes), instruction);
+        assert_eq!(instruction.opcode(), Opcode::LOAD);
+        assert_eq!(instruction.imm16(), 500);
+
+        let instruction = Instruction::from_bytes([Opcode::JEQD.into(), 1, 2, 0]);
+        assert_eq!(instruction.opcode(), Opcode::JEQD);
+        assert_eq!(instruction.operands(), [1, 2, 0]);
+        assert_eq!(instruction.addr16(), 258);
+        assert_eq!(instruction.to_bytes(), [Opcode::JEQD.into(), 1, 2, 0]);
     }
 
     #[test]
+    #[cfg(feature = "std")]
     fn test_str_to_opcode() {
         let opcode = Opcode::from(CompleteStr("load"));
         assert_eq!(opcode, Opcode::LOAD);
         let opcode = Opcode::from(CompleteStr("illegal"));
         assert_eq!(opcode, Opcode::IGL);
     }
+
+    #[test]
+    fn test_f16_round_trip() {
+        for value in [0.0, 1.0, -2.5, 3.140625, 0.00006103515625, 65504.0] {
+            assert_eq!(f16_bits_to_f64(f64_to_f16_bits(value)), value);
+        }
+        assert_eq!(f64_to_f16_bits(1.0), 0x3C00);
+        assert_eq!(f64_to_f16_bits(-2.0), 0xC000);
+        assert_eq!(f16_bits_to_f64(f64_to_f16_bits(3.3)), 3.30078125);
+        assert_eq!(f16_bits_to_f64(f64_to_f16_bits(100000.0)), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_parse_opcode() {
+        assert_eq!("jmpf".parse::<Opcode>(), Ok(Opcode::JMPF));
+        assert_eq!(
+            "illegal".parse::<Opcode>(),
+            Err("Unknown opcode: `illegal`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_jump_classification() {
+        for opcode in [Opcode::JMP, Opcode::JMPB, Opcode::JMPFI, Opcode::TRAP, Opcode::RET] {
+            assert!(opcode.is_jump());
+            assert!(!opcode.is_conditional_jump());
+        }
+        let conditional = [
+            Opcode::JEQ,
+            Opcode::JEQD,
+            Opcode::JNEQD,
+            Opcode::JGTD,
+            Opcode::JLTD,
+            Opcode::JGED,
+            Opcode::JLED,
+        ];
+        for opcode in conditional {
+            assert!(opcode.is_jump());
+            assert!(opcode.is_conditional_jump());
+        }
+        for opcode in [Opcode::HLT, Opcode::LOAD, Opcode::EQ, Opcode::CMOVE, Opcode::IGL] {
+            assert!(!opcode.is_jump());
+            assert!(!opcode.is_conditional_jump());
+        }
+    }
+
+    #[test]
+    fn test_operand_kinds() {
+        assert!(Opcode::HLT.operand_kinds().is_empty());
+        assert_eq!(
+            Opcode::LOAD.operand_kinds(),
+            &[OperandKind::Register, OperandKind::Imm16]
+        );
+        assert_eq!(Opcode::JEQD.operand_kinds(), &[OperandKind::Address]);
+        // every layout fits in the three operand bytes
+        for byte in 0..=255u8 {
+            let width: usize = Opcode::from(byte).operand_kinds().iter().map(|k| k.width()).sum();
+            assert!(width <= 3);
+        }
+    }
+
+    #[test]
+    fn test_decode_program() {
+        let bytes = [
+            Opcode::LOAD.into(), 0, 1, 244,
+            Opcode::INC.into(), 0, 0, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+            Opcode::ADD.into(), 0, 1,
+        ];
+        let decoded = decode_program(&bytes);
+        assert_eq!(
+            decoded,
+            vec![
+                (0, Instruction::with_operands(Opcode::LOAD, [0, 1, 244])),
+                (4, Instruction::with_operands(Opcode::INC, [0, 0, 0])),
+                (8, Instruction::new(Opcode::HLT)),
+            ]
+        );
+        assert_eq!(decoded[0].1.imm16(), 500);
+        assert!(decode_program(&[Opcode::HLT.into(), 0]).is_empty());
+    }
 }
@@ -0,0 +1,77 @@
+//! The input and output the VM reads and writes: `std::io`'s with the `std` feature, and a
+//! minimal stand-in with the same method names without it, which embedders implement instead.
+
+#[cfg(feature = "std")]
+pub use std::io::{stdin, stdout, Error, Read, Result, Write};
+
+#[cfg(not(feature = "std"))]
+pub use self::core_io::*;
+
+#[cfg(not(feature = "std"))]
+mod core_io {
+    use core::fmt;
+
+    /// A read or write that could not be done
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub struct Error;
+
+    pub type Result<T> = core::result::Result<T, Error>;
+
+    /// A source of bytes, such as a UART
+    pub trait Read {
+        /// Reads into `buf`, returning how many bytes were read, 0 at the end of input
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
+    }
+
+    /// A sink for bytes, such as a UART
+    pub trait Write {
+        /// Writes from `buf`, returning how many bytes were written
+        fn write(&mut self, buf: &[u8]) -> Result<usize>;
+
+        fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
+            while !buf.is_empty() {
+                match self.write(buf)? {
+                    0 => return Err(Error),
+                    n => buf = &buf[n..],
+                }
+            }
+            Ok(())
+        }
+
+        /// Lets `write!` and `writeln!` format straight into the sink
+        fn write_fmt(&mut self, args: fmt::Arguments) -> Result<()> {
+            struct Adapter<'a, W: ?Sized>(&'a mut W);
+
+            impl<W: Write + ?Sized> fmt::Write for Adapter<'_, W> {
+                fn write_str(&mut self, s: &str) -> fmt::Result {
+                    self.0.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+                }
+            }
+
+            fmt::write(&mut Adapter(self), args).map_err(|_| Error)
+        }
+    }
+
+    /// Reads nothing and discards what is written, what a VM starts with until
+    /// `set_input` and `set_output` give it real ones
+    pub struct Null;
+
+    impl Read for Null {
+        fn read(&mut self, _buf: &mut [u8]) -> Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Null {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            Ok(buf.len())
+        }
+    }
+
+    impl Write for alloc::vec::Vec<u8> {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            self.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+}
@@ -0,0 +1,24 @@
+//! A register VM with its assembler, REPL and tools. Without the default `std` feature only the
+//! VM core is built, on `alloc`, so it can be embedded where there is no `std`, such as WASM or
+//! a microcontroller: `cargo build --no-default-features`.
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+pub mod instruction;
+pub mod io;
+pub mod pc;
+pub mod vm;
+
+#[cfg(feature = "std")]
+pub mod analysis;
+#[cfg(feature = "std")]
+pub mod assembler;
+#[cfg(feature = "std")]
+pub mod disassembler;
+#[cfg(feature = "std")]
+pub mod linker;
+#[cfg(feature = "std")]
+pub mod repl;
+#[cfg(feature = "std")]
+pub mod verifier;
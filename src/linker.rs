@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use crate::assembler::{pie_header, Relocation};
+use crate::instruction::Instruction;
+
+/// The section of an object a label points into
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Section {
+    Code,
+    Data,
+}
+
+/// A label an object defines, `offset` is relative to the start of its section in the object
+#[derive(Debug, PartialEq, Clone)]
+pub struct ObjectLabel {
+    pub name: String,
+    pub section: Section,
+    pub offset: u32,
+}
+
+/// One separately assembled unit, made by `Assembler::assemble_object`
+#[derive(Debug, PartialEq, Clone)]
+pub struct AssembledObject {
+    pub code: Vec<u8>,
+    pub ro: Vec<u8>,
+    pub labels: Vec<ObjectLabel>,
+    pub relocations: Vec<Relocation>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LinkError {
+    /// More than one object defines the label
+    DuplicateLabel { name: String },
+    /// No object defines the label
+    UndefinedLabel { name: String },
+    /// The final address of the label does not fit the 16-bit operand
+    AddressOutOfRange { name: String, address: u32 },
+}
+
+impl std::fmt::Display for LinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LinkError::DuplicateLabel { name } => {
+                write!(f, "Label `{}` is defined more than once", name)
+            }
+            LinkError::UndefinedLabel { name } => write!(f, "Undefined label: `{}`", name),
+            LinkError::AddressOutOfRange { name, address } => {
+                write!(f, "Label `{}` ends up at {}, past a 16-bit address", name, address)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Concatenates the code and the read-only data of `objects` in order into one program with a
+/// PIE header, pointing every label usage at the final address. Execution starts at the code of
+/// the first object.
+pub fn link(objects: &[AssembledObject]) -> Result<Vec<u8>, LinkError> {
+    let mut addresses: HashMap<&str, u32> = HashMap::new();
+    let mut code = vec![];
+    let mut ro = vec![];
+    for object in objects {
+        for label in &object.labels {
+            let base = match label.section {
+                Section::Code => code.len(),
+                Section::Data => ro.len(),
+            };
+            if addresses.insert(&label.name, base as u32 + label.offset).is_some() {
+                return Err(LinkError::DuplicateLabel { name: label.name.clone() });
+            }
+        }
+        code.extend_from_slice(&object.code);
+        ro.extend_from_slice(&object.ro);
+    }
+
+    let mut code_start = 0;
+    for object in objects {
+        for relocation in &object.relocations {
+            let name = &relocation.label;
+            let address = match addresses.get(name.as_str()) {
+                Some(&address) => address,
+                None => return Err(LinkError::UndefinedLabel { name: name.clone() }),
+            };
+            if address > u16::MAX as u32 {
+                return Err(LinkError::AddressOutOfRange { name: name.clone(), address });
+            }
+            let at = code_start + relocation.offset as usize;
+            let at = at + address_position(&code[at..at + 4]);
+            code[at] = (address >> 8) as u8;
+            code[at + 1] = address as u8;
+        }
+        code_start += object.code.len();
+    }
+
+    let mut program = pie_header(ro.len() as u32, 0);
+    program.append(&mut ro);
+    program.append(&mut code);
+    Ok(program)
+}
+
+/// Where in an instruction its 16-bit operand, the one a label address is encoded into, starts
+fn address_position(bytes: &[u8]) -> usize {
+    let instruction = Instruction::from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let mut position = 1;
+    for kind in instruction.opcode().operand_kinds() {
+        if kind.width() == 2 {
+            break;
+        }
+        position += kind.width();
+    }
+    position
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler::Assembler;
+    use crate::instruction::Opcode;
+    use crate::vm::VM;
+
+    fn object(source: &str) -> AssembledObject {
+        Assembler::new().assemble_object(source).unwrap()
+    }
+
+    #[test]
+    fn test_link_jump_into_other_object() {
+        let main = object("load $0 #0\nload $1 #3\neq $0 $0\njeqd @count\ndone: hlt");
+        let lib = object("count: inc $0\neq $0 $1\njneqd @count\nlea $2 @done\njeqd @done\nhlt");
+        assert_eq!(main.relocations.len(), 1);
+        // unresolved until linked
+        assert_eq!(main.code[12..16].to_vec(), vec![Opcode::JEQD.into(), 0, 0, 0]);
+
+        let program = link(&[main, lib]).unwrap();
+        let code = &program[64..];
+        // `count` moved past the 20 bytes of the first object
+        assert_eq!(code[12..16].to_vec(), vec![Opcode::JEQD.into(), 0, 20, 0]);
+        assert_eq!(code[28..32].to_vec(), vec![Opcode::JNEQD.into(), 0, 20, 0]);
+        assert_eq!(code[32..36].to_vec(), vec![Opcode::LEA.into(), 2, 0, 16]);
+        assert_eq!(code[36..40].to_vec(), vec![Opcode::JEQD.into(), 0, 16, 0]);
+
+        let mut vm = VM::new();
+        vm.load_program(program).unwrap();
+        vm.run();
+        assert_eq!(vm.registers()[0], 3);
+        assert_eq!(vm.registers()[2], 16);
+    }
+
+    #[test]
+    fn test_link_data_labels() {
+        let main = object("msg: .asciiz 'Hi'\nprints @other\nhlt");
+        let lib = object("other: .asciiz 'Yo'\nnop");
+        let program = link(&[main, lib]).unwrap();
+        assert_eq!(program[4..8].to_vec(), vec![0, 0, 0, 6]);
+        assert_eq!(program[64..70].to_vec(), b"Hi\0Yo\0".to_vec());
+        // `other` comes after the 3 bytes of `msg`
+        assert_eq!(program[70..74].to_vec(), vec![Opcode::PRINTS.into(), 0, 3, 0]);
+    }
+
+    #[test]
+    fn test_link_errors() {
+        let a = object("start: hlt");
+        let b = object("start: nop");
+        assert_eq!(
+            link(&[a.clone(), b]),
+            Err(LinkError::DuplicateLabel { name: "start".to_string() })
+        );
+        let c = object("jeqd @nowhere\nhlt");
+        assert_eq!(
+            link(&[a, c]),
+            Err(LinkError::UndefinedLabel { name: "nowhere".to_string() })
+        );
+    }
+}
@@ -1,15 +1,9 @@
-pub mod assembler;
-pub mod instruction;
-pub mod repl;
-pub mod vm;
-use std::fs::File;
-use std::io::prelude::*;
+use register_vm::{assembler, repl, vm};
 use std::path::Path;
 
 #[macro_use]
 extern crate clap;
-use clap::{App, Arg, SubCommand};
-extern crate nom;
+use clap::App;
 
 /// Starts a REPL that will run until the user kills it
 fn start_repl() {
@@ -17,44 +11,41 @@ fn start_repl() {
     repl.run();
 }
 
-/// Attempts to read a file and return the contents. Exits if unable to read the file for any reason.
-fn read_file(tmp: &str) -> String {
-    let filename = Path::new(tmp);
-    match File::open(Path::new(&filename)) {
-        Ok(mut fh) => {
-            let mut contents = String::new();
-            match fh.read_to_string(&mut contents) {
-                Ok(_) => return contents,
-                Err(e) => {
-                    println!("There was an error reading file: {:?}", e);
-                    std::process::exit(1);
-                }
-            }
-        }
-        Err(e) => {
-            println!("File not found: {:?}", e);
-            std::process::exit(1)
-        }
-    }
-}
-
 fn main() {
     let yaml = load_yaml!("cli.yml");
     let matches = App::from_yaml(yaml).get_matches();
     let target_file = matches.value_of("INPUT_FILE");
     match target_file {
         Some(filename) => {
-            let program = read_file(filename);
             let mut asm = assembler::Assembler::new();
             let mut vm = vm::VM::new();
-            let program = asm.assemble(&program);
+            // assembling from the path lets `.include` resolve relative to the file
+            let program = asm.assemble_file(Path::new(filename));
             match program {
-                Some(p) => {
-                    vm.add_bytes(p);
+                Ok(p) => {
+                    for warning in asm.warnings() {
+                        println!("{}", warning);
+                    }
+                    if let Err(e) = vm.load_program(p) {
+                        println!("Unable to load the program: {}", e);
+                        std::process::exit(1);
+                    }
                     vm.run();
+                    // diagnostics are off by default, so a failed run is only reported here
+                    if let Some(e) = vm.error() {
+                        eprintln!("The program failed: {}", e);
+                        std::process::exit(1);
+                    }
                     std::process::exit(0);
                 },
-                None => {}
+                Err(errors) => {
+                    println!("There were errors assembling the code:");
+                    // each error names the line and column it was found at
+                    for e in &errors {
+                        println!("{}", e);
+                    }
+                    std::process::exit(1);
+                }
             }
         },
         None => {
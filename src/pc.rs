@@ -0,0 +1,115 @@
+/// Why a jump was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum PcError {
+    /// The target is not the start of an instruction
+    Misaligned { target: i64 },
+    /// The target is before the start or past the end of the program
+    OutOfBounds { target: i64 },
+}
+
+impl core::fmt::Display for PcError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            PcError::Misaligned { target } => {
+                write!(f, "Jump target {} is not the start of an instruction", target)
+            }
+            PcError::OutOfBounds { target } => {
+                write!(f, "Jump target {} is outside the program", target)
+            }
+        }
+    }
+}
+
+impl core::error::Error for PcError {}
+
+/// A program counter whose moves are checked against a program of `program_len` bytes. A valid
+/// target is the start of an instruction or the very end of the program, where it finishes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Pc(pub usize);
+
+impl Pc {
+    /// Moves to the absolute offset `target`
+    pub fn jump_to(self, target: usize, program_len: usize) -> Result<Pc, PcError> {
+        check(target as i64, program_len)
+    }
+
+    /// Moves to the start of the instruction after the one `self` points into
+    pub fn advance(self, program_len: usize) -> Result<Pc, PcError> {
+        check((self.0 / 4 * 4 + 4) as i64, program_len)
+    }
+
+    /// Moves `offset` bytes forward, an offset too large for an i64 is out of bounds as well
+    pub fn offset_forward(self, offset: usize, program_len: usize) -> Result<Pc, PcError> {
+        match i64::try_from(offset).ok().and_then(|o| (self.0 as i64).checked_add(o)) {
+            Some(target) => check(target, program_len),
+            None => Err(PcError::OutOfBounds { target: i64::MAX }),
+        }
+    }
+
+    /// Moves `offset` bytes backward, an offset too large for an i64 is out of bounds as well
+    pub fn offset_backward(self, offset: usize, program_len: usize) -> Result<Pc, PcError> {
+        match i64::try_from(offset).ok().and_then(|o| (self.0 as i64).checked_sub(o)) {
+            Some(target) => check(target, program_len),
+            None => Err(PcError::OutOfBounds { target: i64::MIN }),
+        }
+    }
+}
+
+fn check(target: i64, program_len: usize) -> Result<Pc, PcError> {
+    if target < 0 || target > program_len as i64 {
+        Err(PcError::OutOfBounds { target })
+    } else if target % 4 != 0 {
+        Err(PcError::Misaligned { target })
+    } else {
+        Ok(Pc(target as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_jump_to() {
+        assert_eq!(Pc(0).jump_to(8, 12), Ok(Pc(8)));
+        // the end of the program is where it finishes
+        assert_eq!(Pc(0).jump_to(12, 12), Ok(Pc(12)));
+        assert_eq!(Pc(0).jump_to(16, 12), Err(PcError::OutOfBounds { target: 16 }));
+        assert_eq!(Pc(0).jump_to(6, 12), Err(PcError::Misaligned { target: 6 }));
+        assert_eq!(
+            Pc(0).jump_to(usize::MAX, 12),
+            Err(PcError::OutOfBounds { target: -1 })
+        );
+    }
+
+    #[test]
+    fn test_advance() {
+        assert_eq!(Pc(0).advance(8), Ok(Pc(4)));
+        // from the middle of an instruction as well
+        assert_eq!(Pc(6).advance(8), Ok(Pc(8)));
+        assert_eq!(Pc(8).advance(8), Err(PcError::OutOfBounds { target: 12 }));
+    }
+
+    #[test]
+    fn test_offsets() {
+        assert_eq!(Pc(4).offset_forward(8, 12), Ok(Pc(12)));
+        assert_eq!(Pc(4).offset_forward(12, 12), Err(PcError::OutOfBounds { target: 16 }));
+        assert_eq!(Pc(4).offset_forward(2, 12), Err(PcError::Misaligned { target: 6 }));
+        assert_eq!(Pc(8).offset_backward(8, 12), Ok(Pc(0)));
+        assert_eq!(Pc(4).offset_backward(8, 12), Err(PcError::OutOfBounds { target: -4 }));
+        assert_eq!(Pc(8).offset_backward(1, 12), Err(PcError::Misaligned { target: 7 }));
+        // a negative register reaches these as a huge offset, which must not wrap around
+        assert_eq!(
+            Pc(4).offset_forward(usize::MAX - 3, 12),
+            Err(PcError::OutOfBounds { target: i64::MAX })
+        );
+        assert_eq!(
+            Pc(4).offset_forward(i64::MAX as usize, 12),
+            Err(PcError::OutOfBounds { target: i64::MAX })
+        );
+        assert_eq!(
+            Pc(4).offset_backward(usize::MAX - 3, 12),
+            Err(PcError::OutOfBounds { target: i64::MIN })
+        );
+    }
+}
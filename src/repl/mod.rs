@@ -1,15 +1,74 @@
-use crate::assembler::Assembler;
-use crate::vm::VM;
+use crate::assembler::{pie_header, Assembler, AssemblerError, PIE_HEADER_LENGTH};
+use crate::disassembler::disassemble;
+use crate::vm::{RunOutcome, Snapshot, VM};
 use std;
+use std::collections::VecDeque;
 use std::io;
 use std::io::Write;
 use std::num::ParseIntError;
+use std::path::Path;
 
-/// Core structure for the REPL for the Assembler
+/// How many steps `.back` can undo
+const UNDO_LIMIT: usize = 64;
+
+/// What the REPL says to input once the program is finished
+const FINISHED: &str =
+    "Program finished, `.reset` starts a new one or `.back` undoes the last step";
+
+/// The dot-commands, for `.help` and completion, with what each does
+const COMMANDS: &[(&str, &str)] = &[
+    (".back", "undo the last typed step"),
+    (".debug", "print the VM's internal state"),
+    (".disassemble", "[<start> <end>] list the program's code"),
+    (".edit", "<n> recall history command n to run it or a replacement"),
+    (".enter", "type a multi-line program, ended by `.end`"),
+    (".exec", "<path> assemble and run a source file"),
+    (".help", "list these commands"),
+    (".history", "list the commands typed so far"),
+    (".load_bytes", "<path> load and run an assembled program"),
+    (".profile", "on, off or show the opcode counts"),
+    (".program", "list the program's bytes"),
+    (".quit", "leave the REPL"),
+    (".registers", "list the registers"),
+    (".reset", "start a new, empty program"),
+    (".run", "<n> run history command n again, as does `!n`"),
+    (".save", "<path> write the program for `.load_bytes`"),
+    (".step", "execute the next instruction"),
+    (".trace", "on, off or show the executed instructions"),
+    (".verbose", "on or off, whether the VM explains why it stopped"),
+    (".watch", "<register> stop when the register changes"),
+];
+
+/// The dot-commands starting with `prefix`, such as `.registers` and `.reset` for `.re`
+pub fn complete(prefix: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|(name, _)| *name)
+        .filter(|name| name.starts_with(prefix))
+        .collect()
+}
+
+/// Core structure for the REPL for the Assembler.
+///
+/// Typed lines are appended to the program and executed one at a time. Once the program halts,
+/// fails or runs off its end, whether by a typed line, `.step` or running a whole program, it is
+/// finished: further lines and steps are refused until `.reset` replaces it with an empty program
+/// or `.back` undoes the step that finished it.
 pub struct REPL {
     command_buffer: Vec<String>,
     // The VM the REPL will use to execute code
     vm: VM,
+    // the VM state and program length before each typed step, newest last
+    undo: VecDeque<(Snapshot, usize)>,
+    // the code labels of the last `.enter` program, for `.disassemble`
+    labels: Vec<(String, usize)>,
+    finished: bool,
+}
+
+impl Default for REPL {
+    fn default() -> REPL {
+        REPL::new()
+    }
 }
 
 impl REPL {
@@ -18,6 +77,9 @@ impl REPL {
         REPL {
             vm: VM::new(),
             command_buffer: vec![],
+            undo: VecDeque::new(),
+            labels: vec![],
+            finished: false,
         }
     }
 
@@ -41,58 +103,415 @@ impl REPL {
                 .read_line(&mut buffer)
                 .expect("Unable to read line from user");
             let buffer = buffer.trim();
-            // This is the line we add to store a copy of each command
-            self.command_buffer.push(buffer.to_string());
-            match buffer {
-                ".quit" => {
-                    println!("Farewell! Have a great day!");
-                    std::process::exit(0);
-                }
-                ".history" => {
-                    for command in self.command_buffer.iter() {
+            let recall = buffer.strip_prefix('!').or_else(|| buffer.strip_prefix(".run "));
+            let command = if let Some(index) = recall {
+                match self.history_entry(index) {
+                    Ok(command) => {
                         println!("{}", command);
+                        command
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                }
+            } else if let Some(index) = buffer.strip_prefix(".edit ") {
+                // stdin has no line editing, so the recalled command is shown and a new line
+                // replaces it, an empty one runs it unchanged
+                match self.history_entry(index) {
+                    Ok(command) => {
+                        println!("{}", command);
+                        print!(">>> ");
+                        io::stdout().flush().expect("Unable to flush stdout");
+                        let mut line = String::new();
+                        stdin
+                            .read_line(&mut line)
+                            .expect("Unable to read line from user");
+                        match line.trim() {
+                            "" => command,
+                            edited => edited.to_string(),
+                        }
+                    }
+                    Err(e) => {
+                        println!("{}", e);
+                        continue;
+                    }
+                }
+            } else {
+                buffer.to_string()
+            };
+            // a blank line does nothing and is not worth recalling
+            if command.is_empty() {
+                continue;
+            }
+            // This is the line we add to store a copy of each command, recalled ones as run
+            self.command_buffer.push(command.clone());
+            self.execute(&command);
+        }
+    }
+
+    /// Runs one command or line of assembly
+    fn execute(&mut self, buffer: &str) {
+        let stdin = io::stdin();
+        match buffer {
+            ".quit" => {
+                println!("Farewell! Have a great day!");
+                std::process::exit(0);
+            }
+            ".help" => {
+                for (name, description) in COMMANDS {
+                    println!("{:<14} {}", name, description);
+                }
+            }
+            ".history" => {
+                for (index, command) in self.command_buffer.iter().enumerate() {
+                    println!("{:>4}  {}", index + 1, command);
+                }
+            }
+            ".program" => {
+                println!("Listing instructions currently in VM's program vector:");
+                for instruction in &self.vm.program {
+                    println!("{}", instruction);
+                }
+                println!("End of Program Listing");
+            }
+            ".registers" => {
+                println!("Listing registers and all contents:");
+                println!("{:#?}", self.vm.registers());
+                println!("End of Register Listing")
+            }
+            ".step" => {
+                for change in self.step_changes() {
+                    println!("{}", change);
+                }
+            }
+            ".debug" => {
+                self.vm.dbg_vm();
+            }
+            ".enter" => {
+                println!("Enter a program, finish it with `.end` on its own line");
+                let mut lines = vec![];
+                loop {
+                    print!("... ");
+                    io::stdout().flush().expect("Unable to flush stdout");
+                    let mut line = String::new();
+                    let read = stdin
+                        .read_line(&mut line)
+                        .expect("Unable to read line from user");
+                    // the end of input finishes the program like `.end` does
+                    if read == 0 || line.trim() == ".end" {
+                        break;
                     }
+                    lines.push(line.trim_end().to_string());
+                }
+                match self.run_block(&lines.join("\n")) {
+                    Ok(()) if self.finished => println!("Program finished"),
+                    Ok(()) => {}
+                    Err(e) => println!("Unable to parse program: {}", e),
+                }
+            }
+            ".reset" => {
+                self.reset();
+                println!("Started a new program");
+            }
+            ".back" => {
+                if self.back() {
+                    println!("Back at pc {}", self.vm.pc());
+                } else {
+                    println!("Nothing to undo");
+                }
+            }
+            ".trace on" => {
+                self.vm.enable_trace(true);
+                println!("Tracing on");
+            }
+            ".trace off" => {
+                self.vm.enable_trace(false);
+                println!("Tracing off");
+            }
+            ".trace show" => {
+                println!("Listing executed instructions:");
+                for (pc, opcode) in self.vm.trace() {
+                    println!("{:>6}: {:?}", pc, opcode);
                 }
-                ".program" => {
-                    println!("Listing instructions currently in VM's program vector:");
-                    for instruction in &self.vm.program {
-                        println!("{}", instruction);
+                println!("End of Trace Listing");
+            }
+            ".verbose on" => {
+                self.vm.set_verbose(true);
+                println!("Verbose on");
+            }
+            ".verbose off" => {
+                self.vm.set_verbose(false);
+                println!("Verbose off");
+            }
+            ".profile on" => {
+                self.vm.enable_profile(true);
+                println!("Profiling on");
+            }
+            ".profile off" => {
+                self.vm.enable_profile(false);
+                println!("Profiling off");
+            }
+            ".profile" | ".profile show" => {
+                println!("Listing opcode counts:");
+                let mut counts: Vec<_> = self.vm.opcode_profile().iter().collect();
+                counts.sort_by(|a, b| b.1.cmp(a.1));
+                for (opcode, count) in counts {
+                    println!("{:>10} {:?}", count, opcode);
+                }
+                println!("End of Profile");
+            }
+            _ if buffer.starts_with(".load_bytes ") => {
+                let path = Path::new(buffer[".load_bytes ".len()..].trim());
+                match self.load_bytes(path) {
+                    Ok(()) => {
+                        self.run_loaded();
+                        if self.finished {
+                            println!("Program finished");
+                        }
                     }
-                    println!("End of Program Listing");
-                }
-                ".registers" => {
-                    println!("Listing registers and all contents:");
-                    println!("{:#?}", self.vm.registers);
-                    println!("End of Register Listing")
-                }
-                ".debug" => {
-                    self.vm.dbg_vm();
-                }
-                _ => {
-                    let mut asm = Assembler::new();
-                    let parsed_program = asm.assemble(buffer);
-                    match parsed_program {
-                        Some(mut result) => {
-                            self.vm.program.append(result.as_mut());
+                    Err(e) => println!("Unable to load {}: {}", path.display(), e),
+                }
+            }
+            _ if buffer == ".disassemble" || buffer.starts_with(".disassemble ") => {
+                match self.disassemble_range(&buffer[".disassemble".len()..]) {
+                    Ok(lines) => {
+                        for line in lines {
+                            println!("{}", line);
                         }
-                        None => {
-                            println!("Unable to parse input");
+                    }
+                    Err(e) => println!("{}", e),
+                }
+            }
+            _ if buffer.starts_with(".exec ") => {
+                let path = Path::new(buffer[".exec ".len()..].trim());
+                match self.exec(path) {
+                    Ok(report) => {
+                        for line in report {
+                            println!("{}", line);
                         }
                     }
-                    self.vm.run_once();
+                    Err(e) => println!("Unable to run {}: {}", path.display(), e),
+                }
+            }
+            _ if buffer.starts_with(".save ") => {
+                let path = Path::new(buffer[".save ".len()..].trim());
+                match self.save(path) {
+                    Ok(()) => println!("Saved program to {}", path.display()),
+                    Err(e) => println!("Unable to save {}: {}", path.display(), e),
+                }
+            }
+            _ if buffer.starts_with(".watch ") => {
+                match buffer[".watch ".len()..].trim().trim_start_matches('$').parse::<u8>() {
+                    Ok(register) => match self.vm.watch_register(register) {
+                        Ok(()) => println!("Watching register {}", register),
+                        Err(e) => println!("Unable to watch: {}", e),
+                    },
+                    _ => println!("Usage: .watch <register>, such as `.watch $2`"),
+                }
+            }
+            // stdin has no tab key handling, so a partial command lists what it could be
+            _ if buffer.starts_with('.')
+                && !buffer.contains(' ')
+                && !complete(buffer).is_empty() =>
+            {
+                println!("{}", complete(buffer).join(" "));
+            }
+            _ => {
+                if let Err(e) = self.step(buffer) {
+                    println!("Unable to parse input: {}", e);
                 }
             }
         }
     }
 }
 
+impl REPL {
+    /// Assembles a whole multi-line program at once, so its labels resolve, then loads and runs it
+    /// from a clean state, with none of the registers or flags of what ran before
+    fn run_block(&mut self, source: &str) -> Result<(), String> {
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).map_err(describe)?;
+        self.vm.load_program(program).map_err(|e| e.to_string())?;
+        self.vm.reset();
+        self.undo.clear();
+        self.labels = asm.code_labels();
+        self.run_loaded();
+        Ok(())
+    }
+
+    /// Assembles a typed line, appends its code to the program and executes one instruction
+    fn step(&mut self, line: &str) -> Result<(), String> {
+        if self.finished {
+            return Err(FINISHED.to_string());
+        }
+        let mut asm = Assembler::new();
+        let program = asm.assemble(line).map_err(describe)?;
+        let code = program[PIE_HEADER_LENGTH + asm.ro.len()..].to_vec();
+        if self.undo.len() == UNDO_LIMIT {
+            self.undo.pop_front();
+        }
+        self.undo.push_back((self.vm.snapshot(), self.vm.program.len()));
+        self.vm.add_bytes(code);
+        self.finished = self.vm.run_steps(1).halted;
+        Ok(())
+    }
+
+    /// Executes the next instruction of the loaded program, describing only what it changed
+    fn step_changes(&mut self) -> Vec<String> {
+        if self.finished {
+            return vec![FINISHED.to_string()];
+        }
+        let registers = self.vm.registers().to_vec();
+        let pc = self.vm.pc();
+        let equal_flag = self.vm.equal_flag();
+        let overflow_flag = self.vm.overflow_flag;
+        let report = self.vm.run_steps(1);
+
+        let mut changes: Vec<String> = report
+            .changed
+            .iter()
+            .map(|&r| {
+                let r = r as usize;
+                format!("${}: {} -> {}", r, registers[r], self.vm.registers()[r])
+            })
+            .collect();
+        if equal_flag != self.vm.equal_flag() {
+            changes.push(format!("equal_flag: {} -> {}", equal_flag, self.vm.equal_flag()));
+        }
+        if overflow_flag != self.vm.overflow_flag {
+            changes.push(format!("overflow_flag: {} -> {}", overflow_flag, self.vm.overflow_flag));
+        }
+        let delta = report.pc as i64 - pc as i64;
+        changes.push(format!("pc: {} -> {} ({:+})", pc, report.pc, delta));
+        if report.halted {
+            self.finished = true;
+            changes.push("halted".to_string());
+        }
+        changes
+    }
+
+    /// Undoes the last typed step, false if there is none left
+    fn back(&mut self) -> bool {
+        match self.undo.pop_back() {
+            Some((snapshot, program_len)) => {
+                self.vm.restore(snapshot);
+                self.vm.program.truncate(program_len);
+                self.finished = false;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Assembles the source file at `path` as a whole, runs it from a clean state to completion,
+    /// past any watchpoints, and describes how it ended and the registers it left nonzero
+    fn exec(&mut self, path: &Path) -> Result<Vec<String>, String> {
+        let source = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(&source)
+            .map_err(|e| format!("assembler error\n{}", describe(e)))?;
+        self.vm.load_program(program).map_err(|e| e.to_string())?;
+        self.vm.reset();
+        self.undo.clear();
+        self.labels = asm.code_labels();
+        while self.vm.run() != RunOutcome::Finished {}
+        self.finished = true;
+        if let Some(error) = self.vm.error() {
+            return Err(format!("VM error: {}", error));
+        }
+        let mut report: Vec<String> = asm.warnings().iter().map(|w| w.to_string()).collect();
+        report.push("Program finished".to_string());
+        for (register, value) in self.vm.registers().iter().enumerate() {
+            if *value != 0 {
+                report.push(format!("${}: {}", register, value));
+            }
+        }
+        Ok(report)
+    }
+
+    /// Loads an already assembled program, header included, from a file, clearing the state
+    /// left by what ran before
+    fn load_bytes(&mut self, path: &Path) -> Result<(), String> {
+        let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+        self.vm.load_program(bytes).map_err(|e| e.to_string())?;
+        self.vm.reset();
+        self.undo.clear();
+        self.labels.clear();
+        self.finished = false;
+        Ok(())
+    }
+
+    /// Replaces the program with an empty one and clears the execution state, keeping the
+    /// VM's settings such as the output and watched registers
+    fn reset(&mut self) {
+        self.vm
+            .load_program(pie_header(0, 0))
+            .expect("an empty program always loads");
+        self.vm.reset();
+        self.undo.clear();
+        self.labels.clear();
+        self.finished = false;
+    }
+
+    /// The command numbered `index` in `.history`, for `!n`, `.run n` and `.edit n`
+    fn history_entry(&self, index: &str) -> Result<String, String> {
+        let count = self.command_buffer.len();
+        match index.trim().parse::<usize>() {
+            Ok(n) if (1..=count).contains(&n) => Ok(self.command_buffer[n - 1].clone()),
+            Ok(n) => Err(format!("No command {} in history, it holds {}", n, count)),
+            Err(_) => Err(format!("Not a history number: `{}`", index.trim())),
+        }
+    }
+
+    /// Lists the program's code from `args`, `<start> <end>` in bytes, or all of it without them
+    fn disassemble_range(&self, args: &str) -> Result<Vec<String>, String> {
+        let usage = "Usage: .disassemble [<start> <end>], such as `.disassemble 0 16`";
+        let bounds: Vec<&str> = args.split_whitespace().collect();
+        let (start, end) = match bounds.as_slice() {
+            [] => (0, self.vm.program.len()),
+            [start, end] => match (start.parse::<usize>(), end.parse::<usize>()) {
+                (Ok(start), Ok(end)) if start <= end => (start, end),
+                _ => return Err(usage.to_string()),
+            },
+            _ => return Err(usage.to_string()),
+        };
+        disassemble(&self.vm.program, start, end, &self.labels)
+    }
+
+    /// Writes the program in the VM, header included, to a file `.load_bytes` can read back
+    fn save(&self, path: &Path) -> Result<(), String> {
+        std::fs::write(path, self.vm.program_bytes()).map_err(|e| e.to_string())
+    }
+
+    fn run_loaded(&mut self) {
+        match self.vm.run() {
+            RunOutcome::Finished => self.finished = true,
+            RunOutcome::Watchpoint(hit) => println!(
+                "Watchpoint hit: register {} changed from {} to {} before pc {}",
+                hit.register,
+                hit.old,
+                hit.new,
+                self.vm.pc()
+            ),
+        }
+    }
+}
+
+/// Puts each of the assembler's errors on its own line
+fn describe(errors: Vec<AssemblerError>) -> String {
+    let messages: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+    messages.join("\n")
+}
+
 /// Accepts a hexadecimal string WITHOUT a leading `0x` and returns a Vec of u8
 /// Example for a LOAD command: 00 01 03 E8
 pub fn parse_hex(i: &str) -> Result<Vec<u8>, ParseIntError> {
     let split = i.split(" ").collect::<Vec<&str>>();
     let mut results: Vec<u8> = vec![];
     for hex_string in split {
-        let byte = u8::from_str_radix(&hex_string, 16);
+        let byte = u8::from_str_radix(hex_string, 16);
         match byte {
             Ok(result) => {
                 results.push(result);
@@ -104,3 +523,196 @@ pub fn parse_hex(i: &str) -> Result<Vec<u8>, ParseIntError> {
     }
     Ok(results)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run_block() {
+        let mut repl = REPL::new();
+        let source = "load $0 #3\nload $1 #0\nloop: dec $0\ninc $2\neq $0 $1\njeqd @done\njmpbi #20\ndone: hlt";
+        repl.run_block(source).unwrap();
+        assert_eq!(repl.vm.registers()[0], 0);
+        assert_eq!(repl.vm.registers()[2], 3);
+
+        // a second program starts over instead of counting on from the first one's registers
+        repl.run_block(source).unwrap();
+        assert_eq!(repl.vm.registers()[2], 3);
+    }
+
+    #[test]
+    fn test_run_block_reports_line() {
+        let mut repl = REPL::new();
+        let result = repl.run_block("load $0 #3\nfrobnicate $0\nhlt");
+        assert_eq!(
+            result,
+            Err("Error at line 2, column 1: Unknown opcode: `frobnicate`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_load_bytes_round_trip() {
+        let path = std::env::temp_dir().join("register_vm_test_load_bytes.bin");
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $0 #7\ninc $0\nhlt").unwrap();
+        std::fs::write(&path, program).unwrap();
+
+        let mut repl = REPL::new();
+        repl.load_bytes(&path).unwrap();
+        repl.run_loaded();
+        assert_eq!(repl.vm.registers()[0], 8);
+        repl.load_bytes(&path).unwrap();
+        assert_eq!(repl.vm.registers()[0], 0);
+
+        std::fs::write(&path, b"not a program").unwrap();
+        let result = REPL::new().load_bytes(&path);
+        assert_eq!(result, Err("Program does not start with a PIE header".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_exec() {
+        let path = std::env::temp_dir().join("register_vm_test_exec.iasm");
+        let source = "load $0 #4\nload $2 #0\nloop: add $1 $1 $0\ndec $0\neq $0 $2\njneqd @loop\n";
+        std::fs::write(&path, source.to_string() + "hlt").unwrap();
+        let mut repl = REPL::new();
+        repl.step("load $5 #9").unwrap();
+        let report = repl.exec(&path).unwrap();
+        assert_eq!(report, vec!["Program finished", "$1: 10"]);
+        assert_eq!(repl.labels, vec![("loop".to_string(), 8)]);
+
+        std::fs::write(&path, "load $0 #1\nfrobnicate $0\nhlt").unwrap();
+        let result = REPL::new().exec(&path);
+        let message = "assembler error\nError at line 2, column 1: Unknown opcode: `frobnicate`";
+        assert_eq!(result, Err(message.to_string()));
+
+        std::fs::write(&path, "load $0 #1\ndiv $0 $0 $1\nhlt").unwrap();
+        let result = REPL::new().exec(&path);
+        assert_eq!(result, Err("VM error: Division by zero at pc 4".to_string()));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_save_round_trip() {
+        let path = std::env::temp_dir().join("register_vm_test_save.bin");
+        let mut asm = Assembler::new();
+        let program = asm.assemble("msg: .asciiz 'Hi'\nload $0 #7\nprints @msg\nhlt").unwrap();
+        std::fs::write(&path, &program).unwrap();
+
+        let mut repl = REPL::new();
+        repl.load_bytes(&path).unwrap();
+        repl.save(&path).unwrap();
+        let mut reloaded = REPL::new();
+        reloaded.load_bytes(&path).unwrap();
+        assert_eq!(reloaded.vm.program, repl.vm.program);
+        assert_eq!(reloaded.vm.ro_data, b"Hi\0".to_vec());
+        assert_eq!(std::fs::read(&path).unwrap(), program);
+        std::fs::remove_file(&path).unwrap();
+
+        // typed steps have no header of their own, saving writes one
+        let mut typed = REPL::new();
+        typed.step("load $0 #5").unwrap();
+        typed.save(&path).unwrap();
+        let mut reloaded = REPL::new();
+        reloaded.load_bytes(&path).unwrap();
+        assert_eq!(reloaded.vm.program, typed.vm.program);
+        std::fs::remove_file(&path).unwrap();
+
+        let missing = std::env::temp_dir().join("register_vm_no_such_dir").join("out.bin");
+        assert!(typed.save(&missing).is_err());
+    }
+
+    #[test]
+    fn test_step_changes() {
+        let mut repl = REPL::new();
+        let mut asm = Assembler::new();
+        let program = asm.assemble("load $3 #5\ninc $3\neq $3 $3\nhlt").unwrap();
+        repl.vm.load_program(program).unwrap();
+        assert_eq!(repl.step_changes(), vec!["$3: 0 -> 5", "pc: 0 -> 4 (+4)"]);
+        assert_eq!(repl.step_changes(), vec!["$3: 5 -> 6", "pc: 4 -> 8 (+4)"]);
+        assert_eq!(repl.step_changes(), vec!["equal_flag: false -> true", "pc: 8 -> 12 (+4)"]);
+        assert_eq!(repl.step_changes(), vec!["pc: 12 -> 13 (+1)", "halted"]);
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut repl = REPL::new();
+        repl.step("load $0 #5").unwrap();
+        repl.step("inc $0").unwrap();
+        assert_eq!(repl.vm.pc(), 8);
+        assert_eq!(repl.vm.registers()[0], 6);
+
+        assert!(repl.back());
+        assert_eq!(repl.vm.pc(), 4);
+        assert_eq!(repl.vm.registers()[0], 5);
+        assert_eq!(repl.vm.program.len(), 4);
+
+        assert!(repl.back());
+        assert!(!repl.back());
+        assert_eq!(repl.vm.registers()[0], 0);
+    }
+
+    #[test]
+    fn test_disassemble_range() {
+        let mut repl = REPL::new();
+        repl.run_block("load $0 #2\nloop: dec $0\neq $0 $1\njneqd @loop\nhlt").unwrap();
+        let listing = repl.disassemble_range("").unwrap();
+        assert_eq!(listing[..3], ["0000: load $0 #2", "loop:", "0004: dec $0"]);
+        assert_eq!(listing[4..], ["000C: jneqd @loop", "0010: hlt"]);
+        assert_eq!(repl.disassemble_range(" 12 16").unwrap(), vec!["000C: jneqd @loop"]);
+        assert!(repl.disassemble_range(" 2 8").is_err());
+        assert!(repl.disassemble_range(" 8").is_err());
+    }
+
+    #[test]
+    fn test_finished_program_refuses_input() {
+        let mut repl = REPL::new();
+        repl.step("load $0 #5").unwrap();
+        repl.step("hlt").unwrap();
+        assert_eq!(repl.step("inc $0"), Err(FINISHED.to_string()));
+        assert_eq!(repl.step_changes(), vec![FINISHED]);
+        assert_eq!(repl.vm.program.len(), 8);
+
+        // undoing the HLT takes the program back to where it can go on
+        assert!(repl.back());
+        repl.step("inc $0").unwrap();
+        assert_eq!(repl.vm.registers()[0], 6);
+
+        repl.run_block("load $1 #2\nhlt").unwrap();
+        assert!(repl.step("inc $1").is_err());
+        repl.reset();
+        assert!(repl.vm.program.is_empty());
+        repl.step("inc $1").unwrap();
+        assert_eq!(repl.vm.registers()[1], 1);
+        assert_eq!(repl.vm.pc(), 4);
+    }
+
+    #[test]
+    fn test_history_entry() {
+        let mut repl = REPL::new();
+        repl.command_buffer = vec![".registers".to_string(), "inc $0".to_string()];
+        assert_eq!(repl.history_entry("1"), Ok(".registers".to_string()));
+        assert_eq!(repl.history_entry(" 2"), Ok("inc $0".to_string()));
+        assert_eq!(
+            repl.history_entry("3"),
+            Err("No command 3 in history, it holds 2".to_string())
+        );
+        assert_eq!(
+            repl.history_entry("0"),
+            Err("No command 0 in history, it holds 2".to_string())
+        );
+        assert_eq!(
+            repl.history_entry("x"),
+            Err("Not a history number: `x`".to_string())
+        );
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(complete(".re"), vec![".registers", ".reset"]);
+        assert_eq!(complete(".dis"), vec![".disassemble"]);
+        assert_eq!(complete(".").len(), COMMANDS.len());
+        assert!(complete(".nothing").is_empty());
+    }
+}
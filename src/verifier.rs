@@ -0,0 +1,158 @@
+use crate::instruction::{Instruction, Opcode, OperandKind};
+use crate::vm::FLOAT_REGISTERS;
+
+/// The first problem `verify` found, offsets are byte offsets of the offending instruction
+#[derive(Debug, PartialEq)]
+pub enum VerifyError {
+    /// The program is not made of whole 4-byte instructions
+    Truncated { len: usize },
+    IllegalOpcode { offset: usize, byte: u8 },
+    RegisterOutOfRange { offset: usize, register: u8 },
+    /// A jump lands outside the program or in the middle of an instruction
+    JumpOutOfBounds { offset: usize, target: i64 },
+    /// The last instruction is not HLT, so the program can run off its end
+    MissingHlt,
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            VerifyError::Truncated { len } => {
+                write!(f, "Program is {} bytes long, which is not a multiple of 4", len)
+            }
+            VerifyError::IllegalOpcode { offset, byte } => {
+                write!(f, "Illegal opcode {} at {}", byte, offset)
+            }
+            VerifyError::RegisterOutOfRange { offset, register } => {
+                write!(f, "Register ${} out of range at {}", register, offset)
+            }
+            VerifyError::JumpOutOfBounds { offset, target } => {
+                write!(f, "Jump at {} targets {}, outside the program", offset, target)
+            }
+            VerifyError::MissingHlt => write!(f, "Program does not end with HLT"),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Checks a code section for problems that would stop or crash a VM with `registers` integer
+/// registers, such as `DEFAULT_REGISTERS`, without running it. Jumps through registers can only
+/// be checked at run time.
+pub fn verify(program: &[u8], registers: usize) -> Result<(), VerifyError> {
+    if !program.len().is_multiple_of(4) {
+        return Err(VerifyError::Truncated { len: program.len() });
+    }
+    for (index, bytes) in program.chunks(4).enumerate() {
+        let offset = index * 4;
+        let instruction = Instruction::from_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        let opcode = instruction.opcode();
+        if opcode == Opcode::IGL {
+            return Err(VerifyError::IllegalOpcode { offset, byte: bytes[0] });
+        }
+        let mut slot = 0;
+        for kind in opcode.operand_kinds() {
+            let byte = instruction.operands()[slot];
+            let count = match kind {
+                OperandKind::Register => Some(registers),
+                OperandKind::FloatRegister => Some(FLOAT_REGISTERS),
+                _ => None,
+            };
+            if count.is_some_and(|count| byte as usize >= count) {
+                return Err(VerifyError::RegisterOutOfRange { offset, register: byte });
+            }
+            slot += kind.width();
+        }
+        if let Some(target) = instruction.static_target(offset) {
+            // jumping to the very end is allowed, the program then simply finishes
+            if target < 0 || target > program.len() as i64 || target % 4 != 0 {
+                return Err(VerifyError::JumpOutOfBounds { offset, target });
+            }
+        }
+    }
+    match program.chunks(4).last() {
+        Some(last) if Opcode::from(last[0]) == Opcode::HLT => Ok(()),
+        _ => Err(VerifyError::MissingHlt),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::DEFAULT_REGISTERS;
+
+    #[test]
+    fn test_verify_clean_program() {
+        let program = vec![
+            Opcode::LOAD.into(), 0, 0, 3,
+            Opcode::DEC.into(), 0, 0, 0,
+            Opcode::EQ.into(), 0, 1, 0,
+            Opcode::JEQD.into(), 0, 20, 0,
+            Opcode::JMPBI.into(), 0, 16, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+        ];
+        assert_eq!(verify(&program, DEFAULT_REGISTERS), Ok(()));
+    }
+
+    #[test]
+    fn test_verify_truncated() {
+        let program = vec![Opcode::HLT.into(), 0, 0, 0, 0, 0];
+        assert_eq!(verify(&program, DEFAULT_REGISTERS), Err(VerifyError::Truncated { len: 6 }));
+    }
+
+    #[test]
+    fn test_verify_illegal_opcode() {
+        let program = vec![Opcode::LOAD.into(), 0, 0, 3, 200, 0, 0, 0, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(
+            verify(&program, DEFAULT_REGISTERS),
+            Err(VerifyError::IllegalOpcode { offset: 4, byte: 200 })
+        );
+    }
+
+    #[test]
+    fn test_verify_register_out_of_range() {
+        let program = vec![Opcode::ADD.into(), 0, 1, 32, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(
+            verify(&program, DEFAULT_REGISTERS),
+            Err(VerifyError::RegisterOutOfRange { offset: 0, register: 32 })
+        );
+        // the immediate of LOAD is not a register
+        let program = vec![Opcode::LOAD.into(), 0, 0, 200, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(verify(&program, DEFAULT_REGISTERS), Ok(()));
+
+        // the integer registers follow the VM's count, the float registers are always 32
+        let program = vec![Opcode::ADD.into(), 0, 1, 63, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(verify(&program, 64), Ok(()));
+        let program = vec![Opcode::FADD.into(), 0, 1, 40, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(
+            verify(&program, 64),
+            Err(VerifyError::RegisterOutOfRange { offset: 0, register: 40 })
+        );
+    }
+
+    #[test]
+    fn test_verify_jump_out_of_bounds() {
+        let program = vec![Opcode::JEQD.into(), 0, 40, 0, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(
+            verify(&program, DEFAULT_REGISTERS),
+            Err(VerifyError::JumpOutOfBounds { offset: 0, target: 40 })
+        );
+        let program = vec![Opcode::JEQD.into(), 0, 2, 0, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(
+            verify(&program, DEFAULT_REGISTERS),
+            Err(VerifyError::JumpOutOfBounds { offset: 0, target: 2 })
+        );
+        let program = vec![Opcode::JMPBI.into(), 0, 8, 0, Opcode::HLT.into(), 0, 0, 0];
+        assert_eq!(
+            verify(&program, DEFAULT_REGISTERS),
+            Err(VerifyError::JumpOutOfBounds { offset: 0, target: -4 })
+        );
+    }
+
+    #[test]
+    fn test_verify_missing_hlt() {
+        let program = vec![Opcode::LOAD.into(), 0, 0, 3];
+        assert_eq!(verify(&program, DEFAULT_REGISTERS), Err(VerifyError::MissingHlt));
+        assert_eq!(verify(&[], DEFAULT_REGISTERS), Err(VerifyError::MissingHlt));
+    }
+}
@@ -1,210 +1,1507 @@
-use crate::instruction::Opcode;
-use crate::assembler::PIE_HEADER_PREFIX;
+use crate::instruction::{f16_bits_to_f64, Instruction, Opcode, OperandKind};
+use crate::io::{self, Read, Write};
+use crate::pc::{Pc, PcError};
+use alloc::collections::BTreeMap;
+#[cfg(not(feature = "std"))]
+use alloc::{boxed::Box, format, string::String, string::ToString, vec, vec::Vec};
+use core::cmp::Ordering;
+use core::ops::Range;
 
-pub struct VM {
-    pub registers: [i32; 32],
+// xorshift can not leave the all zero state, so a zero seed is swapped for this one
+const DEFAULT_SEED: u64 = 0x2545_F491_4F6C_DD1D;
+
+// reports through `VM::diagnostic`, formatting the way `println!` does
+macro_rules! diagnostic {
+    ($vm:expr, $($arg:tt)*) => {
+        $vm.diagnostic(format_args!($($arg)*))
+    };
+}
+
+/// Number of integer registers a VM made with `new` has
+pub const DEFAULT_REGISTERS: usize = 32;
+
+/// Number of float registers, which unlike the integer registers is fixed
+pub const FLOAT_REGISTERS: usize = 32;
+
+/// What an integer register holds, i64 with the `reg64` feature
+#[cfg(not(feature = "reg64"))]
+pub type Reg = i32;
+#[cfg(feature = "reg64")]
+pub type Reg = i64;
+
+/// A register's bits read as unsigned, as DIVU and USHR do
+#[cfg(not(feature = "reg64"))]
+pub type UReg = u32;
+#[cfg(feature = "reg64")]
+pub type UReg = u64;
+
+/// Number of entries in the trap vector table
+pub const TRAP_VECTORS: usize = 16;
+
+// PIE Magic numbers
+pub const PIE_HEADER_PREFIX: [u8; 4] = [45, 50, 49, 45];
+pub const PIE_HEADER_LENGTH: usize = 64;
+
+/// Builds the PIE header for a program with `ro_len` bytes of read-only data starting at `entry`
+pub fn pie_header(ro_len: u32, entry: u32) -> Vec<u8> {
+    let mut header = vec![];
+    for byte in PIE_HEADER_PREFIX {
+        header.push(byte);
+    }
+    // the read-only section sits between the header and the code
+    for byte in ro_len.to_be_bytes() {
+        header.push(byte);
+    }
+    for byte in entry.to_be_bytes() {
+        header.push(byte);
+    }
+
+    while header.len() < PIE_HEADER_LENGTH {
+        header.push(0);
+    }
+    header
+}
+
+/// Why `load_program` rejected its input
+#[derive(Debug, PartialEq)]
+pub enum LoadError {
+    /// The input does not start with a PIE header
+    BadHeader,
+    /// The header announces more read-only data than the input holds
+    Truncated,
+    /// The code section is not made of whole 4-byte instructions
+    MalformedProgram { code_len: usize },
+    /// The entry point in the header is not the start of an instruction in the code section
+    BadEntryPoint { entry: usize },
+}
+
+impl core::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            LoadError::BadHeader => write!(f, "Program does not start with a PIE header"),
+            LoadError::Truncated => {
+                write!(f, "Program is shorter than its read-only data section")
+            }
+            LoadError::MalformedProgram { code_len } => write!(
+                f,
+                "Program code is {} bytes long, which is not a multiple of 4",
+                code_len
+            ),
+            LoadError::BadEntryPoint { entry } => {
+                write!(f, "Program entry point {} is not an instruction", entry)
+            }
+        }
+    }
+}
+
+impl core::error::Error for LoadError {}
+
+/// A device behind a range of memory-mapped addresses, reached through SB and LB. Offsets are
+/// relative to the start of the range, the input and output are those of the VM.
+pub trait IoDevice {
+    fn read(&mut self, offset: usize, input: &mut dyn Read) -> io::Result<u8>;
+    fn write(&mut self, offset: usize, value: u8, output: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Writes stored bytes to the output and reads loaded bytes from the input, 0 at end of input
+pub struct Console;
+
+impl IoDevice for Console {
+    fn read(&mut self, _offset: usize, input: &mut dyn Read) -> io::Result<u8> {
+        let mut byte = [0u8; 1];
+        match input.read(&mut byte)? {
+            0 => Ok(0),
+            _ => Ok(byte[0]),
+        }
+    }
+
+    fn write(&mut self, _offset: usize, value: u8, output: &mut dyn Write) -> io::Result<()> {
+        output.write_all(&[value])
+    }
+}
+
+/// How the left operand of the last comparison ordered against the right one
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Comparison {
+    /// Nothing was compared since the VM was created or reset
+    None,
+    Less,
+    Equal,
+    Greater,
+}
+
+impl From<Ordering> for Comparison {
+    fn from(ordering: Ordering) -> Self {
+        match ordering {
+            Ordering::Less => Comparison::Less,
+            Ordering::Equal => Comparison::Equal,
+            Ordering::Greater => Comparison::Greater,
+        }
+    }
+}
+
+/// Why `run` returned
+#[derive(Debug, PartialEq)]
+pub enum RunOutcome {
+    /// The program halted, failed or ran off its end
+    Finished,
+    /// A watched register changed, running again continues after the instruction that changed it
+    Watchpoint(WatchpointHit),
+}
+
+/// Why the VM stopped running a program that did not halt
+#[derive(Debug, Clone, PartialEq)]
+pub enum VmError {
+    DivByZero { pc: usize },
+    RegisterOutOfRange { idx: u8 },
+    /// The pc is not at the start of an instruction
+    MisalignedPc { pc: usize },
+    /// RET with no return address to go back to
+    StackUnderflow,
+    HeapOutOfBounds { addr: usize },
+    /// ALOC would grow the heap past the cap of `set_max_heap`
+    HeapLimit { requested: usize, max: usize },
+    /// TRAP would nest deeper than the cap of `set_max_stack`
+    StackLimit { max: usize },
+    IllegalOpcode { byte: u8, pc: usize },
+    /// The last instruction of the program is missing some of its bytes
+    TruncatedProgram,
+    BadJump(PcError),
+    /// Any other failure, described in full
+    Other(String),
+}
+
+impl core::fmt::Display for VmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            VmError::DivByZero { pc } => write!(f, "Division by zero at pc {}", pc),
+            VmError::RegisterOutOfRange { idx } => write!(f, "Register {} is out of range", idx),
+            VmError::MisalignedPc { pc } => write!(f, "Pc {} is not at an instruction", pc),
+            VmError::StackUnderflow => write!(f, "RET with an empty call stack"),
+            VmError::HeapOutOfBounds { addr } => write!(f, "Address {} is out of bounds", addr),
+            VmError::HeapLimit { requested, max } => {
+                write!(f, "ALOC to {} bytes exceeds the heap limit of {}", requested, max)
+            }
+            VmError::StackLimit { max } => write!(f, "TRAP nests deeper than the limit of {}", max),
+            VmError::IllegalOpcode { byte, pc } => {
+                write!(f, "Unrecognized opcode {} at pc {}", byte, pc)
+            }
+            VmError::TruncatedProgram => write!(f, "The program ends inside an instruction"),
+            VmError::BadJump(e) => write!(f, "{}", e),
+            VmError::Other(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+impl core::error::Error for VmError {}
+
+#[derive(Debug, PartialEq)]
+pub struct WatchpointHit {
+    pub register: u8,
+    pub old: Reg,
+    pub new: Reg,
+}
+
+/// Resource caps for `run_sandboxed`, None keeps the VM's own setting, unlimited by default
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Limits {
+    /// Instructions to execute before giving up on the program
+    pub max_steps: Option<usize>,
+    pub max_heap: Option<usize>,
+    /// How deep TRAP calls may nest
+    pub max_stack: Option<usize>,
+}
+
+/// How `run_sandboxed` ended
+#[derive(Debug, PartialEq)]
+pub enum SandboxOutcome {
+    /// The program halted or ran off its end
+    Completed,
+    StepLimit,
+    HeapLimit,
+    StackLimit,
+    /// The program failed for a reason other than a limit
+    Failed(VmError),
+}
+
+/// What `run_steps` did
+#[derive(Debug, PartialEq)]
+pub struct StepReport {
+    /// Registers whose value differs from before the steps, in ascending order
+    pub changed: Vec<u8>,
     pub pc: usize,
+    /// Whether the program halted, failed or ran off its end within the steps
+    pub halted: bool,
+}
+
+/// A copy of the execution state, the program and host configuration are not included
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    registers: Vec<Reg>,
+    float_registers: [f64; FLOAT_REGISTERS],
+    pc: usize,
+    remainder: UReg,
+    comparison: Comparison,
+    overflow_flag: bool,
+    heap: Vec<u8>,
+    cycle_count: u64,
+    rng_state: u64,
+    call_stack: Vec<usize>,
+    frames: Vec<Vec<Reg>>,
+}
+
+/// Runs assembled programs.
+///
+/// Code and data live in two address spaces. Code addresses are offsets into `program`, which
+/// jumps, TRAP handlers and code labels use. Data addresses are what LEA of a data label, the
+/// memory opcodes such as LW, SW and MEMCPY, and PRINTS use:
+///
+/// | Data addresses                 | What is there                        |
+/// |--------------------------------|--------------------------------------|
+/// | `0..ro_data.len()`             | the read-only data, `.word` and such |
+/// | `ro_data.len()..` to heap end  | the heap ALOC grows, writable        |
+///
+/// Devices added with `map_io` take over the addresses they map.
+pub struct VM {
+    registers: Vec<Reg>,
+    pub float_registers: [f64; FLOAT_REGISTERS],
+    pc: usize,
     pub program: Vec<u8>,
-    pub remainder: u32,
-    pub equal_flag: bool,
+    pub ro_data: Vec<u8>,
+    remainder: UReg,
+    comparison: Comparison,
+    pub overflow_flag: bool,
     heap: Vec<u8>,
+    cycle_count: u64,
+    output: Box<dyn Write>,
+    input: Box<dyn Read>,
+    // the instruction being executed, operands are read from here
+    ir: Instruction,
+    compiled: Option<Vec<Instruction>>,
+    watched: Vec<u8>,
+    rng_state: u64,
+    trap_table: [Option<usize>; TRAP_VECTORS],
+    // return addresses pushed by TRAP and popped by RET
+    call_stack: Vec<usize>,
+    // the registers each ENTER saved, newest last, popped by LEAVE
+    frames: Vec<Vec<Reg>>,
+    tracing: bool,
+    trace_log: Vec<(usize, Opcode)>,
+    profiling: bool,
+    profile: BTreeMap<Opcode, u64>,
+    // where `reset` puts the pc, from the header of the loaded program
+    entry: usize,
+    max_heap: Option<usize>,
+    // how deep the call stack may grow, unlimited when unset
+    max_stack: Option<usize>,
+    // the tick source of TIME, the cycle count when unset
+    clock: Option<Box<dyn FnMut() -> u64>>,
+    // memory-mapped devices, checked before the read-only data and the heap
+    io: Vec<(Range<usize>, Box<dyn IoDevice>)>,
+    // whether diagnostics are written to the output
+    verbose: bool,
+    // why the last run stopped, if it failed
+    error: Option<VmError>,
+}
+
+impl Default for VM {
+    fn default() -> VM {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
+        VM::with_registers(DEFAULT_REGISTERS)
+    }
+
+    /// A VM with `n` integer registers, register operands of `n` and above are out of range
+    pub fn with_registers(n: usize) -> VM {
         VM {
-            registers: [0; 32],
+            registers: vec![0; n],
+            float_registers: [0.0; FLOAT_REGISTERS],
             pc: 0,
             program: vec![],
+            ro_data: vec![],
             remainder: 0,
-            equal_flag: false,
+            comparison: Comparison::None,
+            overflow_flag: false,
             heap: vec![],
+            cycle_count: 0,
+            output: default_output(),
+            input: default_input(),
+            ir: Instruction::new(Opcode::NOP),
+            compiled: None,
+            watched: vec![],
+            rng_state: DEFAULT_SEED,
+            trap_table: [None; TRAP_VECTORS],
+            call_stack: vec![],
+            frames: vec![],
+            tracing: false,
+            trace_log: vec![],
+            profiling: false,
+            profile: BTreeMap::new(),
+            entry: 0,
+            max_heap: None,
+            max_stack: None,
+            clock: None,
+            io: vec![],
+            verbose: false,
+            error: None,
+        }
+    }
+
+    /// A VM whose registers start with `values`, pairs of a register number and its value.
+    /// Fails if a register number is not below `DEFAULT_REGISTERS`.
+    pub fn with_registers_preset(values: &[(u8, Reg)]) -> Result<VM, VmError> {
+        let mut vm = VM::new();
+        for &(idx, value) in values {
+            match vm.registers.get_mut(idx as usize) {
+                Some(register) => *register = value,
+                None => return Err(VmError::RegisterOutOfRange { idx }),
+            }
+        }
+        Ok(vm)
+    }
+
+    /// Seeds the generator behind RAND, the same seed always yields the same numbers
+    pub fn seed(&mut self, seed: u64) {
+        self.rng_state = if seed == 0 { DEFAULT_SEED } else { seed };
+    }
+
+    /// Advances the xorshift64 generator
+    fn next_random(&mut self) -> u64 {
+        let mut x = self.rng_state;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.rng_state = x;
+        x
+    }
+
+    /// Makes `run` stop as soon as register `idx` changes value, `idx` must be one of the VM's
+    pub fn watch_register(&mut self, idx: u8) -> Result<(), VmError> {
+        if idx as usize >= self.registers.len() {
+            return Err(VmError::RegisterOutOfRange { idx });
+        }
+        if !self.watched.contains(&idx) {
+            self.watched.push(idx);
+        }
+        Ok(())
+    }
+
+    pub fn unwatch_register(&mut self, idx: u8) {
+        self.watched.retain(|&watched| watched != idx);
+    }
+
+    /// Records the pc and opcode of every executed instruction while on, off by default
+    pub fn enable_trace(&mut self, on: bool) {
+        self.tracing = on;
+    }
+
+    /// The instructions executed while tracing was on, oldest first
+    pub fn trace(&self) -> &[(usize, Opcode)] {
+        &self.trace_log
+    }
+
+    /// Counts how often each opcode executes while on, off by default
+    pub fn enable_profile(&mut self, on: bool) {
+        self.profiling = on;
+    }
+
+    /// How many times each opcode executed while profiling was on, opcodes never seen are absent
+    pub fn opcode_profile(&self) -> &BTreeMap<Opcode, u64> {
+        &self.profile
+    }
+
+    /// Makes `TRAP #n` jump to `addr`. Panics if `n` is not below `TRAP_VECTORS`.
+    pub fn set_trap_handler(&mut self, n: usize, addr: usize) {
+        self.trap_table[n] = Some(addr);
+    }
+
+    /// Caps the heap at `bytes`, an ALOC that would grow it further stops the VM instead.
+    /// The heap is unlimited by default.
+    pub fn set_max_heap(&mut self, bytes: usize) {
+        self.max_heap = Some(bytes);
+    }
+
+    /// Caps how deep TRAP calls may nest, a TRAP beyond `depth` stops the VM instead.
+    /// The call stack is unlimited by default.
+    pub fn set_max_stack(&mut self, depth: usize) {
+        self.max_stack = Some(depth);
+    }
+
+    /// Makes TIME read its ticks from `clock` instead of the cycle count
+    pub fn set_clock(&mut self, clock: Box<dyn FnMut() -> u64>) {
+        self.clock = Some(clock);
+    }
+
+    /// Maps `device` over the addresses in `range`, so SB and LB there reach the device instead
+    /// of the read-only data or the heap
+    pub fn map_io(&mut self, range: Range<usize>, device: Box<dyn IoDevice>) {
+        self.io.push((range, device));
+    }
+
+    /// Writes diagnostics such as `HLT encountered` to the output while on, off by default
+    pub fn set_verbose(&mut self, on: bool) {
+        self.verbose = on;
+    }
+
+    /// The heap, which ALOC grows and SB writes, starting at the address after the read-only data
+    pub fn heap(&self) -> &[u8] {
+        &self.heap
+    }
+
+    /// The data addresses of the read-only data
+    pub fn ro_data_addresses(&self) -> Range<usize> {
+        0..self.ro_data.len()
+    }
+
+    /// The data addresses of the heap, right after the read-only data
+    pub fn heap_addresses(&self) -> Range<usize> {
+        self.ro_data.len()..self.ro_data.len() + self.heap.len()
+    }
+
+    /// Redirects what the program prints, stdout by default
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+
+    /// Replaces where GETC reads from, stdin by default
+    pub fn set_input(&mut self, input: Box<dyn Read>) {
+        self.input = input;
+    }
+
+    /// Clears all execution state so the loaded program can be run again from the start
+    pub fn reset(&mut self) {
+        self.registers.iter_mut().for_each(|r| *r = 0);
+        self.float_registers = [0.0; FLOAT_REGISTERS];
+        self.pc = self.entry;
+        self.remainder = 0;
+        self.comparison = Comparison::None;
+        self.overflow_flag = false;
+        self.heap.clear();
+        self.cycle_count = 0;
+        self.call_stack.clear();
+        self.frames.clear();
+        self.trace_log.clear();
+        self.profile.clear();
+        self.error = None;
+    }
+
+    /// The integer registers
+    pub fn registers(&self) -> &[Reg] {
+        &self.registers
+    }
+
+    /// Offset of the next instruction in the code section
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Remainder of the last DIV
+    pub fn remainder(&self) -> UReg {
+        self.remainder
+    }
+
+    /// Whether the operands of the last comparison were equal, derived from `comparison` and
+    /// read by JEQ, JEQD, JNEQD and the conditional moves
+    pub fn equal_flag(&self) -> bool {
+        self.comparison == Comparison::Equal
+    }
+
+    /// How the operands of the last comparison ordered, read by JGTD, JLTD, JGED and JLED
+    pub fn comparison(&self) -> Comparison {
+        self.comparison
+    }
+
+    /// Copies the execution state so it can be put back with `restore`
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers.clone(),
+            float_registers: self.float_registers,
+            pc: self.pc,
+            remainder: self.remainder,
+            comparison: self.comparison,
+            overflow_flag: self.overflow_flag,
+            heap: self.heap.clone(),
+            cycle_count: self.cycle_count,
+            rng_state: self.rng_state,
+            call_stack: self.call_stack.clone(),
+            frames: self.frames.clone(),
         }
     }
 
-    fn verify_header(&self) ->bool {
-        self.program[0..4] == PIE_HEADER_PREFIX
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.registers = snapshot.registers;
+        self.float_registers = snapshot.float_registers;
+        self.pc = snapshot.pc;
+        self.remainder = snapshot.remainder;
+        self.comparison = snapshot.comparison;
+        self.overflow_flag = snapshot.overflow_flag;
+        self.heap = snapshot.heap;
+        self.cycle_count = snapshot.cycle_count;
+        self.rng_state = snapshot.rng_state;
+        self.call_stack = snapshot.call_stack;
+        self.frames = snapshot.frames;
+    }
+
+    /// Number of instructions executed since the VM was created or last reset
+    pub fn cycles(&self) -> u64 {
+        self.cycle_count
     }
 
     pub fn add_byte(&mut self, v: u8) {
         self.program.push(v);
+        self.compiled = None;
     }
 
     fn next_8_bits(&mut self) -> u8 {
-        let result = self.program[self.pc];
+        // pc only tracks how far into the current instruction we have read
+        let result = self.ir.operands()[self.pc % 4 - 1];
         self.pc += 1;
         result
     }
 
     fn next_16_bits(&mut self) -> u16 {
-        let result = ((self.program[self.pc] as u16) << 8) | (self.program[self.pc + 1] as u16);
-        self.pc += 2;
-        result
+        let upper = self.next_8_bits() as u16;
+        let lower = self.next_8_bits() as u16;
+        (upper << 8) | lower
     }
 
     pub fn add_bytes(&mut self, mut bytes: Vec<u8>) {
         self.program.append(bytes.as_mut());
+        self.compiled = None;
+    }
+
+    /// Decodes the whole program up front so running it skips decoding every step.
+    /// Changing `program` directly afterwards requires compiling again.
+    pub fn compile(&mut self) {
+        self.compiled = Some(self.program.chunks(4).map(decode).collect());
+    }
+
+    /// Loads the output of the assembler, splitting off the PIE header and the read-only data
+    pub fn load_program(&mut self, bytes: Vec<u8>) -> Result<(), LoadError> {
+        if bytes.len() < PIE_HEADER_LENGTH || bytes[0..4] != PIE_HEADER_PREFIX {
+            return Err(LoadError::BadHeader);
+        }
+        let ro_len = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let code_start = PIE_HEADER_LENGTH + ro_len;
+        if bytes.len() < code_start {
+            return Err(LoadError::Truncated);
+        }
+        let code_len = bytes.len() - code_start;
+        if !code_len.is_multiple_of(4) {
+            return Err(LoadError::MalformedProgram { code_len });
+        }
+        let entry = u32::from_be_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+        if !entry.is_multiple_of(4) || entry > code_len {
+            return Err(LoadError::BadEntryPoint { entry });
+        }
+        self.ro_data = bytes[PIE_HEADER_LENGTH..code_start].to_vec();
+        self.program = bytes[code_start..].to_vec();
+        self.compiled = None;
+        self.entry = entry;
+        self.pc = entry;
+        Ok(())
+    }
+
+    /// The loaded program in the form `load_program` accepts, under a freshly written header
+    pub fn program_bytes(&self) -> Vec<u8> {
+        let mut bytes = pie_header(self.ro_data.len() as u32, self.entry as u32);
+        bytes.extend_from_slice(&self.ro_data);
+        bytes.extend_from_slice(&self.program);
+        bytes
+    }
+
+    /// Reads the null-terminated string at `start`, addresses past the read-only data fall
+    /// through to the heap. `None` if no terminator is found before the end of that region.
+    fn read_string(&self, start: usize) -> Option<Vec<u8>> {
+        let bytes = if start < self.ro_data.len() {
+            &self.ro_data[start..]
+        } else {
+            self.heap.get(start - self.ro_data.len()..)?
+        };
+        let end = bytes.iter().position(|&b| b == 0)?;
+        Some(bytes[..end].to_vec())
+    }
+
+    /// Records how `left` ordered against `right`, which the conditional jumps and moves read
+    fn compare(&mut self, left: Reg, right: Reg) {
+        self.comparison = left.cmp(&right).into();
+    }
+
+    /// Stores `value` to the device mapped at `addr`, `None` if no device is mapped there
+    fn io_write(&mut self, addr: usize, value: u8) -> Option<io::Result<()>> {
+        let (range, device) = self.io.iter_mut().find(|(range, _)| range.contains(&addr))?;
+        Some(device.write(addr - range.start, value, &mut *self.output))
+    }
+
+    /// Loads a byte from the device mapped at `addr`, `None` if no device is mapped there
+    fn io_read(&mut self, addr: usize) -> Option<io::Result<u8>> {
+        let (range, device) = self.io.iter_mut().find(|(range, _)| range.contains(&addr))?;
+        Some(device.read(addr - range.start, &mut *self.input))
+    }
+
+    /// Stores the low `width` bytes of `value` little-endian from `addr` on, each to a mapped
+    /// device or else the heap. Nothing is stored unless every byte can be, false if the VM has
+    /// to stop.
+    fn store(&mut self, addr: usize, value: Reg, width: usize) -> bool {
+        let heap = self.heap_addresses();
+        let addrs = (0..width).map(|i| addr.wrapping_add(i));
+        // the read-only data can not be written, only the heap after it
+        if let Some(at) = addrs.clone().find(|at| !self.io_mapped(*at) && !heap.contains(at)) {
+            return self.fail(VmError::HeapOutOfBounds { addr: at });
+        }
+        for (at, byte) in addrs.zip(value.to_le_bytes()) {
+            match self.io_write(at, byte) {
+                Some(Ok(())) => {}
+                Some(Err(e)) => {
+                    return self.fail(VmError::Other(format!(
+                        "Unable to write to the device at {}: {:?}",
+                        at,
+                        e
+                    )));
+                }
+                None => self.heap[at - heap.start] = byte,
+            }
+        }
+        true
+    }
+
+    /// Loads `width` bytes little-endian from `addr` on, each from a mapped device, the
+    /// read-only data or the heap, zero-extended. None if the VM has to stop.
+    fn load(&mut self, addr: usize, width: usize) -> Option<u32> {
+        let addrs = (0..width).map(|i| addr.wrapping_add(i));
+        let unreadable = addrs
+            .clone()
+            .find(|at| !self.io_mapped(*at) && self.read_byte(*at).is_none());
+        if let Some(at) = unreadable {
+            self.fail(VmError::HeapOutOfBounds { addr: at });
+            return None;
+        }
+        let mut value = 0;
+        for (i, at) in addrs.enumerate() {
+            let byte = match self.io_read(at) {
+                Some(Ok(byte)) => byte,
+                Some(Err(e)) => {
+                    self.fail(VmError::Other(format!(
+                        "Unable to read from the device at {}: {:?}",
+                        at,
+                        e
+                    )));
+                    return None;
+                }
+                None => self.read_byte(at)?,
+            };
+            value |= (byte as u32) << (8 * i);
+        }
+        Some(value)
+    }
+
+    /// Whether a device is mapped at `addr`
+    fn io_mapped(&self, addr: usize) -> bool {
+        self.io.iter().any(|(range, _)| range.contains(&addr))
+    }
+
+    /// The byte at `addr`, addresses past the read-only data fall through to the heap
+    fn read_byte(&self, addr: usize) -> Option<u8> {
+        if addr < self.ro_data.len() {
+            Some(self.ro_data[addr])
+        } else {
+            self.heap.get(addr - self.ro_data.len()).copied()
+        }
+    }
+
+    /// The first register operand of the decoded instruction naming no register
+    fn bad_register(&self) -> Option<u8> {
+        let mut slot = 0;
+        for kind in self.ir.opcode().operand_kinds() {
+            let idx = self.ir.operands()[slot];
+            let count = match kind {
+                OperandKind::Register => self.registers.len(),
+                OperandKind::FloatRegister => self.float_registers.len(),
+                _ => usize::MAX,
+            };
+            if idx as usize >= count {
+                return Some(idx);
+            }
+            slot += kind.width();
+        }
+        None
+    }
+
+    /// The heap offsets of the `len` bytes at address `addr`, None unless all of them are in
+    /// the heap
+    fn heap_range(&self, addr: Reg, len: Reg) -> Option<Range<usize>> {
+        let start = usize::try_from(addr).ok()?.checked_sub(self.ro_data.len())?;
+        let end = start.checked_add(usize::try_from(len).ok()?)?;
+        if end <= self.heap.len() {
+            Some(start..end)
+        } else {
+            None
+        }
+    }
+
+    /// Records why the program can not go on and reports it, always false so failing
+    /// instructions can return it
+    fn fail(&mut self, error: VmError) -> bool {
+        diagnostic!(self, "{}! Terminating!", error);
+        self.error = Some(error);
+        false
+    }
+
+    /// Why the program stopped, None unless it failed. Cleared by `reset`.
+    pub fn error(&self) -> Option<&VmError> {
+        self.error.as_ref()
+    }
+
+    /// Reports why execution stopped or went wrong. Every diagnostic the VM prints while running
+    /// goes through here, and they reach the output only when verbose.
+    fn diagnostic(&mut self, message: core::fmt::Arguments) {
+        if self.verbose {
+            // a failing output has nowhere left to report to
+            let _ = writeln!(self.output, "{}", message);
+        }
+    }
+
+    /// Moves the pc where a checked jump landed, false if the jump was refused
+    fn jump(&mut self, result: Result<Pc, PcError>) -> bool {
+        match result {
+            Ok(pc) => {
+                self.pc = pc.0;
+                true
+            }
+            Err(e) => self.fail(VmError::BadJump(e)),
+        }
+    }
+
+    /// Writes the string at `start` to the output, false if the VM has to stop
+    fn print_string(&mut self, start: usize) -> bool {
+        let bytes = match self.read_string(start) {
+            Some(bytes) => bytes,
+            None => {
+                return self.fail(VmError::Other(format!(
+                    "No string terminator found after {}",
+                    start
+                )));
+            }
+        };
+        if let Err(e) = self.output.write_all(&bytes) {
+            return self.fail(VmError::Other(format!("Unable to write output: {:?}", e)));
+        }
+        true
+    }
+
+    pub fn run(&mut self) -> RunOutcome {
+        loop {
+            // only pay for the copy when something is watched
+            let before = if self.watched.is_empty() {
+                None
+            } else {
+                Some(self.registers.clone())
+            };
+            let no_err = self.execute_instruction();
+            if let Some(before) = before {
+                for &register in &self.watched {
+                    let old = before[register as usize];
+                    let new = self.registers[register as usize];
+                    if old != new {
+                        return RunOutcome::Watchpoint(WatchpointHit { register, old, new });
+                    }
+                }
+            }
+            if !no_err {
+                return RunOutcome::Finished;
+            }
+        }
     }
 
-    pub fn run(&mut self) {
-        let mut no_err = true;
-        while no_err {
-            no_err = self.execute_instruction();
+    /// Runs at most `n` instructions, stopping early if the program halts
+    pub fn run_steps(&mut self, n: usize) -> StepReport {
+        let before = self.registers.clone();
+        let mut halted = false;
+        for _ in 0..n {
+            if !self.execute_instruction() {
+                halted = true;
+                break;
+            }
+        }
+        let changed = (0..self.registers.len())
+            .filter(|&i| before[i] != self.registers[i])
+            .map(|i| i as u8)
+            .collect();
+        StepReport {
+            changed,
+            pc: self.pc,
+            halted,
         }
     }
 
+    /// Runs the program under `limits` until it finishes or hits one of them, ignoring
+    /// watchpoints. The VM's own caps are back in place afterwards.
+    pub fn run_sandboxed(&mut self, limits: Limits) -> SandboxOutcome {
+        let (max_heap, max_stack) = (self.max_heap, self.max_stack);
+        self.max_heap = limits.max_heap.or(max_heap);
+        self.max_stack = limits.max_stack.or(max_stack);
+        let mut steps = 0;
+        let outcome = loop {
+            if limits.max_steps == Some(steps) {
+                break SandboxOutcome::StepLimit;
+            }
+            steps += 1;
+            if !self.execute_instruction() {
+                break match &self.error {
+                    None => SandboxOutcome::Completed,
+                    Some(VmError::HeapLimit { .. }) => SandboxOutcome::HeapLimit,
+                    Some(VmError::StackLimit { .. }) => SandboxOutcome::StackLimit,
+                    Some(e) => SandboxOutcome::Failed(e.clone()),
+                };
+            }
+        };
+        self.max_heap = max_heap;
+        self.max_stack = max_stack;
+        outcome
+    }
+
     fn decode_opcode(&mut self) -> Opcode {
-        if self.pc % 4 != 0 {
+        if !self.pc.is_multiple_of(4) {
+            #[cfg(feature = "std")]
             self.dbg_vm();
             panic!("Executtion error: pc % 4 != 0"); // sanity check
         }
-        let opcode = Opcode::from(self.program[self.pc]);
+        self.ir = match self.compiled.as_ref().and_then(|c| c.get(self.pc / 4)) {
+            Some(instruction) => *instruction,
+            None => decode(&self.program[self.pc..]),
+        };
         self.pc += 1;
-        opcode
+        self.ir.opcode()
+    }
+
+    /// Executes the next instruction and returns its opcode, None once the program has run off
+    /// its end. HLT leaves the pc inside itself, so stepping on after it fails until `reset`.
+    pub fn step(&mut self) -> Result<Option<Opcode>, VmError> {
+        if self.pc >= self.program.len() {
+            return Ok(None);
+        }
+        if !self.execute_instruction() {
+            if let Some(error) = &self.error {
+                return Err(error.clone());
+            }
+        }
+        Ok(Some(self.ir.opcode()))
     }
 
+    /// Executes the next instruction, see `step` to learn what it was
     pub fn run_once(&mut self) {
-        self.execute_instruction();
+        let _ = self.step();
     }
 
     fn execute_instruction(&mut self) -> bool {
         if self.pc >= self.program.len() {
             return false;
         }
+        self.cycle_count += 1;
 
-        match self.decode_opcode() {
+        let pc = self.pc;
+        if !pc.is_multiple_of(4) {
+            return self.fail(VmError::MisalignedPc { pc });
+        }
+        if pc + 4 > self.program.len() {
+            return self.fail(VmError::TruncatedProgram);
+        }
+        let opcode = self.decode_opcode();
+        if let Some(idx) = self.bad_register() {
+            return self.fail(VmError::RegisterOutOfRange { idx });
+        }
+        if self.tracing {
+            self.trace_log.push((pc, opcode));
+        }
+        if self.profiling {
+            *self.profile.entry(opcode).or_insert(0) += 1;
+        }
+        match opcode {
             Opcode::HLT => {
-                println!("HLT encountered");
+                diagnostic!(self, "HLT encountered");
                 return false;
             }
             Opcode::LOAD => {
                 let register = self.next_8_bits() as usize;
-                let number = self.next_16_bits() as u16;
-                self.registers[register] = number as i32;
+                let number = self.next_16_bits();
+                self.registers[register] = number as Reg;
+            }
+            Opcode::LOADS => {
+                let register = self.next_8_bits() as usize;
+                let number = self.next_16_bits() as i16;
+                self.registers[register] = number as Reg;
             }
             Opcode::ADD => {
                 let r0 = self.next_8_bits() as usize;
                 let r1 = self.next_8_bits() as usize;
                 let r2 = self.next_8_bits() as usize;
-                self.registers[r0] = self.registers[r1] + self.registers[r2];
+                let (value, overflow) = self.registers[r1].overflowing_add(self.registers[r2]);
+                self.overflow_flag = overflow;
+                self.registers[r0] = value;
             }
             Opcode::SUB => {
                 let r0 = self.next_8_bits() as usize;
                 let r1 = self.next_8_bits() as usize;
                 let r2 = self.next_8_bits() as usize;
-                self.registers[r0] = self.registers[r1] - self.registers[r2];
+                let (value, overflow) = self.registers[r1].overflowing_sub(self.registers[r2]);
+                self.overflow_flag = overflow;
+                self.registers[r0] = value;
             }
             Opcode::MUL => {
                 let r0 = self.next_8_bits() as usize;
                 let r1 = self.next_8_bits() as usize;
                 let r2 = self.next_8_bits() as usize;
-                self.registers[r0] = self.registers[r1] * self.registers[r2];
+                let (value, overflow) = self.registers[r1].overflowing_mul(self.registers[r2]);
+                self.overflow_flag = overflow;
+                self.registers[r0] = value;
             }
             Opcode::DIV => {
                 let r0 = self.next_8_bits() as usize;
                 let r1 = self.next_8_bits() as usize;
                 let r2 = self.next_8_bits() as usize;
-                self.registers[r0] = self.registers[r1] / self.registers[r2];
-                self.remainder = (self.registers[r1] % self.registers[r2]) as u32;
+                let (a, b) = (self.registers[r1], self.registers[r2]);
+                if b == 0 {
+                    return self.fail(VmError::DivByZero { pc });
+                }
+                // Reg::MIN / -1 is not representable, it wraps to Reg::MIN like NEG does
+                let quotient = a.checked_div(b);
+                self.overflow_flag = quotient.is_none();
+                self.registers[r0] = quotient.unwrap_or(Reg::MIN);
+                self.remainder = a.checked_rem(b).unwrap_or(0) as UReg;
+            }
+            Opcode::DIVU => {
+                let r0 = self.next_8_bits() as usize;
+                let a_register = self.next_8_bits() as usize;
+                let a = self.registers[a_register] as UReg;
+                let b_register = self.next_8_bits() as usize;
+                let b = self.registers[b_register] as UReg;
+                if b == 0 {
+                    return self.fail(VmError::DivByZero { pc });
+                }
+                self.registers[r0] = (a / b) as Reg;
+                self.remainder = a % b;
             }
             Opcode::JMP => {
                 let register = self.next_8_bits() as usize;
-                let target = self.registers[register];
-                self.pc = target as usize;
+                let target = self.registers[register] as usize;
+                if !self.jump(Pc(self.pc).jump_to(target, self.program.len())) {
+                    return false;
+                }
             }
+            // the offsets of JMPF and JMPB count from just after their register operand
             Opcode::JMPF => {
                 let register = self.next_8_bits() as usize;
                 let offset = self.registers[register] as usize;
-                self.pc += offset;
+                if !self.jump(Pc(self.pc).offset_forward(offset, self.program.len())) {
+                    return false;
+                }
             }
             Opcode::JMPB => {
                 let register = self.next_8_bits() as usize;
                 let offset = self.registers[register] as usize;
-                self.pc -= offset;
-            }
-            Opcode::EQ => {
-                let r0 = self.next_8_bits() as usize;
-                let r1 = self.next_8_bits() as usize;
-                self.equal_flag = self.registers[r0] == self.registers[r1];
-                self.next_8_bits();
-            }
-            Opcode::NEQ => {
-                let r0 = self.next_8_bits() as usize;
-                let r1 = self.next_8_bits() as usize;
-                self.equal_flag = self.registers[r0] != self.registers[r1];
-                self.next_8_bits();
-            }
-            Opcode::GT => {
-                let r0 = self.next_8_bits() as usize;
-                let r1 = self.next_8_bits() as usize;
-                self.equal_flag = self.registers[r0] > self.registers[r1];
-                self.next_8_bits();
+                if !self.jump(Pc(self.pc).offset_backward(offset, self.program.len())) {
+                    return false;
+                }
             }
-            Opcode::LT => {
-                let r0 = self.next_8_bits() as usize;
-                let r1 = self.next_8_bits() as usize;
-                self.equal_flag = self.registers[r0] < self.registers[r1];
+            Opcode::EQ | Opcode::NEQ | Opcode::GT | Opcode::LT | Opcode::GTE | Opcode::LTE => {
+                let left_register = self.next_8_bits() as usize;
+                let left = self.registers[left_register];
+                let right_register = self.next_8_bits() as usize;
+                let right = self.registers[right_register];
+                self.compare(left, right);
                 self.next_8_bits();
             }
-            Opcode::GTE => {
-                let r0 = self.next_8_bits() as usize;
-                let r1 = self.next_8_bits() as usize;
-                self.equal_flag = self.registers[r0] >= self.registers[r1];
-                self.next_8_bits();
+            Opcode::EQI | Opcode::NEQI | Opcode::GTI | Opcode::LTI => {
+                let left_register = self.next_8_bits() as usize;
+                let left = self.registers[left_register];
+                let right = self.next_16_bits() as i16 as Reg;
+                self.compare(left, right);
             }
-            Opcode::LTE => {
-                let r0 = self.next_8_bits() as usize;
-                let r1 = self.next_8_bits() as usize;
-                self.equal_flag = self.registers[r0] <= self.registers[r1];
-                self.next_8_bits();
+            Opcode::JEQD | Opcode::JNEQD => {
+                let target = self.next_16_bits() as usize;
+                let pc = Pc(self.pc);
+                let len = self.program.len();
+                let taken = self.equal_flag() == (opcode == Opcode::JEQD);
+                let result = if taken { pc.jump_to(target, len) } else { pc.advance(len) };
+                if !self.jump(result) {
+                    return false;
+                }
             }
-            Opcode::JEQD => {
+            Opcode::JGTD | Opcode::JLTD | Opcode::JGED | Opcode::JLED => {
                 let target = self.next_16_bits();
-                if self.equal_flag {
-                    self.pc = target as usize;
-                } else {
-                    self.next_8_bits();
+                let taken = match (opcode, self.comparison) {
+                    (_, Comparison::None) => false,
+                    (Opcode::JGTD, c) => c == Comparison::Greater,
+                    (Opcode::JLTD, c) => c == Comparison::Less,
+                    (Opcode::JGED, c) => c != Comparison::Less,
+                    (_, c) => c != Comparison::Greater,
+                };
+                let pc = Pc(self.pc);
+                let len = self.program.len();
+                let result = if taken { pc.jump_to(target as usize, len) } else { pc.advance(len) };
+                if !self.jump(result) {
+                    return false;
                 }
             }
             Opcode::JEQ => {
                 let register = self.next_8_bits() as usize;
-                let offset = self.registers[register] as usize;
-                if !self.equal_flag {
-                    self.pc = offset;
-                } else {
-                    self.next_16_bits();
+                let target = self.registers[register] as usize;
+                let pc = Pc(self.pc);
+                let len = self.program.len();
+                // JEQ has always jumped when the last comparison did not hold
+                let taken = !self.equal_flag();
+                let result = if taken { pc.jump_to(target, len) } else { pc.advance(len) };
+                if !self.jump(result) {
+                    return false;
                 }
             }
             Opcode::ALOC => {
                 let register = self.next_8_bits() as usize;
                 let bytes = self.registers[register];
-                let new_end = self.heap.len() as i32 + bytes;
-                self.heap.resize(new_end as usize, 0);
+                if bytes < 0 {
+                    return self.fail(VmError::Other(format!("ALOC of a negative size {}", bytes)));
+                }
+                let new_end = self.heap.len() + bytes as usize;
+                if let Some(max) = self.max_heap {
+                    if new_end > max {
+                        return self.fail(VmError::HeapLimit {
+                            requested: new_end,
+                            max,
+                        });
+                    }
+                }
+                self.heap.resize(new_end, 0);
                 self.pc += 2;
             }
             Opcode::INC => {
                 let register = self.next_8_bits() as usize;
-                self.registers[register] += 1;
+                let (value, overflow) = self.registers[register].overflowing_add(1);
+                self.overflow_flag = overflow;
+                self.registers[register] = value;
                 self.pc += 2;
             }
             Opcode::DEC => {
                 let register = self.next_8_bits() as usize;
-                self.registers[register] -= 1;
+                let (value, overflow) = self.registers[register].overflowing_sub(1);
+                self.overflow_flag = overflow;
+                self.registers[register] = value;
                 self.pc += 2;
             }
-            _ => {
-                println!("Unrecognized opcode found! Terminating!");
-                return false;
+            Opcode::ADDI => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let imm = self.next_8_bits() as Reg;
+                let (value, overflow) = self.registers[r1].overflowing_add(imm);
+                self.overflow_flag = overflow;
+                self.registers[r0] = value;
             }
-        }
-        true
-    }
-
-    #[allow(dead_code)]
-    fn pc_valid(&self) -> bool {
-        self.pc % 4 == 0
+            Opcode::NEG => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let value = self.registers[r1].checked_neg();
+                self.overflow_flag = value.is_none();
+                // -Reg::MIN is not representable, it stays Reg::MIN like a wrapping negation
+                self.registers[r0] = value.unwrap_or(Reg::MIN);
+                self.next_8_bits();
+            }
+            Opcode::CMOVE => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                if self.equal_flag() {
+                    self.registers[r0] = self.registers[r1];
+                }
+                self.next_8_bits();
+            }
+            Opcode::CMOVNE => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                if !self.equal_flag() {
+                    self.registers[r0] = self.registers[r1];
+                }
+                self.next_8_bits();
+            }
+            Opcode::JMPFI => {
+                let offset = self.next_16_bits() as usize;
+                self.next_8_bits();
+                if !self.jump(Pc(self.pc).offset_forward(offset, self.program.len())) {
+                    return false;
+                }
+            }
+            Opcode::JMPBI => {
+                let offset = self.next_16_bits() as usize;
+                self.next_8_bits();
+                if !self.jump(Pc(self.pc).offset_backward(offset, self.program.len())) {
+                    return false;
+                }
+            }
+            Opcode::FLOAD => {
+                let register = self.next_8_bits() as usize;
+                let number = self.next_16_bits();
+                self.float_registers[register] = f16_bits_to_f64(number);
+            }
+            Opcode::FADD => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.float_registers[r0] = self.float_registers[r1] + self.float_registers[r2];
+            }
+            Opcode::FSUB => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.float_registers[r0] = self.float_registers[r1] - self.float_registers[r2];
+            }
+            Opcode::FMUL => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.float_registers[r0] = self.float_registers[r1] * self.float_registers[r2];
+            }
+            Opcode::FDIV => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.float_registers[r0] = self.float_registers[r1] / self.float_registers[r2];
+            }
+            Opcode::ITOF => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                self.float_registers[r0] = self.registers[r1] as f64;
+                self.next_8_bits();
+            }
+            Opcode::FTOI => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                // `as` truncates toward zero, saturates out of range values and maps NaN to 0
+                self.registers[r0] = self.float_registers[r1] as Reg;
+                self.next_8_bits();
+            }
+            Opcode::PRINTS => {
+                let start = self.next_16_bits() as usize;
+                self.next_8_bits();
+                if !self.print_string(start) {
+                    return false;
+                }
+            }
+            Opcode::DUMP => {
+                let snapshot = format!(
+                    "pc: {} equal_flag: {} overflow_flag: {} remainder: {}\nregisters: {:?}\n",
+                    self.pc - 1,
+                    self.equal_flag(),
+                    self.overflow_flag,
+                    self.remainder,
+                    self.registers
+                );
+                self.pc += 3;
+                if let Err(e) = self.output.write_all(snapshot.as_bytes()) {
+                    return self.fail(VmError::Other(format!("Unable to write output: {:?}", e)));
+                }
+            }
+            Opcode::NOP => {
+                self.pc += 3;
+            }
+            Opcode::RAND => {
+                let register = self.next_8_bits() as usize;
+                // the high bits of xorshift are the better distributed ones
+                self.registers[register] = (self.next_random() >> (64 - Reg::BITS)) as Reg;
+                self.pc += 2;
+            }
+            Opcode::RANDR => {
+                let r0 = self.next_8_bits() as usize;
+                // wide enough that `hi - lo` can not overflow for any register width
+                let lo_register = self.next_8_bits() as usize;
+                let lo = self.registers[lo_register] as i128;
+                let hi_register = self.next_8_bits() as usize;
+                let hi = self.registers[hi_register] as i128;
+                if lo >= hi {
+                    return self.fail(VmError::Other(format!(
+                        "RANDR range [{}, {}) is empty",
+                        lo,
+                        hi
+                    )));
+                }
+                let offset = self.next_random() % (hi - lo) as u64;
+                self.registers[r0] = (lo + offset as i128) as Reg;
+            }
+            Opcode::TRAP => {
+                let n = self.next_16_bits() as usize;
+                self.next_8_bits();
+                match self.trap_table.get(n).cloned().flatten() {
+                    Some(handler) => {
+                        if let Some(max) = self.max_stack {
+                            if self.call_stack.len() >= max {
+                                return self.fail(VmError::StackLimit { max });
+                            }
+                        }
+                        self.call_stack.push(self.pc);
+                        if !self.jump(Pc(self.pc).jump_to(handler, self.program.len())) {
+                            return false;
+                        }
+                    }
+                    None => {
+                        return self.fail(VmError::Other(format!(
+                            "No handler installed for trap {}",
+                            n
+                        )));
+                    }
+                }
+            }
+            Opcode::GETC => {
+                let register = self.next_8_bits() as usize;
+                let mut byte = [0u8; 1];
+                self.registers[register] = match self.input.read(&mut byte) {
+                    Ok(0) => -1,
+                    Ok(_) => byte[0] as Reg,
+                    Err(e) => {
+                        return self.fail(VmError::Other(format!("Unable to read input: {:?}", e)));
+                    }
+                };
+                self.next_16_bits();
+            }
+            Opcode::PUTC => {
+                let register = self.next_8_bits() as usize;
+                let byte = self.registers[register] as u8;
+                if let Err(e) = self.output.write_all(&[byte]) {
+                    return self.fail(VmError::Other(format!("Unable to write output: {:?}", e)));
+                }
+                self.next_16_bits();
+            }
+            // both shift by the amount modulo the register width, they only differ for negative
+            // values
+            Opcode::SHR => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let amount_register = self.next_8_bits() as usize;
+                let amount = self.registers[amount_register] as u32;
+                self.registers[r0] = self.registers[r1].wrapping_shr(amount);
+            }
+            Opcode::USHR => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let amount_register = self.next_8_bits() as usize;
+                let amount = self.registers[amount_register] as u32;
+                self.registers[r0] = (self.registers[r1] as UReg).wrapping_shr(amount) as Reg;
+            }
+            Opcode::ROL => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let amount_register = self.next_8_bits() as usize;
+                let amount = self.registers[amount_register] as u32 % Reg::BITS;
+                self.registers[r0] = self.registers[r1].rotate_left(amount);
+            }
+            Opcode::ROR => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let amount_register = self.next_8_bits() as usize;
+                let amount = self.registers[amount_register] as u32 % Reg::BITS;
+                self.registers[r0] = self.registers[r1].rotate_right(amount);
+            }
+            Opcode::MIN => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.registers[r0] = self.registers[r1].min(self.registers[r2]);
+            }
+            Opcode::MAX => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.registers[r0] = self.registers[r1].max(self.registers[r2]);
+            }
+            Opcode::ABS => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let value = self.registers[r1].checked_abs();
+                self.overflow_flag = value.is_none();
+                // |Reg::MIN| is not representable, it stays Reg::MIN like NEG does
+                self.registers[r0] = value.unwrap_or(Reg::MIN);
+                self.next_8_bits();
+            }
+            Opcode::POPCNT | Opcode::CLZ | Opcode::CTZ => {
+                let r0 = self.next_8_bits() as usize;
+                let value_register = self.next_8_bits() as usize;
+                let value = self.registers[value_register];
+                self.registers[r0] = match opcode {
+                    Opcode::POPCNT => value.count_ones(),
+                    Opcode::CLZ => value.leading_zeros(),
+                    _ => value.trailing_zeros(),
+                } as Reg;
+                self.next_8_bits();
+            }
+            Opcode::SB | Opcode::SH | Opcode::SW => {
+                let value_register = self.next_8_bits() as usize;
+                let value = self.registers[value_register];
+                let addr_register = self.next_8_bits() as usize;
+                let addr = self.registers[addr_register] as usize;
+                let width = match opcode {
+                    Opcode::SB => 1,
+                    Opcode::SH => 2,
+                    _ => 4,
+                };
+                if !self.store(addr, value, width) {
+                    return false;
+                }
+                self.next_8_bits();
+            }
+            Opcode::LB | Opcode::LBS | Opcode::LH | Opcode::LHS | Opcode::LW => {
+                let register = self.next_8_bits() as usize;
+                let addr_register = self.next_8_bits() as usize;
+                let addr = self.registers[addr_register] as usize;
+                let width = match opcode {
+                    Opcode::LB | Opcode::LBS => 1,
+                    Opcode::LH | Opcode::LHS => 2,
+                    _ => 4,
+                };
+                let value = match self.load(addr, width) {
+                    Some(value) => value,
+                    None => return false,
+                };
+                self.registers[register] = match opcode {
+                    Opcode::LBS => value as u8 as i8 as Reg,
+                    Opcode::LHS => value as u16 as i16 as Reg,
+                    // a word is 4 bytes whatever the register width, sign-extended like LHS
+                    _ => value as i32 as Reg,
+                };
+                self.next_8_bits();
+            }
+            // the saturating versions clamp to Reg::MIN or Reg::MAX instead of overflowing
+            Opcode::ADDS => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.registers[r0] = self.registers[r1].saturating_add(self.registers[r2]);
+            }
+            Opcode::SUBS => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.registers[r0] = self.registers[r1].saturating_sub(self.registers[r2]);
+            }
+            Opcode::MULS => {
+                let r0 = self.next_8_bits() as usize;
+                let r1 = self.next_8_bits() as usize;
+                let r2 = self.next_8_bits() as usize;
+                self.registers[r0] = self.registers[r1].saturating_mul(self.registers[r2]);
+            }
+            Opcode::MOVLO => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = self.next_16_bits() as Reg;
+            }
+            // builds a 32-bit value, which is sign-extended in wider registers like LW's
+            Opcode::MOVHI => {
+                let register = self.next_8_bits() as usize;
+                let high = (self.next_16_bits() as u32) << 16;
+                let low = self.registers[register] as u32 & 0xFFFF;
+                self.registers[register] = (high | low) as i32 as Reg;
+            }
+            Opcode::MFREM => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = self.remainder as Reg;
+                self.next_16_bits();
+            }
+            Opcode::TIME => {
+                let register = self.next_8_bits() as usize;
+                let ticks = match &mut self.clock {
+                    Some(clock) => clock(),
+                    None => self.cycle_count,
+                };
+                self.registers[register] = ticks as Reg;
+                self.next_16_bits();
+            }
+            Opcode::LEA => {
+                let register = self.next_8_bits() as usize;
+                self.registers[register] = self.next_16_bits() as Reg;
+            }
+            Opcode::RET => match self.call_stack.pop() {
+                Some(address) => self.pc = address,
+                None => return self.fail(VmError::StackUnderflow),
+            },
+            Opcode::ENTER => {
+                let n = self.next_16_bits() as usize;
+                self.next_8_bits();
+                if n > self.registers.len() {
+                    return self.fail(VmError::Other(format!(
+                        "ENTER #{} saves more than the {} registers",
+                        n,
+                        self.registers.len()
+                    )));
+                }
+                self.frames.push(self.registers[..n].to_vec());
+            }
+            Opcode::LEAVE => {
+                let n = self.next_16_bits() as usize;
+                self.next_8_bits();
+                match self.frames.pop() {
+                    Some(saved) if saved.len() == n => self.registers[..n].copy_from_slice(&saved),
+                    Some(saved) => {
+                        return self.fail(VmError::Other(format!(
+                            "LEAVE #{} does not match ENTER #{}",
+                            n,
+                            saved.len()
+                        )));
+                    }
+                    None => {
+                        return self.fail(VmError::Other("LEAVE without an ENTER".to_string()));
+                    }
+                }
+            }
+            Opcode::PRINTSR => {
+                let register = self.next_8_bits() as usize;
+                self.next_16_bits();
+                let start = self.registers[register];
+                if start < 0 {
+                    return self.fail(VmError::Other(format!(
+                        "PRINTSR address {} is negative",
+                        start
+                    )));
+                }
+                if !self.print_string(start as usize) {
+                    return false;
+                }
+            }
+            Opcode::MEMCPY => {
+                let dst_register = self.next_8_bits() as usize;
+                let dst = self.registers[dst_register];
+                let src_register = self.next_8_bits() as usize;
+                let src = self.registers[src_register];
+                let len_register = self.next_8_bits() as usize;
+                let len = self.registers[len_register];
+                match (self.heap_range(dst, len), self.heap_range(src, len)) {
+                    (Some(to), Some(from)) => self.heap.copy_within(from, to.start),
+                    (None, _) => return self.fail(VmError::HeapOutOfBounds { addr: dst as usize }),
+                    (_, None) => return self.fail(VmError::HeapOutOfBounds { addr: src as usize }),
+                }
+            }
+            Opcode::MEMSET => {
+                let addr_register = self.next_8_bits() as usize;
+                let addr = self.registers[addr_register];
+                let value_register = self.next_8_bits() as usize;
+                let value = self.registers[value_register];
+                let len_register = self.next_8_bits() as usize;
+                let len = self.registers[len_register];
+                match self.heap_range(addr, len) {
+                    Some(range) => self.heap[range].fill(value as u8),
+                    None => return self.fail(VmError::HeapOutOfBounds { addr: addr as usize }),
+                }
+            }
+            _ => {
+                let byte = self.program[pc];
+                return self.fail(VmError::IllegalOpcode { byte, pc });
+            }
+        }
+        true
+    }
+
+    #[allow(dead_code)]
+    fn pc_valid(&self) -> bool {
+        self.pc.is_multiple_of(4)
     }
 
+    #[cfg(feature = "std")]
     pub fn dbg_program(&self) {
         println!("Instructions: ");
         let len = self.program.len();
@@ -220,22 +1517,80 @@ impl VM {
         println!("-------- end --------\n");
     }
 
+    #[cfg(feature = "std")]
     pub fn dbg_vm(&self) {
-        println!("pc: {}", self.pc);
-        println!("Total instruction num: {}", self.program.len());
-        println!("Registers:");
-        for i in 0..4 {
-            for j in 0..8 {
-                print!("{:3} ", self.registers[i * 4 + j]);
+        println!("{}", self);
+    }
+}
+
+/// A compact summary listing only the registers that are not zero
+impl core::fmt::Display for VM {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        writeln!(
+            f,
+            "pc: {} cycles: {} equal_flag: {} program: {} bytes heap: {} bytes",
+            self.pc,
+            self.cycle_count,
+            self.equal_flag(),
+            self.program.len(),
+            self.heap.len()
+        )?;
+        write!(f, "registers:")?;
+        for (i, value) in self.registers.iter().enumerate() {
+            if *value != 0 {
+                write!(f, " ${}={}", i, value)?;
             }
-            println!();
         }
+        Ok(())
+    }
+}
+
+/// The output a new VM writes to, stdout with `std` and nowhere without it
+fn default_output() -> Box<dyn Write> {
+    #[cfg(feature = "std")]
+    return Box::new(io::stdout());
+    #[cfg(not(feature = "std"))]
+    return Box::new(io::Null);
+}
+
+/// The input a new VM reads from, stdin with `std` and nothing without it
+fn default_input() -> Box<dyn Read> {
+    #[cfg(feature = "std")]
+    return Box::new(io::stdin());
+    #[cfg(not(feature = "std"))]
+    return Box::new(io::Null);
+}
+
+/// Decodes the instruction at the start of `bytes`, missing operand bytes read as 0
+fn decode(bytes: &[u8]) -> Instruction {
+    let mut padded = [0; 4];
+    for (i, byte) in bytes.iter().take(4).enumerate() {
+        padded[i] = *byte;
     }
+    Instruction::from_bytes(padded)
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "std"))]
 mod tests {
     use super::*;
+    use crate::assembler::Assembler;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Collects everything the VM prints so tests can inspect it
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
 
     fn get_test_vm() -> VM {
         VM::new()
@@ -244,7 +1599,7 @@ mod tests {
     #[test]
     fn test_create_vm() {
         let test_vm = VM::new();
-        assert_eq!(test_vm.registers[0], 0)
+        assert_eq!(test_vm.registers()[0], 0)
     }
 
     #[test]
@@ -253,7 +1608,7 @@ mod tests {
         let test_bytes = vec![Opcode::HLT.into(), 0, 0, 0];
         test_vm.program = test_bytes;
         test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.pc(), 1);
     }
 
     #[test]
@@ -262,7 +1617,7 @@ mod tests {
         let test_bytes = vec![200, 0, 0, 0];
         test_vm.program = test_bytes;
         test_vm.run();
-        assert_eq!(test_vm.pc, 1);
+        assert_eq!(test_vm.pc(), 1);
     }
 
     #[test]
@@ -270,17 +1625,24 @@ mod tests {
         let mut test_vm = get_test_vm();
         test_vm.program = vec![Opcode::LOAD.into(), 0, 1, 244]; // Remember, this is how we represent 500 using two u8s in little endian format
         test_vm.run();
-        assert_eq!(test_vm.registers[0], 500);
+        assert_eq!(test_vm.registers()[0], 500);
         assert!(test_vm.pc_valid());
     }
 
     #[test]
     fn test_jmp_opcode() {
         let mut test_vm = get_test_vm();
-        test_vm.registers[0] = 255;
-        test_vm.program = vec![Opcode::JMP.into(), 0, 0, 0];
+        test_vm.registers[0] = 4;
+        test_vm.program = vec![Opcode::JMP.into(), 0, 0, 0, Opcode::HLT.into(), 0, 0, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 255);
+        assert_eq!(test_vm.pc(), 4);
+
+        // targets past the end or inside an instruction stop the VM
+        for target in [255, 2, -4] {
+            test_vm.registers[0] = target;
+            test_vm.pc = 0;
+            assert!(!test_vm.execute_instruction(), "target {}", target);
+        }
     }
 
     #[test]
@@ -289,7 +1651,18 @@ mod tests {
         test_vm.registers[0] = 2;
         test_vm.program = vec![Opcode::JMPF.into(), 0, 0, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.pc(), 4);
+
+        // a negative offset is out of bounds rather than a jump backward
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -4;
+        test_vm.program = vec![Opcode::HLT.into(), 0, 0, 0, Opcode::JMPF.into(), 0, 0, 0];
+        test_vm.pc = 4;
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VmError::BadJump(PcError::OutOfBounds { target: i64::MAX }))
+        );
     }
 
     #[test]
@@ -298,7 +1671,7 @@ mod tests {
         test_vm.registers[0] = 2;
         test_vm.program = vec![Opcode::JMPB.into(), 0, 0, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 0);
+        assert_eq!(test_vm.pc(), 0);
     }
 
     #[test]
@@ -308,11 +1681,11 @@ mod tests {
         test_vm.registers[1] = 2;
         test_vm.program = vec![Opcode::EQ.into(), 0, 1, 0, Opcode::EQ.into(), 0, 1, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(test_vm.equal_flag());
         assert!(test_vm.pc_valid());
         test_vm.registers[1] = 3;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(!test_vm.equal_flag());
         assert!(test_vm.pc_valid());
     }
 
@@ -322,12 +1695,14 @@ mod tests {
         test_vm.registers[0] = 2;
         test_vm.registers[1] = 2;
         test_vm.program = vec![Opcode::NEQ.into(), 0, 1, 0, Opcode::NEQ.into(), 0, 1, 0];
+        // like every comparison it records the ordering, JNEQD is what jumps when they differ
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert!(test_vm.equal_flag());
         assert!(test_vm.pc_valid());
         test_vm.registers[1] = 3;
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert!(!test_vm.equal_flag());
+        assert_eq!(test_vm.comparison(), Comparison::Less);
         assert!(test_vm.pc_valid());
     }
 
@@ -356,16 +1731,20 @@ mod tests {
             0,
         ];
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, false);
+        assert_eq!(test_vm.comparison(), Comparison::Less);
+        assert!(!test_vm.equal_flag());
         assert!(test_vm.pc_valid());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert_eq!(test_vm.comparison(), Comparison::Less);
+        assert!(!test_vm.equal_flag());
         assert!(test_vm.pc_valid());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert_eq!(test_vm.comparison(), Comparison::Equal);
+        assert!(test_vm.equal_flag());
         assert!(test_vm.pc_valid());
         test_vm.run_once();
-        assert_eq!(test_vm.equal_flag, true);
+        assert_eq!(test_vm.comparison(), Comparison::Equal);
+        assert!(test_vm.equal_flag());
         assert!(test_vm.pc_valid());
     }
 
@@ -373,18 +1752,23 @@ mod tests {
     fn test_jeqd_opcode() {
         let mut test_vm = get_test_vm();
         test_vm.registers[0] = 4;
-        test_vm.equal_flag = true;
-        test_vm.program = vec![Opcode::JEQ.into(), 0, 0, 0, Opcode::JEQD.into(), 8, 10, 0, Opcode::JEQD.into(), 0, 16, 0];
+        test_vm.comparison = Comparison::Equal;
+        test_vm.program = vec![Opcode::JEQ.into(), 0, 0, 0, Opcode::JEQD.into(), 8, 10, 0, Opcode::JEQD.into(), 0, 12, 0];
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 4);
+        assert_eq!(test_vm.pc(), 4);
 
-        test_vm.equal_flag = false;
+        test_vm.comparison = Comparison::Less;
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 8);
+        assert_eq!(test_vm.pc(), 8);
 
-        test_vm.equal_flag = true;
+        test_vm.comparison = Comparison::Equal;
         test_vm.run_once();
-        assert_eq!(test_vm.pc, 16);
+        assert_eq!(test_vm.pc(), 12);
+
+        // a taken jump past the end stops the VM
+        test_vm.program[10] = 16;
+        test_vm.pc = 8;
+        assert!(!test_vm.execute_instruction());
     }
 
     #[test]
@@ -396,4 +1780,1451 @@ mod tests {
         assert_eq!(test_vm.heap.len(), 1024);
         assert!(test_vm.pc_valid());
     }
+
+    #[test]
+    fn test_aloc_heap_limit() {
+        let mut test_vm = get_test_vm();
+        test_vm.set_max_heap(1024);
+        test_vm.registers[0] = 2048;
+        test_vm.registers[1] = 1024;
+        test_vm.program = vec![Opcode::ALOC.into(), 0, 0, 0, Opcode::ALOC.into(), 1, 0, 0];
+        assert!(!test_vm.execute_instruction());
+        assert!(test_vm.heap.is_empty());
+
+        // growing up to the limit is fine
+        test_vm.pc = 4;
+        assert!(test_vm.execute_instruction());
+        assert_eq!(test_vm.heap.len(), 1024);
+    }
+
+    #[test]
+    fn test_aloc_negative() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = -1;
+        test_vm.program = vec![Opcode::ALOC.into(), 0, 0, 0];
+        assert!(!test_vm.execute_instruction());
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_addi_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 10;
+        test_vm.program = vec![Opcode::ADDI.into(), 0, 1, 200];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 210);
+        assert_eq!(test_vm.registers()[1], 10);
+        assert!(test_vm.pc_valid());
+
+        // wraps around like NEG instead of panicking, noting the overflow
+        test_vm.registers[1] = Reg::MAX;
+        test_vm.program.extend([Opcode::ADDI.into(), 0, 1, 1]);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], Reg::MIN);
+        assert!(test_vm.overflow_flag);
+    }
+
+    #[test]
+    fn test_neg_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 42;
+        test_vm.program = vec![Opcode::NEG.into(), 0, 1, 0, Opcode::NEG.into(), 2, 0, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], -42);
+        assert!(!test_vm.overflow_flag);
+        assert!(test_vm.pc_valid());
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[2], 42);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_neg_opcode_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = Reg::MIN;
+        test_vm.program = vec![Opcode::NEG.into(), 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], Reg::MIN);
+        assert!(test_vm.overflow_flag);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_cycle_count() {
+        let mut test_vm = get_test_vm();
+        // count $0 down from 3, jumping back to the DEC at 8 until it reaches $2 (0)
+        test_vm.program = vec![
+            Opcode::LOAD.into(), 1, 0, 8,
+            Opcode::LOAD.into(), 0, 0, 3,
+            Opcode::DEC.into(), 0, 0, 0,
+            Opcode::EQ.into(), 0, 2, 0,
+            Opcode::JEQ.into(), 1, 0, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 0);
+        assert_eq!(test_vm.cycles(), 12);
+
+        test_vm.reset();
+        assert_eq!(test_vm.cycles(), 0);
+        assert_eq!(test_vm.pc(), 0);
+        test_vm.run();
+        assert_eq!(test_vm.cycles(), 12);
+    }
+
+    #[test]
+    fn test_cmove_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 7;
+        test_vm.program = vec![Opcode::CMOVE.into(), 0, 1, 0, Opcode::CMOVE.into(), 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 0);
+        assert!(test_vm.pc_valid());
+        test_vm.comparison = Comparison::Equal;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 7);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_cmovne_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 7;
+        test_vm.comparison = Comparison::Equal;
+        test_vm.program = vec![Opcode::CMOVNE.into(), 0, 1, 0, Opcode::CMOVNE.into(), 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 0);
+        assert!(test_vm.pc_valid());
+        test_vm.comparison = Comparison::Less;
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 7);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_jmpfi_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::JMPFI.into(), 0, 4, 0,
+            Opcode::INC.into(), 0, 0, 0,
+            Opcode::INC.into(), 1, 0, 0,
+        ];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 8);
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 0);
+        assert_eq!(test_vm.registers()[1], 1);
+
+        // jumping past the end of the program stops the VM where it was
+        test_vm.program = vec![Opcode::JMPFI.into(), 0, 8, 0];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 4);
+    }
+
+    #[test]
+    fn test_jmpbi_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::INC.into(), 0, 0, 0,
+            Opcode::JMPBI.into(), 0, 8, 0,
+        ];
+        test_vm.run_once();
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 0);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 2);
+
+        test_vm.program = vec![Opcode::JMPBI.into(), 0, 8, 0];
+        test_vm.pc = 0;
+        assert!(!test_vm.execute_instruction());
+    }
+
+    #[test]
+    fn test_fload_opcode() {
+        let mut test_vm = get_test_vm();
+        // 0x4100 is 2.5 in half precision
+        test_vm.program = vec![Opcode::FLOAD.into(), 3, 0x41, 0x00];
+        test_vm.run_once();
+        assert_eq!(test_vm.float_registers[3], 2.5);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_float_arithmetic_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.float_registers[0] = 7.5;
+        test_vm.float_registers[1] = 2.5;
+        test_vm.program = vec![
+            Opcode::FADD.into(), 2, 0, 1,
+            Opcode::FSUB.into(), 3, 0, 1,
+            Opcode::FMUL.into(), 4, 0, 1,
+            Opcode::FDIV.into(), 5, 0, 1,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.float_registers[2], 10.0);
+        assert_eq!(test_vm.float_registers[3], 5.0);
+        assert_eq!(test_vm.float_registers[4], 18.75);
+        assert_eq!(test_vm.float_registers[5], 3.0);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_itof_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = -12;
+        test_vm.program = vec![Opcode::ITOF.into(), 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.float_registers[0], -12.0);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_ftoi_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.float_registers[1] = 3.75;
+        test_vm.float_registers[2] = -3.75;
+        test_vm.float_registers[3] = 1e20;
+        test_vm.float_registers[4] = f64::NEG_INFINITY;
+        test_vm.program = vec![
+            Opcode::FTOI.into(), 1, 1, 0,
+            Opcode::FTOI.into(), 2, 2, 0,
+            Opcode::FTOI.into(), 3, 3, 0,
+            Opcode::FTOI.into(), 4, 4, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[1], 3);
+        assert_eq!(test_vm.registers()[2], -3);
+        assert_eq!(test_vm.registers()[3], Reg::MAX);
+        assert_eq!(test_vm.registers()[4], Reg::MIN);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_prints_opcode() {
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.ro_data = b"Hi\0there\0".to_vec();
+        test_vm.program = vec![Opcode::PRINTS.into(), 0, 3, 0, Opcode::PRINTS.into(), 0, 0, 0];
+        test_vm.run();
+        assert_eq!(output.0.borrow().as_slice(), b"thereHi");
+        assert!(test_vm.pc_valid());
+
+        // no terminator before the end of the data section
+        test_vm.ro_data = b"Hi".to_vec();
+        test_vm.pc = 4;
+        assert!(!test_vm.execute_instruction());
+    }
+
+    #[test]
+    fn test_assemble_and_print_string() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("msg: .asciiz 'Hello, world'\nprints @msg\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.load_program(program).unwrap();
+        test_vm.run();
+        assert_eq!(output.0.borrow().as_slice(), b"Hello, world");
+    }
+
+    #[test]
+    fn test_load_program_without_header() {
+        let mut test_vm = get_test_vm();
+        let result = test_vm.load_program(vec![Opcode::HLT.into(), 0, 0, 0]);
+        assert_eq!(result, Err(LoadError::BadHeader));
+    }
+
+    #[test]
+    fn test_load_program_malformed() {
+        let mut test_vm = get_test_vm();
+        let mut bytes = vec![0; PIE_HEADER_LENGTH + 2];
+        bytes[0..4].copy_from_slice(&PIE_HEADER_PREFIX);
+        assert_eq!(bytes.len(), 66);
+        let result = test_vm.load_program(bytes);
+        assert_eq!(result, Err(LoadError::MalformedProgram { code_len: 2 }));
+    }
+
+    #[test]
+    fn test_load_program_entry_point() {
+        let mut test_vm = get_test_vm();
+        let mut bytes = vec![0; PIE_HEADER_LENGTH + 8];
+        bytes[0..4].copy_from_slice(&PIE_HEADER_PREFIX);
+        bytes[11] = 4;
+        test_vm.load_program(bytes.clone()).unwrap();
+        assert_eq!(test_vm.pc(), 4);
+        test_vm.pc = 0;
+        test_vm.reset();
+        assert_eq!(test_vm.pc(), 4);
+
+        bytes[11] = 6;
+        let result = test_vm.load_program(bytes);
+        assert_eq!(result, Err(LoadError::BadEntryPoint { entry: 6 }));
+    }
+
+    #[test]
+    fn test_printsr_opcode() {
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.ro_data = b"ab\0".to_vec();
+        test_vm.heap = b"Hi\0".to_vec();
+        // the heap starts right after the 3 bytes of read-only data
+        test_vm.registers[0] = 3;
+        test_vm.program = vec![Opcode::PRINTSR.into(), 0, 0, 0];
+        test_vm.run();
+        assert_eq!(output.0.borrow().as_slice(), b"Hi");
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_printsr_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.heap = b"Hi".to_vec();
+        test_vm.program = vec![Opcode::PRINTSR.into(), 0, 0, 0];
+        assert!(!test_vm.execute_instruction());
+
+        test_vm.registers[0] = 100;
+        test_vm.pc = 0;
+        assert!(!test_vm.execute_instruction());
+
+        test_vm.registers[0] = -1;
+        test_vm.pc = 0;
+        assert!(!test_vm.execute_instruction());
+        assert!(output.0.borrow().is_empty());
+    }
+
+    /// Counts $0 down from `n` to 0 in a loop
+    fn countdown_program(n: u16) -> Vec<u8> {
+        vec![
+            Opcode::LOAD.into(), 1, 0, 8,
+            Opcode::LOAD.into(), 0, (n >> 8) as u8, n as u8,
+            Opcode::DEC.into(), 0, 0, 0,
+            Opcode::EQ.into(), 0, 2, 0,
+            Opcode::JEQ.into(), 1, 0, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+        ]
+    }
+
+    #[test]
+    fn test_compiled_run_matches_interpreted() {
+        let mut interpreted = get_test_vm();
+        interpreted.program = countdown_program(60000);
+        interpreted.run();
+
+        let mut compiled = get_test_vm();
+        compiled.program = countdown_program(60000);
+        compiled.compile();
+        compiled.run();
+
+        assert_eq!(compiled.registers(), interpreted.registers());
+        assert_eq!(compiled.equal_flag(), interpreted.equal_flag());
+        assert_eq!(compiled.pc(), interpreted.pc());
+        assert_eq!(compiled.cycles(), interpreted.cycles());
+        assert_eq!(compiled.cycles(), 3 + 3 * 60000);
+    }
+
+    #[test]
+    fn test_compile_is_dropped_when_program_grows() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::INC.into(), 0, 0, 0];
+        test_vm.compile();
+        test_vm.add_bytes(vec![Opcode::INC.into(), 1, 0, 0]);
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 1);
+        assert_eq!(test_vm.registers()[1], 1);
+    }
+
+    #[test]
+    fn test_watch_register() {
+        let mut test_vm = get_test_vm();
+        test_vm.watch_register(2).unwrap();
+        test_vm.program = vec![
+            Opcode::LOAD.into(), 0, 0, 5,
+            Opcode::INC.into(), 2, 0, 0,
+            Opcode::INC.into(), 2, 0, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+        ];
+        let outcome = test_vm.run();
+        assert_eq!(
+            outcome,
+            RunOutcome::Watchpoint(WatchpointHit {
+                register: 2,
+                old: 0,
+                new: 1
+            })
+        );
+        assert_eq!(test_vm.pc(), 8);
+
+        let outcome = test_vm.run();
+        assert_eq!(
+            outcome,
+            RunOutcome::Watchpoint(WatchpointHit {
+                register: 2,
+                old: 1,
+                new: 2
+            })
+        );
+
+        test_vm.unwatch_register(2);
+        assert_eq!(test_vm.run(), RunOutcome::Finished);
+        assert_eq!(test_vm.registers()[2], 2);
+    }
+
+    #[test]
+    fn test_watch_register_out_of_range() {
+        let mut test_vm = VM::with_registers(4);
+        assert_eq!(test_vm.watch_register(10), Err(VmError::RegisterOutOfRange { idx: 10 }));
+        test_vm.program = vec![Opcode::INC.into(), 3, 0, 0];
+        assert_eq!(test_vm.run(), RunOutcome::Finished);
+    }
+
+    #[test]
+    fn test_dump_opcode() {
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.program = vec![
+            Opcode::LOAD.into(), 3, 1, 244,
+            Opcode::DUMP.into(), 0, 0, 0,
+            Opcode::INC.into(), 0, 0, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 1);
+        let dump = String::from_utf8(output.0.borrow().clone()).unwrap();
+        assert!(dump.starts_with("pc: 4 equal_flag: false"), "{}", dump);
+        assert!(dump.contains("registers: [0, 0, 0, 500, 0"), "{}", dump);
+    }
+
+    #[test]
+    fn test_rand_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.seed(42);
+        test_vm.program = vec![Opcode::RAND.into(), 0, 0, 0, Opcode::RAND.into(), 1, 0, 0];
+        // the first xorshift outputs for seed 42, RAND keeps their high bits, which are
+        // 10 and -1609913603 for 32-bit registers
+        let outputs: [u64; 2] = [45_454_805_674, 11_532_217_803_599_905_471];
+        let expected = outputs.map(|x| (x >> (64 - Reg::BITS)) as Reg);
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0..2], expected);
+        assert!(test_vm.pc_valid());
+
+        test_vm.seed(42);
+        test_vm.reset();
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0..2], expected);
+    }
+
+    #[test]
+    fn test_randr_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.seed(42);
+        test_vm.registers[1] = -5;
+        test_vm.registers[2] = 10;
+        test_vm.program = vec![
+            Opcode::RANDR.into(), 0, 1, 2,
+            Opcode::RANDR.into(), 3, 1, 2,
+            Opcode::RANDR.into(), 4, 1, 2,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], -1);
+        assert_eq!(test_vm.registers()[3], -4);
+        assert_eq!(test_vm.registers()[4], 4);
+        assert!(test_vm.pc_valid());
+
+        // the widest range of the register width can not overflow
+        test_vm.registers[1] = Reg::MIN;
+        test_vm.registers[2] = Reg::MAX;
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(test_vm.error(), None);
+        assert!(test_vm.registers()[0] < Reg::MAX);
+    }
+
+    #[test]
+    fn test_randr_empty_range() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 3;
+        test_vm.registers[2] = 3;
+        test_vm.program = vec![Opcode::RANDR.into(), 0, 1, 2];
+        assert!(!test_vm.execute_instruction());
+    }
+
+    #[test]
+    fn test_load_zero_extends() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::LOAD.into(), 0, 0xFF, 0xFF];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 65535);
+    }
+
+    #[test]
+    fn test_loads_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::LOADS.into(), 0, 0xFF, 0xFF,
+            Opcode::LOADS.into(), 1, 0x80, 0x00,
+            Opcode::LOADS.into(), 2, 1, 244,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], -1);
+        assert_eq!(test_vm.registers()[1], -32768);
+        assert_eq!(test_vm.registers()[2], 500);
+    }
+
+    #[test]
+    fn test_trap_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.set_trap_handler(3, 12);
+        test_vm.program = vec![
+            Opcode::TRAP.into(), 0, 3, 0,
+            Opcode::LOAD.into(), 1, 0, 7,
+            Opcode::HLT.into(), 0, 0, 0,
+            // the handler
+            Opcode::LOAD.into(), 0, 0, 42,
+            Opcode::RET.into(), 0, 0, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 42);
+        assert_eq!(test_vm.registers()[1], 7);
+        assert_eq!(test_vm.pc(), 9);
+    }
+
+    #[test]
+    fn test_trap_without_handler() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::TRAP.into(), 0, 3, 0];
+        assert!(!test_vm.execute_instruction());
+
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::TRAP.into(), 0, 99, 0];
+        assert!(!test_vm.execute_instruction());
+    }
+
+    #[test]
+    fn test_ret_with_empty_call_stack() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::RET.into(), 0, 0, 0];
+        assert!(!test_vm.execute_instruction());
+    }
+
+    #[test]
+    fn test_getc_putc_opcodes() {
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.set_input(Box::new(&b"ok"[..]));
+        test_vm.registers[3] = 0x121;
+        test_vm.program = vec![
+            Opcode::GETC.into(), 0, 0, 0,
+            Opcode::GETC.into(), 1, 0, 0,
+            Opcode::GETC.into(), 2, 0, 0,
+            Opcode::PUTC.into(), 1, 0, 0,
+            Opcode::PUTC.into(), 0, 0, 0,
+            Opcode::PUTC.into(), 3, 0, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], b'o' as Reg);
+        assert_eq!(test_vm.registers()[1], b'k' as Reg);
+        assert_eq!(test_vm.registers()[2], -1);
+        assert_eq!(output.0.borrow().as_slice(), b"ko!");
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_trace() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = countdown_program(2);
+        test_vm.run();
+        assert!(test_vm.trace().is_empty());
+
+        test_vm.reset();
+        test_vm.enable_trace(true);
+        test_vm.run();
+        assert_eq!(
+            test_vm.trace(),
+            &[
+                (0, Opcode::LOAD),
+                (4, Opcode::LOAD),
+                (8, Opcode::DEC),
+                (12, Opcode::EQ),
+                (16, Opcode::JEQ),
+                (8, Opcode::DEC),
+                (12, Opcode::EQ),
+                (16, Opcode::JEQ),
+                (20, Opcode::HLT),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_opcode_profile() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #0\nload $1 #5\nloop: inc $0\neq $0 $1\njneqd @loop\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.load_program(program).unwrap();
+        test_vm.run();
+        assert!(test_vm.opcode_profile().is_empty());
+
+        test_vm.reset();
+        test_vm.enable_profile(true);
+        test_vm.run();
+        let profile = test_vm.opcode_profile();
+        assert_eq!(profile[&Opcode::INC], 5);
+        assert_eq!(profile[&Opcode::JNEQD], 5);
+        assert_eq!(profile[&Opcode::LOAD], 2);
+        assert_eq!(profile[&Opcode::HLT], 1);
+        assert!(!profile.contains_key(&Opcode::DEC));
+    }
+
+    #[test]
+    fn test_shr_and_ushr_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = -16;
+        test_vm.registers[2] = 2;
+        test_vm.program = vec![Opcode::SHR.into(), 0, 1, 2, Opcode::USHR.into(), 3, 1, 2];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], -4);
+        // 0x3FFF_FFFC for 32-bit registers
+        assert_eq!(test_vm.registers()[3], ((UReg::MAX >> 2) - 3) as Reg);
+        assert!(test_vm.pc_valid());
+
+        // positive values shift the same either way
+        test_vm.registers[1] = 16;
+        test_vm.pc = 0;
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 4);
+        assert_eq!(test_vm.registers()[3], 4);
+    }
+
+    #[test]
+    fn test_rol_and_ror_opcodes() {
+        let mut test_vm = get_test_vm();
+        // the top and bottom bits set, 0x8000_0001 for 32-bit registers
+        let top: UReg = 1 << (Reg::BITS - 1);
+        test_vm.registers[1] = (top | 1) as Reg;
+        test_vm.program = vec![Opcode::ROL.into(), 0, 1, 2, Opcode::ROR.into(), 3, 1, 2];
+        let cases = [
+            (0, top | 1, top | 1),
+            (1, 0b11, top | (top >> 1)),
+            (4, 0b11000, 0b11 << (Reg::BITS - 5)),
+            (Reg::BITS - 1, top | (top >> 1), 0b11),
+            (Reg::BITS, top | 1, top | 1),
+        ];
+        for (amount, left, right) in cases {
+            test_vm.registers[2] = amount as Reg;
+            test_vm.pc = 0;
+            test_vm.run();
+            assert_eq!(test_vm.registers()[0] as UReg, left, "rol by {}", amount);
+            assert_eq!(test_vm.registers()[3] as UReg, right, "ror by {}", amount);
+        }
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_min_and_max_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = -3;
+        test_vm.registers[2] = 7;
+        test_vm.program = vec![
+            Opcode::MIN.into(), 0, 1, 2,
+            Opcode::MAX.into(), 3, 1, 2,
+            Opcode::MIN.into(), 4, 2, 2,
+            Opcode::MAX.into(), 5, 2, 2,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], -3);
+        assert_eq!(test_vm.registers()[3], 7);
+        assert_eq!(test_vm.registers()[4], 7);
+        assert_eq!(test_vm.registers()[5], 7);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_abs_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = -42;
+        test_vm.registers[2] = 42;
+        test_vm.program = vec![Opcode::ABS.into(), 0, 1, 0, Opcode::ABS.into(), 3, 2, 0];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 42);
+        assert_eq!(test_vm.registers()[3], 42);
+        assert!(!test_vm.overflow_flag);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_abs_opcode_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = Reg::MIN;
+        test_vm.program = vec![Opcode::ABS.into(), 0, 1, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], Reg::MIN);
+        assert!(test_vm.overflow_flag);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_run_steps() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::INC.into(), 3, 0, 0,
+            Opcode::INC.into(), 1, 0, 0,
+            Opcode::INC.into(), 3, 0, 0,
+            Opcode::INC.into(), 7, 0, 0,
+        ];
+        let report = test_vm.run_steps(3);
+        assert_eq!(
+            report,
+            StepReport {
+                changed: vec![1, 3],
+                pc: 12,
+                halted: false,
+            }
+        );
+        assert_eq!(test_vm.registers()[3], 2);
+
+        let report = test_vm.run_steps(5);
+        assert_eq!(
+            report,
+            StepReport {
+                changed: vec![7],
+                pc: 16,
+                halted: true,
+            }
+        );
+    }
+
+    #[test]
+    fn test_sb_and_lb_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.ro_data = vec![7, 200];
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[1] = 0x1FF;
+        test_vm.registers[2] = 3;
+        test_vm.registers[3] = 1;
+        test_vm.program = vec![
+            Opcode::SB.into(), 1, 2, 0,
+            Opcode::LB.into(), 4, 2, 0,
+            Opcode::LB.into(), 5, 3, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.heap, vec![0, 255, 0, 0]);
+        assert_eq!(test_vm.registers()[4], 255);
+        assert_eq!(test_vm.registers()[5], 200);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_sb_and_lb_out_of_bounds() {
+        let mut test_vm = get_test_vm();
+        test_vm.ro_data = vec![7];
+        test_vm.heap = vec![0; 4];
+        test_vm.registers[2] = 5;
+        test_vm.program = vec![Opcode::LB.into(), 0, 2, 0];
+        assert!(!test_vm.execute_instruction());
+
+        // stores into the read-only data are rejected too
+        test_vm.registers[2] = 0;
+        test_vm.program = vec![Opcode::SB.into(), 1, 2, 0];
+        test_vm.pc = 0;
+        assert!(!test_vm.execute_instruction());
+        assert_eq!(test_vm.ro_data, vec![7]);
+    }
+
+    #[test]
+    fn test_with_registers() {
+        let mut test_vm = VM::with_registers(64);
+        assert_eq!(test_vm.registers().len(), 64);
+        test_vm.program = vec![Opcode::LOAD.into(), 40, 0, 9, Opcode::INC.into(), 40, 0, 0];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[40], 10);
+        assert_eq!(VM::new().registers().len(), DEFAULT_REGISTERS);
+    }
+
+    #[test]
+    fn test_register_out_of_range() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::LOAD.into(), 40, 0, 9];
+        test_vm.run();
+        assert_eq!(test_vm.error(), Some(&VmError::RegisterOutOfRange { idx: 40 }));
+    }
+
+    #[test]
+    fn test_saturating_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = Reg::MAX - 1;
+        test_vm.registers[2] = 5;
+        test_vm.registers[3] = Reg::MIN + 1;
+        test_vm.registers[4] = -5;
+        test_vm.program = vec![
+            Opcode::ADDS.into(), 10, 1, 2,
+            Opcode::SUBS.into(), 11, 3, 2,
+            Opcode::MULS.into(), 12, 1, 2,
+            Opcode::MULS.into(), 13, 1, 4,
+            Opcode::ADDS.into(), 14, 2, 4,
+            Opcode::SUBS.into(), 15, 1, 4,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[10], Reg::MAX);
+        assert_eq!(test_vm.registers()[11], Reg::MIN);
+        assert_eq!(test_vm.registers()[12], Reg::MAX);
+        assert_eq!(test_vm.registers()[13], Reg::MIN);
+        assert_eq!(test_vm.registers()[14], 0);
+        assert_eq!(test_vm.registers()[15], Reg::MAX);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_wrapping_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = Reg::MAX;
+        test_vm.registers[2] = 1;
+        test_vm.registers[3] = Reg::MIN;
+        test_vm.registers[4] = 2;
+        test_vm.program = vec![
+            Opcode::ADD.into(), 10, 1, 2,
+            Opcode::ADD.into(), 11, 2, 2,
+            Opcode::SUB.into(), 12, 3, 2,
+            Opcode::MUL.into(), 13, 1, 4,
+            Opcode::INC.into(), 1, 0, 0,
+            Opcode::DEC.into(), 3, 0, 0,
+        ];
+        // wraps around like ADDI instead of panicking, noting the overflow
+        let expected = [
+            (10, Reg::MIN, true),
+            (11, 2, false),
+            (12, Reg::MAX, true),
+            (13, -2, true),
+            (1, Reg::MIN, true),
+            (3, Reg::MAX, true),
+        ];
+        for (register, value, overflow) in expected {
+            test_vm.run_once();
+            assert_eq!(test_vm.registers()[register], value);
+            assert_eq!(test_vm.overflow_flag, overflow);
+        }
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::LOAD.into(), 0, 0, 7, Opcode::ALOC.into(), 0, 0, 0];
+        let before = test_vm.snapshot();
+        test_vm.run();
+        assert_eq!(test_vm.heap.len(), 7);
+        test_vm.restore(before.clone());
+        assert_eq!(test_vm.snapshot(), before);
+        assert_eq!(test_vm.pc(), 0);
+        assert_eq!(test_vm.registers()[0], 0);
+        assert!(test_vm.heap.is_empty());
+    }
+
+    #[test]
+    fn test_movlo_and_movhi_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 0x7FFF_FFFF;
+        test_vm.program = vec![
+            Opcode::MOVLO.into(), 0, 0x56, 0x78,
+            Opcode::MOVHI.into(), 0, 0x12, 0x34,
+            Opcode::MOVLO.into(), 1, 0xFF, 0xFF,
+            Opcode::MOVHI.into(), 1, 0xFF, 0xFF,
+        ];
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 0x5678);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[0], 0x1234_5678);
+        test_vm.run_once();
+        // MOVLO clears the high bits left over in the register
+        assert_eq!(test_vm.registers()[1], 0xFFFF);
+        test_vm.run_once();
+        assert_eq!(test_vm.registers()[1], -1);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_mfrem_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = 17;
+        test_vm.registers[2] = 5;
+        test_vm.program = vec![Opcode::DIV.into(), 0, 1, 2, Opcode::MFREM.into(), 3, 0, 0];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 3);
+        assert_eq!(test_vm.registers()[3], 2);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_divu_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = -7;
+        test_vm.registers[2] = 2;
+        test_vm.program = vec![
+            Opcode::DIV.into(), 0, 1, 2,
+            Opcode::MFREM.into(), 3, 0, 0,
+            Opcode::DIVU.into(), 4, 1, 2,
+            Opcode::MFREM.into(), 5, 0, 0,
+            Opcode::DIVU.into(), 6, 1, 7,
+        ];
+        test_vm.run();
+        // signed: -3 rem -1, rounding toward zero
+        assert_eq!(test_vm.registers()[0], -3);
+        assert_eq!(test_vm.registers()[3], -1);
+        // unsigned: the same bits are 4294967289 for 32-bit registers, halved to 2147483644
+        assert_eq!(test_vm.registers()[4], ((UReg::MAX - 6) / 2) as Reg);
+        assert_eq!(test_vm.registers()[5], 1);
+        assert_eq!(test_vm.error(), Some(&VmError::DivByZero { pc: 16 }));
+    }
+
+    #[test]
+    fn test_div_opcode_overflow() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[1] = Reg::MIN;
+        test_vm.registers[2] = -1;
+        test_vm.program = vec![Opcode::DIV.into(), 0, 1, 2, Opcode::MFREM.into(), 3, 0, 0];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], Reg::MIN);
+        assert_eq!(test_vm.registers()[3], 0);
+        assert!(test_vm.overflow_flag);
+        assert_eq!(test_vm.error(), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::LOAD.into(), 3, 0, 42];
+        test_vm.run();
+        assert_eq!(
+            test_vm.to_string(),
+            "pc: 4 cycles: 1 equal_flag: false program: 4 bytes heap: 0 bytes\nregisters: $3=42"
+        );
+    }
+
+    #[test]
+    fn test_jneqd_loop() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #0\nload $1 #3\nloop: inc $0\neq $0 $1\njneqd @loop\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.load_program(program).unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 3);
+        assert!(test_vm.equal_flag());
+    }
+
+    #[test]
+    fn test_bit_count_opcodes() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::POPCNT.into(), 0, 3, 0,
+            Opcode::CLZ.into(), 1, 3, 0,
+            Opcode::CTZ.into(), 2, 3, 0,
+        ];
+        // (value, set bits, leading zeros, trailing zeros), counted over the register width
+        let bits = Reg::BITS;
+        let cases = [(0, 0, bits, bits), (-1, bits, 0, 0), (0x0F00_0A00, 6, bits - 28, 9)];
+        for (value, ones, leading, trailing) in cases {
+            test_vm.registers[3] = value;
+            test_vm.pc = 0;
+            test_vm.run();
+            assert_eq!(test_vm.registers()[0], ones as Reg);
+            assert_eq!(test_vm.registers()[1], leading as Reg);
+            assert_eq!(test_vm.registers()[2], trailing as Reg);
+        }
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_immediate_compare_opcodes() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("eqi $0 #7\nneqi $0 #7\ngti $0 #-1\nlti $0 #-1\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.load_program(program).unwrap();
+        // (register, against 7, against -1), the immediate is sign-extended
+        let cases = [
+            (7, Comparison::Equal, Comparison::Greater),
+            (8, Comparison::Greater, Comparison::Greater),
+            (-5, Comparison::Less, Comparison::Less),
+        ];
+        for (value, seven, minus_one) in cases {
+            test_vm.reset();
+            test_vm.registers[0] = value;
+            for expected in [seven, seven, minus_one, minus_one] {
+                test_vm.run_once();
+                assert_eq!(test_vm.comparison(), expected, "register {}", value);
+            }
+        }
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_time_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::TIME.into(), 0, 0, 0,
+            Opcode::NOP.into(), 0, 0, 0,
+            Opcode::TIME.into(), 1, 0, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 1);
+        assert_eq!(test_vm.registers()[1], 3);
+
+        let mut ticks = 100;
+        test_vm.set_clock(Box::new(move || {
+            ticks += 10;
+            ticks
+        }));
+        test_vm.reset();
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 110);
+        assert_eq!(test_vm.registers()[1], 120);
+        assert!(test_vm.pc_valid());
+    }
+
+    #[test]
+    fn test_comparison_ordering() {
+        let mut test_vm = get_test_vm();
+        assert_eq!(test_vm.comparison(), Comparison::None);
+        test_vm.registers[1] = 5;
+        for opcode in [Opcode::EQ, Opcode::NEQ, Opcode::GT, Opcode::LT, Opcode::GTE, Opcode::LTE] {
+            let cases = [(4, Comparison::Less), (5, Comparison::Equal), (6, Comparison::Greater)];
+            for (left, expected) in cases {
+                test_vm.registers[0] = left;
+                test_vm.program = vec![opcode.into(), 0, 1, 0];
+                test_vm.pc = 0;
+                test_vm.run_once();
+                assert_eq!(test_vm.comparison(), expected, "{:?} {}", opcode, left);
+            }
+        }
+        test_vm.program = vec![Opcode::EQI.into(), 0, 0xFF, 0xFF];
+        test_vm.pc = 0;
+        test_vm.run_once();
+        assert_eq!(test_vm.comparison(), Comparison::Greater);
+
+        test_vm.reset();
+        assert_eq!(test_vm.comparison(), Comparison::None);
+    }
+
+    #[test]
+    fn test_jgtd_and_jltd() {
+        let mut test_vm = get_test_vm();
+        // nothing compared yet, neither jumps
+        test_vm.program = vec![Opcode::JGTD.into(), 0, 12, 0, Opcode::JLTD.into(), 0, 12, 0];
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 4);
+        test_vm.run_once();
+        assert_eq!(test_vm.pc(), 8);
+
+        let mut asm = Assembler::new();
+        let source = "lt $0 $1\njgtd @greater\njltd @less\nhlt\n\
+                      greater: load $2 #1\nhlt\nless: load $2 #2\nhlt";
+        let program = asm.assemble(source).unwrap();
+        test_vm.load_program(program).unwrap();
+        for (left, right, taken) in [(9, 3, 1), (3, 9, 2), (3, 3, 0)] {
+            test_vm.reset();
+            test_vm.registers[0] = left;
+            test_vm.registers[1] = right;
+            test_vm.run();
+            assert_eq!(test_vm.registers()[2], taken, "{} against {}", left, right);
+        }
+        // lt did not hold for 9 against 3 yet the ordering still drives jgtd
+        test_vm.reset();
+        test_vm.registers[0] = 9;
+        test_vm.registers[1] = 3;
+        test_vm.run();
+        assert!(!test_vm.equal_flag());
+        assert_eq!(test_vm.comparison(), Comparison::Greater);
+    }
+
+    #[test]
+    fn test_jge_counting_loop() {
+        let mut asm = Assembler::new();
+        let source = "load $0 #0\nload $1 #4\nloop: inc $0\ninc $2\ngte $0 $1\njge @done\n\
+                      jmpbi #20\ndone: hlt";
+        let program = asm.assemble(source).unwrap();
+        let mut test_vm = get_test_vm();
+        test_vm.load_program(program).unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.registers()[0], 4);
+        assert_eq!(test_vm.registers()[2], 4);
+
+        // the other ordered jumps, after comparing 3 against 3
+        test_vm.program = vec![
+            Opcode::EQ.into(), 0, 0, 0,
+            Opcode::JLED.into(), 0, 12, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+            Opcode::JGTD.into(), 0, 4, 0,
+        ];
+        test_vm.reset();
+        test_vm.run_steps(3);
+        assert_eq!(test_vm.pc(), 16);
+    }
+
+    #[test]
+    fn test_memory_mapped_io() {
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble("load $0 #72\nload $1 #4096\nsb $0 $1\ninc $1\nlb $2 $1\nsb $2 $1\nhlt")
+            .unwrap();
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.set_input(Box::new(std::io::Cursor::new(b"i".to_vec())));
+        test_vm.map_io(4096..4098, Box::new(Console));
+        test_vm.load_program(program).unwrap();
+        test_vm.run();
+        assert_eq!(output.0.borrow().as_slice(), b"Hi");
+        assert_eq!(test_vm.registers()[2], b'i' as Reg);
+        assert!(test_vm.heap.is_empty());
+
+        // outside the mapped range the heap is used as before
+        test_vm.reset();
+        test_vm.heap = vec![0; 8];
+        test_vm.registers[1] = 3;
+        test_vm.program = vec![Opcode::SB.into(), 0, 1, 0, Opcode::LB.into(), 2, 1, 0];
+        test_vm.registers[0] = 9;
+        test_vm.run();
+        assert_eq!(test_vm.heap[3], 9);
+        assert_eq!(test_vm.registers()[2], 9);
+    }
+
+    #[test]
+    fn test_verbose_diagnostics() {
+        let mut test_vm = get_test_vm();
+        let output = SharedBuffer::default();
+        test_vm.set_output(Box::new(output.clone()));
+        test_vm.program = vec![Opcode::HLT.into(), 0, 0, 0];
+        test_vm.run();
+        test_vm.program = vec![200, 0, 0, 0];
+        test_vm.reset();
+        test_vm.run();
+        assert!(output.0.borrow().is_empty());
+
+        test_vm.set_verbose(true);
+        test_vm.reset();
+        test_vm.run();
+        let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+        assert_eq!(printed, "Unrecognized opcode 200 at pc 0! Terminating!\n");
+    }
+
+    #[test]
+    fn test_memcpy_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 16;
+        test_vm.registers[1] = 2;
+        test_vm.registers[2] = 0;
+        test_vm.registers[3] = 5;
+        test_vm.program = vec![
+            Opcode::ALOC.into(), 0, 0, 0,
+            Opcode::MEMCPY.into(), 1, 2, 3,
+            Opcode::MEMCPY.into(), 1, 2, 0,
+        ];
+        test_vm.run_once();
+        test_vm.heap[..5].copy_from_slice(&[1, 2, 3, 4, 5]);
+
+        // the destination overlaps the end of the source
+        assert!(test_vm.execute_instruction());
+        assert_eq!(test_vm.heap[..8], [1, 2, 1, 2, 3, 4, 5, 0]);
+        assert!(test_vm.pc_valid());
+
+        // copying 16 bytes to offset 2 runs past the end of the heap
+        assert!(!test_vm.execute_instruction());
+        assert_eq!(test_vm.heap[..8], [1, 2, 1, 2, 3, 4, 5, 0]);
+    }
+
+    #[test]
+    fn test_memset_opcode() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 32;
+        test_vm.registers[1] = 8;
+        test_vm.registers[2] = 0x1FF;
+        test_vm.registers[3] = 16;
+        test_vm.registers[4] = 20;
+        test_vm.program = vec![
+            Opcode::ALOC.into(), 0, 0, 0,
+            Opcode::MEMSET.into(), 1, 2, 3,
+            Opcode::MEMSET.into(), 4, 2, 3,
+        ];
+        test_vm.run_once();
+        assert!(test_vm.execute_instruction());
+        assert_eq!(test_vm.heap()[..8], [0; 8]);
+        assert_eq!(test_vm.heap()[8..24], [0xFF; 16]);
+        assert_eq!(test_vm.heap()[24..], [0; 8]);
+        assert!(test_vm.pc_valid());
+
+        // 16 bytes from offset 20 run past the end of the heap
+        assert!(!test_vm.execute_instruction());
+        assert_eq!(test_vm.heap()[24..], [0; 8]);
+    }
+
+    #[test]
+    fn test_vm_errors() {
+        let error_of = |program: Vec<u8>| {
+            let mut test_vm = get_test_vm();
+            test_vm.registers[1] = 7;
+            test_vm.program = program;
+            test_vm.run();
+            test_vm.error
+        };
+        assert_eq!(
+            error_of(vec![Opcode::NOP.into(), 0, 0, 0, Opcode::DIV.into(), 0, 1, 2]),
+            Some(VmError::DivByZero { pc: 4 })
+        );
+        assert_eq!(
+            error_of(vec![Opcode::INC.into(), 32, 0, 0]),
+            Some(VmError::RegisterOutOfRange { idx: 32 })
+        );
+        assert_eq!(error_of(vec![Opcode::RET.into(), 0, 0, 0]), Some(VmError::StackUnderflow));
+        assert_eq!(
+            error_of(vec![Opcode::SB.into(), 0, 1, 0]),
+            Some(VmError::HeapOutOfBounds { addr: 7 })
+        );
+        assert_eq!(
+            error_of(vec![Opcode::NOP.into(), 0, 0, 0, 200, 0, 0, 0]),
+            Some(VmError::IllegalOpcode { byte: 200, pc: 4 })
+        );
+        assert_eq!(
+            error_of(vec![Opcode::NOP.into(), 0, 0, 0, Opcode::NOP.into(), 0]),
+            Some(VmError::TruncatedProgram)
+        );
+        assert_eq!(
+            error_of(vec![Opcode::JMP.into(), 1, 0, 0, Opcode::HLT.into(), 0, 0, 0]),
+            Some(VmError::BadJump(PcError::Misaligned { target: 7 }))
+        );
+        // halting is no error
+        assert_eq!(error_of(vec![Opcode::HLT.into(), 0, 0, 0]), None);
+
+        // running on from the middle of the HLT it stopped at
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::HLT.into(), 0, 0, 0];
+        test_vm.run();
+        test_vm.run();
+        assert_eq!(test_vm.error(), Some(&VmError::MisalignedPc { pc: 1 }));
+        test_vm.reset();
+        assert_eq!(test_vm.error(), None);
+    }
+
+    #[test]
+    fn test_load_store_widths() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 16;
+        test_vm.registers[1] = -2;
+        test_vm.registers[2] = 0;
+        test_vm.registers[3] = 4;
+        test_vm.registers[4] = 8;
+        test_vm.registers[5] = 0x1234_8001;
+        test_vm.registers[12] = 14;
+        test_vm.program = vec![
+            Opcode::ALOC.into(), 0, 0, 0,
+            Opcode::SW.into(), 1, 2, 0,
+            Opcode::SH.into(), 5, 3, 0,
+            Opcode::SB.into(), 5, 4, 0,
+            Opcode::LW.into(), 6, 2, 0,
+            Opcode::LH.into(), 7, 3, 0,
+            Opcode::LHS.into(), 8, 3, 0,
+            Opcode::LB.into(), 9, 2, 0,
+            Opcode::LBS.into(), 10, 2, 0,
+            Opcode::LB.into(), 11, 4, 0,
+            Opcode::SW.into(), 1, 12, 0,
+        ];
+        test_vm.run();
+        assert_eq!(test_vm.heap()[..9], [0xFE, 0xFF, 0xFF, 0xFF, 0x01, 0x80, 0, 0, 0x01]);
+        assert_eq!(test_vm.registers()[6], -2);
+        assert_eq!(test_vm.registers()[7], 0x8001);
+        assert_eq!(test_vm.registers()[8], -0x7FFF);
+        assert_eq!(test_vm.registers()[9], 0xFE);
+        assert_eq!(test_vm.registers()[10], -2);
+        assert_eq!(test_vm.registers()[11], 1);
+
+        // a word at 14 would end past the heap, so none of it is stored
+        assert_eq!(test_vm.error(), Some(&VmError::HeapOutOfBounds { addr: 16 }));
+        assert_eq!(test_vm.heap()[14..], [0, 0]);
+    }
+
+    #[test]
+    fn test_run_sandboxed() {
+        let sandboxed = |program: Vec<u8>, limits: Limits| {
+            let mut test_vm = get_test_vm();
+            test_vm.registers[0] = 2048;
+            test_vm.set_trap_handler(0, 0);
+            test_vm.program = program;
+            test_vm.run_sandboxed(limits)
+        };
+        let limits = Limits {
+            max_steps: Some(3),
+            max_heap: Some(1024),
+            max_stack: Some(4),
+        };
+        let counting = vec![
+            Opcode::INC.into(), 1, 0, 0,
+            Opcode::INC.into(), 1, 0, 0,
+            Opcode::HLT.into(), 0, 0, 0,
+        ];
+        assert_eq!(sandboxed(counting.clone(), limits), SandboxOutcome::Completed);
+        let steps = Limits { max_steps: Some(2), ..limits };
+        assert_eq!(sandboxed(counting, steps), SandboxOutcome::StepLimit);
+        assert_eq!(
+            sandboxed(vec![Opcode::ALOC.into(), 0, 0, 0], limits),
+            SandboxOutcome::HeapLimit
+        );
+        // a trap whose handler is itself nests until the stack limit
+        let stack = Limits { max_steps: Some(100), ..limits };
+        assert_eq!(
+            sandboxed(vec![Opcode::TRAP.into(), 0, 0, 0], stack),
+            SandboxOutcome::StackLimit
+        );
+        assert_eq!(
+            sandboxed(vec![Opcode::DIV.into(), 0, 1, 2], limits),
+            SandboxOutcome::Failed(VmError::DivByZero { pc: 0 })
+        );
+
+        // the VM's own caps apply again afterwards
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = 2048;
+        test_vm.program = vec![Opcode::ALOC.into(), 0, 0, 0];
+        assert_eq!(test_vm.run_sandboxed(limits), SandboxOutcome::HeapLimit);
+        test_vm.reset();
+        test_vm.registers[0] = 2048;
+        assert_eq!(test_vm.run_sandboxed(Limits::default()), SandboxOutcome::Completed);
+        assert_eq!(test_vm.heap.len(), 2048);
+    }
+
+    #[test]
+    fn test_with_registers_preset() {
+        let mut test_vm = VM::with_registers_preset(&[(1, 20), (2, 22)]).unwrap();
+        test_vm.program = vec![Opcode::ADD.into(), 0, 1, 2];
+        test_vm.run();
+        assert_eq!(&test_vm.registers()[..3], &[42, 20, 22]);
+
+        let result = VM::with_registers_preset(&[(1, 20), (32, 1)]);
+        assert_eq!(result.err(), Some(VmError::RegisterOutOfRange { idx: 32 }));
+    }
+
+    #[test]
+    fn test_step() {
+        let mut test_vm = VM::with_registers_preset(&[(1, 3)]).unwrap();
+        test_vm.program = vec![
+            Opcode::LOAD.into(), 0, 0, 4,
+            Opcode::ADD.into(), 2, 0, 1,
+            Opcode::DEC.into(), 2, 0, 0,
+        ];
+        let mut executed = vec![];
+        while let Some(opcode) = test_vm.step().unwrap() {
+            executed.push(opcode);
+        }
+        assert_eq!(executed, vec![Opcode::LOAD, Opcode::ADD, Opcode::DEC]);
+        assert_eq!(test_vm.registers()[2], 6);
+        assert_eq!(test_vm.step(), Ok(None));
+
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![Opcode::HLT.into(), 0, 0, 0, Opcode::DIV.into(), 0, 1, 2];
+        assert_eq!(test_vm.step(), Ok(Some(Opcode::HLT)));
+        test_vm.pc = 4;
+        assert_eq!(test_vm.step(), Err(VmError::DivByZero { pc: 4 }));
+    }
+
+    #[test]
+    fn test_data_address_space() {
+        let source = "flag: .byte 7\n.align 4\ncount: .word 70000\nlea $0 @count\nlw $1 $0\n\
+                      load $2 #4\naloc $2\nload $3 #8\nsw $1 $3\nlw $4 $3\nsw $1 $0\nhlt";
+        let mut test_vm = VM::new();
+        test_vm.load_program(Assembler::new().assemble(source).unwrap()).unwrap();
+        test_vm.run();
+        assert_eq!(test_vm.ro_data_addresses(), 0..8);
+        assert_eq!(test_vm.heap_addresses(), 8..12);
+        // LEA gives the data address of the label, which LW reads through
+        assert_eq!(test_vm.registers()[0], 4);
+        assert_eq!(test_vm.registers()[1], 70000);
+        // the heap follows in the same space and the read-only data can not be written
+        assert_eq!(test_vm.registers()[4], 70000);
+        assert_eq!(test_vm.error(), Some(&VmError::HeapOutOfBounds { addr: 4 }));
+    }
+
+    #[test]
+    fn test_enter_leave() {
+        let source = "load $0 #1\nload $1 #2\ntrap #0\nhlt\n\
+                      helper: enter #2\nload $0 #50\nload $1 #60\nadd $5 $0 $1\nleave #2\nret";
+        let mut asm = Assembler::new();
+        let program = asm.assemble(source).unwrap();
+        let mut test_vm = VM::new();
+        test_vm.load_program(program).unwrap();
+        test_vm.set_trap_handler(0, asm.symbols.symbol_value("helper").unwrap() as usize);
+        test_vm.run();
+        assert_eq!(test_vm.error(), None);
+        // the caller's registers are back, the result left above the window survives
+        assert_eq!(&test_vm.registers()[..2], &[1, 2]);
+        assert_eq!(test_vm.registers()[5], 110);
+
+        let error_of = |program: Vec<u8>| {
+            let mut test_vm = get_test_vm();
+            test_vm.program = program;
+            test_vm.run();
+            test_vm.error
+        };
+        assert_eq!(
+            error_of(vec![Opcode::LEAVE.into(), 0, 1, 0]),
+            Some(VmError::Other("LEAVE without an ENTER".to_string()))
+        );
+        assert_eq!(
+            error_of(vec![Opcode::ENTER.into(), 0, 2, 0, Opcode::LEAVE.into(), 0, 3, 0]),
+            Some(VmError::Other("LEAVE #3 does not match ENTER #2".to_string()))
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "reg64")]
+    fn test_reg64_arithmetic() {
+        let mut test_vm = get_test_vm();
+        test_vm.program = vec![
+            Opcode::LOAD.into(), 0, 0xC3, 0x50,
+            Opcode::ADD.into(), 0, 0, 0,
+            Opcode::MUL.into(), 1, 0, 0,
+            Opcode::SUB.into(), 2, 2, 1,
+            Opcode::DIV.into(), 3, 1, 0,
+        ];
+        test_vm.run();
+        // 100000 squared overflows an i32
+        assert_eq!(test_vm.registers()[1], 10_000_000_000);
+        assert_eq!(test_vm.registers()[2], -10_000_000_000);
+        assert_eq!(test_vm.registers()[3], 100_000);
+        assert_eq!(test_vm.error(), None);
+    }
+
+    #[test]
+    #[cfg(feature = "reg64")]
+    fn test_reg64_jmpf_huge_offset() {
+        let mut test_vm = get_test_vm();
+        test_vm.registers[0] = i64::MAX;
+        test_vm.program = vec![Opcode::JMPF.into(), 0, 0, 0];
+        test_vm.run();
+        assert_eq!(
+            test_vm.error(),
+            Some(&VmError::BadJump(PcError::OutOfBounds { target: i64::MAX }))
+        );
+    }
+}
+
+#[cfg(all(test, not(feature = "std")))]
+mod no_std_tests {
+    use super::*;
+
+    #[test]
+    fn test_core_execution() {
+        let mut test_vm = VM::new();
+        test_vm.add_bytes(vec![1, 0, 0, 100, 1, 1, 0, 2, 2, 2, 0, 1, 0, 0, 0, 0]);
+        test_vm.run();
+        assert_eq!(test_vm.registers()[2], 102);
+        assert_eq!(test_vm.error(), None);
+    }
 }